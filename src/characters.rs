@@ -1,114 +1,544 @@
-use std::error::Error;
+use std::{error::Error, time::Instant};
 
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    deployment::Deployment,
     dice_roll::dice_roll,
-    flarum::Forum,
-    language::Language,
-    llm::{get_llm_response, LlmError},
+    embeddings::{index_discussion, related_discussions},
+    forum::{DiscussionData, Notification, Post, ReplyTo, Tag},
+    injection::{delimit, inline, report_injections},
+    llm::{ChatMessage, ChatRequest, LlmError},
+    memory::recall,
+    moderation::Verdict,
+    relationships::{self, Relationship},
+    selection::SelectionContext,
+    store::{ActionRecord, Memory},
+    tokens::{fit_history, fit_posts, TokenCounter, OMITTED},
 };
 
+/// Tokens of the answered post shown when summarizing a reply to remember it.
+const MEMORY_CONTEXT_TOKENS: usize = 500;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Character {
     pub user_id: i32,
+    pub name: String,
     pub system_message: String,
+    /// Overrides the forum's LLM model for this character.
+    pub model: Option<String>,
+    /// Relative likelihood of being picked when no character is given.
+    pub weight: f64,
+    /// Slugs of the tags the character prefers for new discussions.
+    pub tags: Vec<String>,
+    /// What the character is to other characters of the forum.
+    pub relationships: Vec<Relationship>,
 }
 
 impl Character {
-    async fn ask_gpt(&self, user_message: &str) -> Result<String, LlmError> {
-        get_llm_response(&self.system_message, user_message).await
+    async fn ask_gpt(
+        &self,
+        deployment: &Deployment,
+        action: &mut ActionRecord,
+        user_message: &str,
+    ) -> Result<String, LlmError> {
+        let request = ChatRequest::new(&self.system_message, user_message);
+        self.ask_chat(deployment, action, request).await
     }
 
-    pub async fn create_title_for_new_topic(
+    async fn ask_chat(
         &self,
-        lang: &dyn Language,
+        deployment: &Deployment,
+        action: &mut ActionRecord,
+        request: ChatRequest,
     ) -> Result<String, LlmError> {
-        self.ask_gpt(&lang.get_new_topic_title_prompt()).await
+        let request = request.with_model(self.model.as_deref());
+        let started = Instant::now();
+        let completion = deployment.llm.complete(&request).await?;
+        action.add_completion(&request.transcript(), &completion, started.elapsed());
+        Ok(completion.content)
+    }
+
+    /// Has the LLM answer `request` and the answer moderated, asking again
+    /// for as long as moderation wants it regenerated.
+    async fn ask_moderated(
+        &self,
+        deployment: &Deployment,
+        action: &mut ActionRecord,
+        request: ChatRequest,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut attempt = 0;
+        loop {
+            let content = self.ask_chat(deployment, action, request.clone()).await?;
+            match deployment
+                .moderator
+                .review(deployment, self, action, &content, attempt)
+                .await?
+            {
+                Verdict::Publish => return Ok(content),
+                Verdict::Regenerate => attempt += 1,
+            }
+        }
+    }
+
+    pub fn relationship(&self, user_id: i32) -> Option<&Relationship> {
+        self.relationships.iter().find(|r| r.user_id == user_id)
+    }
+
+    fn model<'a>(&'a self, deployment: &'a Deployment) -> &'a str {
+        self.model
+            .as_deref()
+            .unwrap_or(&deployment.llm_config.model)
+    }
+
+    fn is_author_of(&self, post: &Post) -> bool {
+        post.author.as_ref().map(|a| a.id) == Some(self.user_id)
+    }
+
+    /// The system message replies are written with. Thread content is only
+    /// ever given to the LLM as delimited data, which it is told about.
+    pub fn reply_system_message(&self, deployment: &Deployment) -> String {
+        format!(
+            "{}\n\n{}",
+            self.system_message,
+            deployment.lang.get_untrusted_notice()
+        )
+    }
+
+    /// The request for a reply to a discussion, either with the history in a
+    /// single prompt or, with `chat_history`, as a conversation where the
+    /// character's own posts are its previous answers. `recollection` is put
+    /// before the history.
+    fn reply_request(
+        &self,
+        deployment: &Deployment,
+        data: &DiscussionData,
+        recollection: &str,
+    ) -> ChatRequest {
+        let title = inline(&data.discussion.title);
+        let window = data.window(deployment.history_posts);
+        report_injections(
+            deployment,
+            data.discussion.id,
+            window
+                .iter()
+                .filter(|post| !self.is_author_of(post))
+                .map(|post| (post.id, post.content.as_str())),
+        );
+        let system = self.reply_system_message(deployment);
+        let delimited = |post: &Post, content: &str| {
+            delimit(
+                post.author.as_ref().map(|a| a.display_name.as_str()),
+                content,
+            )
+        };
+        let model = self.model(deployment);
+        let counter = TokenCounter::for_model(model);
+        let tags: usize = window
+            .iter()
+            .map(|post| counter.count(&delimited(post, "")))
+            .sum();
+        let budget = |prompt: &str| {
+            deployment.llm_config.prompt_budget(model).saturating_sub(
+                counter.count(&system) + counter.count(recollection) + counter.count(prompt) + tags,
+            )
+        };
+        let with_recollection = |prompt: String| {
+            if recollection.is_empty() {
+                prompt
+            } else {
+                format!("{}\n\n{}", recollection, prompt)
+            }
+        };
+
+        if !deployment.chat_history {
+            let posts: Vec<String> = window.iter().map(|c| c.content.clone()).collect();
+            let budget = budget(&deployment.lang.get_reply_prompt(&title, ""));
+            let history = fit_history(&counter, &posts, budget, |i, content| {
+                delimited(window[i], content)
+            });
+            let msg = with_recollection(deployment.lang.get_reply_prompt(&title, &history));
+            return ChatRequest::new(&system, &msg);
+        }
+
+        let intro = with_recollection(deployment.lang.get_chat_reply_prompt(&title));
+        let instruction = deployment.lang.get_chat_reply_instruction();
+        let posts: Vec<String> = window.iter().map(|post| post.content.clone()).collect();
+        let budget = budget(&format!(
+            "{}{}",
+            deployment.lang.get_chat_reply_prompt(&title),
+            instruction
+        ));
+        let mut request = ChatRequest::empty(&system);
+        request.push(ChatMessage::user(&intro));
+        let mut omitted = false;
+        for (post, content) in window.iter().zip(fit_posts(&counter, &posts, budget)) {
+            match content {
+                Some(content) if self.is_author_of(post) => {
+                    request.push(ChatMessage::assistant(&content))
+                }
+                Some(content) => request.push(ChatMessage::user(&delimited(post, &content))),
+                None if !omitted => {
+                    omitted = true;
+                    request.push(ChatMessage::user(OMITTED));
+                }
+                None => {}
+            }
+        }
+        request.push(ChatMessage::user(&instruction));
+        request
+    }
+
+    fn new_action(&self, deployment: &Deployment, kind: &str) -> ActionRecord {
+        ActionRecord::new(&deployment.name, self.user_id, &self.name, kind)
+    }
+
+    pub async fn create_title_for_new_topic(
+        &self,
+        deployment: &Deployment,
+        action: &mut ActionRecord,
+    ) -> Result<String, Box<dyn Error>> {
+        let prompt = deployment.lang.get_new_topic_title_prompt();
+        let request = ChatRequest::new(&self.system_message, &prompt);
+        self.ask_moderated(deployment, action, request).await
     }
 
     pub async fn create_post_new_topic(
         &self,
-        lang: &dyn Language,
+        deployment: &Deployment,
+        action: &mut ActionRecord,
         title: &str,
-    ) -> Result<String, LlmError> {
-        let prompt = lang.get_new_topic_prompt(title);
-        self.ask_gpt(&prompt).await
+    ) -> Result<String, Box<dyn Error>> {
+        let prompt = deployment.lang.get_new_topic_prompt(title);
+        let request = ChatRequest::new(&self.system_message, &prompt);
+        self.ask_moderated(deployment, action, request).await
     }
 
-    pub async fn create_new_topic(
+    /// Has the LLM summarize a post the character just made and stores the
+    /// summary as a memory. Failures are reported but don't fail the post.
+    async fn remember(
         &self,
-        lang: &dyn Language,
-        forum: &Forum,
-    ) -> Result<(), Box<dyn Error>> {
-        let title = self.create_title_for_new_topic(lang).await?;
-        let post = self.create_post_new_topic(lang, &title).await?;
-        forum
-            .create_new_discussion(self.user_id, &title, &post)
-            .await?;
-        Ok(())
+        deployment: &Deployment,
+        action: &mut ActionRecord,
+        title: &str,
+        replied_to: Option<&Post>,
+        content: &str,
+    ) {
+        if !deployment.memory.enabled || deployment.dry_run.is_some() {
+            return;
+        }
+        let person = replied_to.and_then(|post| post.author.as_ref());
+        let counter = TokenCounter::for_model(self.model(deployment));
+        let answered = replied_to.map(|post| {
+            let content = counter.truncate(&post.content, MEMORY_CONTEXT_TOKENS);
+            delimit(person.map(|a| a.display_name.as_str()), &content)
+        });
+        let answered = answered.as_deref().map(|post| {
+            let name = person.map_or("?", |a| a.display_name.as_str());
+            (name, post)
+        });
+        let prompt = deployment
+            .lang
+            .get_remember_prompt(&inline(title), answered, content);
+        let summary = match self.ask_gpt(deployment, action, &prompt).await {
+            Ok(summary) => summary,
+            Err(e) => {
+                eprintln!("Could not summarize the post to remember it: {}", e);
+                return;
+            }
+        };
+        let memory = Memory {
+            created_at: action.created_at.clone(),
+            forum: deployment.name.clone(),
+            user_id: self.user_id,
+            discussion_id: action.discussion_id,
+            title: inline(title),
+            person_id: person.map(|a| a.id),
+            person: person.map(|a| a.display_name.clone()),
+            summary: summary.trim().to_string(),
+            ..Default::default()
+        };
+        match deployment.store.add_memory(&memory) {
+            Ok(id) => {
+                if let Some(embeddings) = &deployment.embeddings {
+                    let memory = Memory { id, ..memory };
+                    if let Err(e) = embeddings.index_memory(&deployment.store, &memory).await {
+                        eprintln!("Could not embed memory: {}", e);
+                    }
+                }
+            }
+            Err(e) => eprintln!("Could not store memory: {}", e),
+        }
+    }
+
+    /// Has the LLM rate the tone of a reply to another character and updates
+    /// their affinities with it.
+    async fn judge_tone(
+        &self,
+        deployment: &Deployment,
+        action: &mut ActionRecord,
+        other: &Character,
+        content: &str,
+    ) {
+        if !deployment.relationships.evolve || deployment.dry_run.is_some() {
+            return;
+        }
+        let prompt = deployment.lang.get_tone_prompt(&other.name, content);
+        let answer = match self.ask_gpt(deployment, action, &prompt).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                eprintln!("Could not judge the tone of the reply: {}", e);
+                return;
+            }
+        };
+        let tone = answer
+            .trim()
+            .trim_matches(|c: char| !(c.is_ascii_digit() || c == '-' || c == '+' || c == '.'))
+            .parse::<f64>();
+        match tone {
+            Ok(tone) => relationships::interact(deployment, self, other, tone),
+            Err(_) => eprintln!("Could not understand the tone of the reply: {}", answer),
+        }
+    }
+
+    /// Lets the LLM pick tags for a new discussion among those the forum
+    /// offers, then makes the choice fit the forum's rules.
+    pub async fn choose_tags(
+        &self,
+        deployment: &Deployment,
+        action: &mut ActionRecord,
+        title: &str,
+    ) -> Result<Vec<Tag>, Box<dyn Error>> {
+        let catalog = match deployment.forum.list_tags(self.user_id).await {
+            Ok(catalog) => catalog,
+            Err(e) => {
+                eprintln!("Could not list tags, using the default tag: {}", e);
+                return Ok(Vec::new());
+            }
+        };
+        if catalog.tags.is_empty() {
+            return Ok(Vec::new());
+        }
+        let tag_list = catalog
+            .tags
+            .iter()
+            .map(|tag| {
+                let mut line = format!("- {}: {}", tag.slug, tag.name);
+                if tag.primary {
+                    line.push_str(" [primary]");
+                }
+                if let Some(description) = &tag.description {
+                    line.push_str(&format!(" ({})", description));
+                }
+                line
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        let preferred: Vec<&Tag> = self.tags.iter().filter_map(|t| catalog.find(t)).collect();
+        let preferred_list = preferred
+            .iter()
+            .map(|t| t.slug.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+        let prompt =
+            deployment
+                .lang
+                .get_tags_prompt(title, &tag_list, &preferred_list, &catalog.rules);
+        let answer = self.ask_gpt(deployment, action, &prompt).await?;
+        let requested: Vec<&Tag> = answer
+            .split([',', '\n'])
+            .map(|t| t.trim_matches(|c: char| c.is_whitespace() || "-*`'\"#[]".contains(c)))
+            .filter_map(|t| catalog.find(t))
+            .collect();
+        Ok(catalog.select(&requested, &preferred))
+    }
+
+    pub async fn create_new_topic(&self, deployment: &Deployment) -> Result<(), Box<dyn Error>> {
+        let mut action = self.new_action(deployment, "new_topic");
+        let result = async {
+            let title = self
+                .create_title_for_new_topic(deployment, &mut action)
+                .await?;
+            let post = self
+                .create_post_new_topic(deployment, &mut action, &title)
+                .await?;
+            let tags = if deployment.choose_tags {
+                self.choose_tags(deployment, &mut action, &title).await?
+            } else {
+                Vec::new()
+            };
+            action.content = Some(format!("{}\n\n{}", title, post));
+            action.discussion_id = deployment
+                .publish_discussion(self, &action, &title, &tags, &post)
+                .await?;
+            self.remember(deployment, &mut action, &title, None, &post)
+                .await;
+            Ok(())
+        }
+        .await;
+        deployment.record(&mut action, &result);
+        result
     }
 
+    /// Replies to a discussion, answering its last post unless it is the
+    /// character's own.
     pub async fn post_on_discussion(
         &self,
-        lang: &dyn Language,
-        forum: &Forum,
+        deployment: &Deployment,
         discussion_id: i32,
     ) -> Result<(), Box<dyn Error>> {
-        let last_comments = forum.fetch_discussion(discussion_id).await?;
-        let history = last_comments
-            .recent_posts
-            .iter()
-            .map(|c| c.content.clone())
+        self.reply(deployment, discussion_id, None).await
+    }
+
+    /// Replies to the post `post_id` of a discussion, wherever it is in the
+    /// thread.
+    pub async fn answer_post(
+        &self,
+        deployment: &Deployment,
+        discussion_id: i32,
+        post_id: i32,
+    ) -> Result<(), Box<dyn Error>> {
+        self.reply(deployment, discussion_id, Some(post_id)).await
+    }
+
+    async fn reply(
+        &self,
+        deployment: &Deployment,
+        discussion_id: i32,
+        post_id: Option<i32>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut action = self.new_action(deployment, "reply");
+        action.discussion_id = Some(discussion_id);
+        let result = async {
+            let last_comments = deployment.forum.fetch_discussion(discussion_id).await?;
+            let answered = match post_id {
+                Some(post_id) => Some(
+                    last_comments
+                        .posts
+                        .iter()
+                        .find(|post| post.id == post_id)
+                        .ok_or_else(|| {
+                            format!("Post {} is not in discussion {}", post_id, discussion_id)
+                        })?,
+                ),
+                None => last_comments
+                    .posts
+                    .last()
+                    .filter(|post| !self.is_author_of(post)),
+            };
+            // Another character being answered is seen through the
+            // relationship with them.
+            let other = answered
+                .and_then(|post| post.author.as_ref())
+                .and_then(|author| {
+                    deployment
+                        .characters
+                        .iter()
+                        .find(|c| c.user_id == author.id)
+                });
+            let character = match other {
+                Some(other) => Character {
+                    system_message: relationships::system_message(deployment, self, other),
+                    ..self.clone()
+                },
+                None => self.clone(),
+            };
+            let query = index_discussion(deployment, &last_comments).await;
+            let recollection = [
+                recall(deployment, self, &last_comments, query.as_deref()),
+                related_discussions(deployment, &last_comments, query.as_deref()),
+            ]
+            .into_iter()
+            .filter(|part| !part.is_empty())
             .collect::<Vec<String>>()
             .join("\n\n");
-        let msg = lang.get_reply_prompt(&last_comments.title, &history);
-        let content = self.ask_gpt(msg.as_str()).await?;
-        forum
-            .write_post(self.user_id, discussion_id, &content)
-            .await?;
-        Ok(())
+            let request = character.reply_request(deployment, &last_comments, &recollection);
+            let content = character
+                .ask_moderated(deployment, &mut action, request)
+                .await?;
+            action.content = Some(content.clone());
+            let reply_to = answered.map(|post| ReplyTo::new(post, deployment.quote_replies));
+            action.post_id = deployment
+                .publish_reply(
+                    &character,
+                    &action,
+                    discussion_id,
+                    reply_to.as_ref(),
+                    &content,
+                )
+                .await?;
+            if let Some(other) = other {
+                self.judge_tone(deployment, &mut action, other, &content)
+                    .await;
+            }
+            let title = &last_comments.discussion.title;
+            self.remember(deployment, &mut action, title, answered, &content)
+                .await;
+            Ok(())
+        }
+        .await;
+        deployment.record(&mut action, &result);
+        result
     }
 
-    pub async fn interact_with_forum(
+    /// The character's to-do list: unread notifications about conversations
+    /// involving it, oldest first. They are marked read unless `peek` is set
+    /// or in dry-run mode.
+    pub async fn inbox(
         &self,
-        lang: &dyn Language,
-        forum: &Forum,
-    ) -> Result<(), Box<dyn Error>> {
+        deployment: &Deployment,
+        peek: bool,
+    ) -> Result<Vec<Notification>, Box<dyn Error>> {
+        let notifications = deployment.forum.notifications(self.user_id).await?;
+        if !peek && deployment.dry_run.is_none() {
+            for notification in &notifications {
+                deployment
+                    .forum
+                    .mark_notification_read(self.user_id, notification.id)
+                    .await?;
+            }
+        }
+        Ok(notifications)
+    }
+
+    pub async fn interact_with_forum(&self, deployment: &Deployment) -> Result<(), Box<dyn Error>> {
         let mut should_create_new_topic = dice_roll(20);
         if !should_create_new_topic {
-            let random_discussion = forum.get_random_discussion(self.user_id).await?;
-            match random_discussion {
+            let query = deployment.listing.random_query();
+            let discussions = deployment
+                .forum
+                .list_discussions(self.user_id, &query)
+                .await?;
+            let context = SelectionContext::new(deployment)?;
+            match deployment.selector.select(&discussions, &context) {
                 Some(discussion) => {
-                    self.post_on_discussion(lang, forum, discussion.id).await?;
+                    self.post_on_discussion(deployment, discussion.id).await?;
                 }
                 None => {
-                    println!("No discussions found, creating a new one");
+                    println!("No discussions to reply to, creating a new one");
                     should_create_new_topic = true;
                 }
             }
         }
         if should_create_new_topic {
-            self.create_new_topic(lang, forum).await?;
+            self.create_new_topic(deployment).await?;
         }
         Ok(())
     }
 }
 
-pub fn get_character(lang: &dyn Language, user_id: Option<i32>) -> Character {
+pub fn get_character(characters: &[Character], user_id: Option<i32>) -> Option<Character> {
     match user_id {
-        None => get_random_character(lang),
-        Some(user_id) => lang
-            .get_character_list()
-            .iter()
-            .find(|c| c.user_id == user_id)
-            .unwrap()
-            .clone(),
+        None => get_random_character(characters),
+        Some(user_id) => characters.iter().find(|c| c.user_id == user_id).cloned(),
     }
 }
 
-fn get_random_character(lang: &dyn Language) -> Character {
+fn get_random_character(characters: &[Character]) -> Option<Character> {
     let mut rng = rand::thread_rng();
-    lang.get_character_list().choose(&mut rng).unwrap().clone()
+    characters
+        .choose_weighted(&mut rng, |c| c.weight)
+        .ok()
+        .cloned()
 }
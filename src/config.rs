@@ -0,0 +1,276 @@
+use std::{
+    collections::BTreeMap,
+    error::Error as StdError,
+    fmt::{self, Display, Formatter},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    discourse,
+    embeddings::EmbeddingConfig,
+    english::EnglishLanguage,
+    flarum,
+    forum::{BackendKind, ForumBackend, ListingConfig},
+    language::Language,
+    listener::ListenerConfig,
+    llm::{LlmConfig, LlmError, LlmProvider},
+    memory::MemoryConfig,
+    moderation::ModerationConfig,
+    portuguese::PortugueseLanguage,
+    relationships::RelationshipConfig,
+    scheduler::ScheduleConfig,
+    selection::SelectionConfig,
+};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    UnknownForum(String),
+    UnknownLanguage(String),
+    Invalid(String, String),
+    MissingEnv(String),
+    Llm(LlmError),
+}
+
+impl StdError for ConfigError {}
+
+impl From<LlmError> for ConfigError {
+    fn from(error: LlmError) -> Self {
+        ConfigError::Llm(error)
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Parse(path, error) => write!(f, "{}: {}", path.display(), error),
+            ConfigError::UnknownForum(name) => write!(f, "Unknown forum: {}", name),
+            ConfigError::UnknownLanguage(code) => write!(f, "Unknown language: {}", code),
+            ConfigError::Invalid(forum, message) => write!(f, "forums.{}: {}", forum, message),
+            ConfigError::MissingEnv(var) => write!(f, "{} must be set", var),
+            ConfigError::Llm(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+fn default_api_key_env() -> String {
+    "FLARUM_API_KEY".to_string()
+}
+
+fn default_tag() -> String {
+    "1".to_string()
+}
+
+fn default_choose_tags() -> bool {
+    true
+}
+
+fn default_history_posts() -> usize {
+    20
+}
+
+fn default_personas() -> Vec<PathBuf> {
+    vec![PathBuf::from("personas")]
+}
+
+fn default_state_db() -> PathBuf {
+    PathBuf::from("autoforum.db")
+}
+
+/// One forum deployment, i.e. a `[forums.<name>]` table.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ForumConfig {
+    /// Key of the `[forums.<name>]` table, filled in after loading.
+    #[serde(skip)]
+    pub name: String,
+    #[serde(default)]
+    pub backend: BackendKind,
+    /// Flarum's API URL (ending in `/api`), or the Discourse site URL.
+    pub api_url: String,
+    #[serde(default = "default_api_key_env")]
+    pub api_key_env: String,
+    pub language: String,
+    /// Tag id (Flarum) or category id (Discourse) of new discussions.
+    #[serde(default = "default_tag")]
+    pub default_tag: String,
+    /// Number of posts, after the opening post, that reply prompts show of
+    /// a discussion.
+    #[serde(default = "default_history_posts")]
+    pub history_posts: usize,
+    /// Let the LLM pick the tags of new discussions among the forum's tags.
+    /// When off, `default_tag` is used.
+    #[serde(default = "default_choose_tags")]
+    pub choose_tags: bool,
+    /// Quote the passage being answered at the top of replies.
+    #[serde(default)]
+    pub quote_replies: bool,
+    /// Send reply histories as a conversation, with the character's own
+    /// posts as assistant messages, instead of a single prompt.
+    #[serde(default)]
+    pub chat_history: bool,
+    pub llm: LlmConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub discussions: ListingConfig,
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    /// Enables related discussions and memories found by similarity.
+    pub embeddings: Option<EmbeddingConfig>,
+    #[serde(default)]
+    pub relationships: RelationshipConfig,
+    #[serde(default)]
+    pub selection: SelectionConfig,
+    #[serde(default)]
+    pub listener: ListenerConfig,
+    #[serde(default)]
+    pub moderation: ModerationConfig,
+}
+
+impl ForumConfig {
+    pub fn language(&self) -> Result<Box<dyn Language>, ConfigError> {
+        match self.language.as_str() {
+            "en" => Ok(Box::new(EnglishLanguage)),
+            "pt" => Ok(Box::new(PortugueseLanguage)),
+            other => Err(ConfigError::UnknownLanguage(other.to_string())),
+        }
+    }
+
+    pub fn forum(&self) -> Result<Box<dyn ForumBackend>, ConfigError> {
+        dotenvy::dotenv().ok();
+        let api_key = dotenvy::var(&self.api_key_env)
+            .map_err(|_| ConfigError::MissingEnv(self.api_key_env.clone()))?;
+        match self.backend {
+            BackendKind::Flarum => Ok(Box::new(flarum::Forum::new(
+                &self.api_url,
+                &api_key,
+                &self.default_tag,
+            ))),
+            BackendKind::Discourse => {
+                let category = self.default_tag.parse().map_err(|_| {
+                    ConfigError::Invalid(
+                        self.name.clone(),
+                        "default_tag must be a Discourse category id".to_string(),
+                    )
+                })?;
+                Ok(Box::new(discourse::Forum::new(
+                    &self.api_url,
+                    &api_key,
+                    category,
+                )))
+            }
+        }
+    }
+
+    pub fn llm(&self) -> Result<Box<dyn LlmProvider>, ConfigError> {
+        Ok(self.llm.build_provider()?)
+    }
+
+    /// API keys of the forum and of the services it uses, which generated
+    /// texts must never contain.
+    pub fn secrets(&self) -> Vec<String> {
+        dotenvy::dotenv().ok();
+        let mut secrets = vec![dotenvy::var(&self.api_key_env).ok(), self.llm.api_key()];
+        let vars = [
+            self.embeddings
+                .as_ref()
+                .and_then(|e| e.api_key_env.as_deref()),
+            self.moderation
+                .openai
+                .as_ref()
+                .map(|o| o.api_key_env.as_deref().unwrap_or("OPENAI_API_KEY")),
+        ];
+        secrets.extend(vars.into_iter().flatten().map(|var| dotenvy::var(var).ok()));
+        secrets.into_iter().flatten().collect()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// Forum used when `--forum` is not given.
+    pub default_forum: Option<String>,
+    /// Persona files or directories of persona files.
+    #[serde(default = "default_personas")]
+    pub personas: Vec<PathBuf>,
+    /// SQLite database where every action is recorded.
+    #[serde(default = "default_state_db")]
+    pub state_db: PathBuf,
+    pub forums: BTreeMap<String, ForumConfig>,
+}
+
+impl Config {
+    /// Loads the config file, or falls back to the two fbmac.net forums with
+    /// the LLM taken from the environment when the file does not exist.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let mut config: Self = match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| ConfigError::Parse(path.into(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::builtin()?,
+            Err(e) => return Err(ConfigError::Io(path.into(), e)),
+        };
+        for (name, forum) in config.forums.iter_mut() {
+            forum.name = name.clone();
+            forum
+                .schedule
+                .validate()
+                .and_then(|_| forum.discussions.validate())
+                .and_then(|_| forum.selection.validate())
+                .and_then(|_| forum.listener.validate())
+                .and_then(|_| forum.moderation.validate())
+                .and_then(|_| forum.llm.retry.validate())
+                .map_err(|message| ConfigError::Invalid(name.clone(), message))?;
+        }
+        Ok(config)
+    }
+
+    fn builtin() -> Result<Self, ConfigError> {
+        let llm = LlmConfig::from_env()?;
+        let forum = |api_url: &str, language: &str| ForumConfig {
+            name: String::new(),
+            backend: BackendKind::Flarum,
+            api_url: api_url.to_string(),
+            api_key_env: default_api_key_env(),
+            language: language.to_string(),
+            default_tag: default_tag(),
+            history_posts: default_history_posts(),
+            choose_tags: default_choose_tags(),
+            quote_replies: false,
+            chat_history: false,
+            llm: llm.clone(),
+            schedule: ScheduleConfig::default(),
+            discussions: ListingConfig::default(),
+            memory: MemoryConfig::default(),
+            embeddings: None,
+            relationships: RelationshipConfig::default(),
+            selection: SelectionConfig::default(),
+            listener: ListenerConfig::default(),
+            moderation: ModerationConfig::default(),
+        };
+        let mut forums = BTreeMap::new();
+        forums.insert("en".to_string(), forum("https://forum.fbmac.net/api", "en"));
+        forums.insert(
+            "pt".to_string(),
+            forum("https://forumbr.fbmac.net/api", "pt"),
+        );
+        Ok(Self {
+            default_forum: Some("en".to_string()),
+            personas: default_personas(),
+            state_db: default_state_db(),
+            forums,
+        })
+    }
+
+    pub fn forum(&self, name: Option<&str>) -> Result<&ForumConfig, ConfigError> {
+        let name = name
+            .or(self.default_forum.as_deref())
+            .or_else(|| self.forums.keys().next().map(|k| k.as_str()))
+            .unwrap_or_default();
+        self.forums
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownForum(name.to_string()))
+    }
+}
@@ -0,0 +1,172 @@
+use std::error::Error;
+
+use crate::{
+    characters::{get_character, Character},
+    config::{Config, ForumConfig},
+    dry_run::{DryRun, DryRunOutput},
+    embeddings::Embeddings,
+    forum::{ForumBackend, ListingConfig, ReplyTo, Tag},
+    language::Language,
+    llm::{LlmConfig, LlmProvider},
+    memory::MemoryConfig,
+    moderation::Moderator,
+    personas::load_characters,
+    relationships::RelationshipConfig,
+    selection::DiscussionSelector,
+    store::{ActionRecord, Store},
+};
+
+/// Everything needed to run the characters of one configured forum.
+pub struct Deployment {
+    pub name: String,
+    pub lang: Box<dyn Language>,
+    pub forum: Box<dyn ForumBackend>,
+    pub llm: Box<dyn LlmProvider>,
+    pub llm_config: LlmConfig,
+    pub characters: Vec<Character>,
+    pub store: Store,
+    /// Where characters look for discussions to reply to.
+    pub listing: ListingConfig,
+    /// Which of them they pick.
+    pub selector: DiscussionSelector,
+    /// Posts shown to the LLM after the opening post of a discussion.
+    pub history_posts: usize,
+    /// Whether the LLM picks the tags of new discussions.
+    pub choose_tags: bool,
+    /// Whether replies quote the passage they answer.
+    pub quote_replies: bool,
+    /// Whether reply histories are sent as a conversation.
+    pub chat_history: bool,
+    pub memory: MemoryConfig,
+    pub embeddings: Option<Embeddings>,
+    pub relationships: RelationshipConfig,
+    /// Checks generated texts go through before being published.
+    pub moderator: Moderator,
+    /// When set, reads and LLM calls happen as usual but nothing is posted.
+    pub dry_run: Option<DryRun>,
+}
+
+impl Deployment {
+    pub fn new(
+        config: &Config,
+        forum_config: &ForumConfig,
+        dry_run: Option<DryRun>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let lang = forum_config.language()?;
+        let characters = load_characters(
+            &config.personas,
+            &forum_config.name,
+            &forum_config.language,
+            lang.as_ref(),
+        )?;
+        Ok(Self {
+            name: forum_config.name.clone(),
+            forum: forum_config.forum()?,
+            llm: forum_config.llm()?,
+            llm_config: forum_config.llm.clone(),
+            lang,
+            characters,
+            store: Store::open(&config.state_db)?,
+            listing: forum_config.discussions.clone(),
+            selector: DiscussionSelector::new(&forum_config.selection),
+            history_posts: forum_config.history_posts,
+            choose_tags: forum_config.choose_tags,
+            quote_replies: forum_config.quote_replies,
+            chat_history: forum_config.chat_history,
+            memory: forum_config.memory.clone(),
+            embeddings: forum_config
+                .embeddings
+                .as_ref()
+                .map(|e| e.build(&forum_config.llm))
+                .transpose()?,
+            relationships: forum_config.relationships.clone(),
+            moderator: forum_config
+                .moderation
+                .build(&forum_config.llm, forum_config.secrets())?,
+            dry_run,
+        })
+    }
+
+    fn dry_run_output<'a>(
+        &'a self,
+        character: &'a Character,
+        action: &'a ActionRecord,
+        title: Option<&'a str>,
+        tags: &'a [Tag],
+        content: &'a str,
+    ) -> DryRunOutput<'a> {
+        DryRunOutput {
+            forum: &self.name,
+            character: &character.name,
+            user_id: character.user_id,
+            kind: &action.kind,
+            discussion_id: action.discussion_id,
+            title,
+            tags: tags.iter().map(|t| t.name.as_str()).collect(),
+            system_message: &character.system_message,
+            prompt: &action.prompt,
+            content,
+        }
+    }
+
+    /// Creates a discussion and returns its id, or `None` in dry-run mode.
+    pub async fn publish_discussion(
+        &self,
+        character: &Character,
+        action: &ActionRecord,
+        title: &str,
+        tags: &[Tag],
+        content: &str,
+    ) -> Result<Option<i32>, Box<dyn Error>> {
+        if let Some(dry_run) = &self.dry_run {
+            dry_run.emit(&self.dry_run_output(character, action, Some(title), tags, content))?;
+            return Ok(None);
+        }
+        let discussion_id = self
+            .forum
+            .create_discussion(character.user_id, title, content, tags)
+            .await?;
+        Ok(Some(discussion_id))
+    }
+
+    /// Replies to a discussion and returns the post id, or `None` in dry-run
+    /// mode.
+    pub async fn publish_reply(
+        &self,
+        character: &Character,
+        action: &ActionRecord,
+        discussion_id: i32,
+        reply_to: Option<&ReplyTo<'_>>,
+        content: &str,
+    ) -> Result<Option<i32>, Box<dyn Error>> {
+        if let Some(dry_run) = &self.dry_run {
+            let system_message = character.reply_system_message(self);
+            dry_run.emit(&DryRunOutput {
+                system_message: &system_message,
+                ..self.dry_run_output(character, action, None, &[], content)
+            })?;
+            return Ok(None);
+        }
+        let post_id = self
+            .forum
+            .reply(character.user_id, discussion_id, reply_to, content)
+            .await?;
+        Ok(Some(post_id))
+    }
+
+    /// Stores the outcome of an action. Failing to record is reported but
+    /// doesn't fail the action itself.
+    pub fn record<T, E: ToString>(&self, action: &mut ActionRecord, result: &Result<T, E>) {
+        action.finish(result);
+        if self.dry_run.is_some() && result.is_ok() {
+            action.outcome = "dry_run".to_string();
+        }
+        if let Err(e) = self.store.record(action) {
+            eprintln!("Could not record action: {}", e);
+        }
+    }
+
+    pub fn character(&self, user_id: Option<i32>) -> Option<Character> {
+        get_character(&self.characters, user_id)
+    }
+}
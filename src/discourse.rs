@@ -0,0 +1,1179 @@
+use std::{
+    collections::HashMap,
+    error::Error as StdError,
+    fmt::{self, Display, Formatter},
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use html2md::parse_html;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, InvalidHeaderValue, CONTENT_TYPE},
+    Response,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+use crate::forum::{
+    Discussion, DiscussionData, DiscussionQuery, DiscussionSort, ForumBackend, ForumError,
+    ForumPost, ForumUser, Notification, NotificationKind, Post, ReplyTo, Tag, TagCatalog, TagRules,
+};
+
+/// Account used for requests that aren't made on behalf of a character, such
+/// as resolving user ids to usernames. It exists on every Discourse site and
+/// is an admin.
+const SYSTEM_USERNAME: &str = "system";
+
+/// Posts requested at once when loading a long topic.
+const POSTS_CHUNK_SIZE: usize = 50;
+
+/// Default of the `max_tags_per_topic` site setting.
+const MAX_TAGS_PER_TOPIC: usize = 5;
+
+/// Topics per page of Discourse topic lists.
+const DEFAULT_PAGE_SIZE: u32 = 30;
+
+#[derive(Debug)]
+pub enum DiscourseError {
+    /// The API answered with a non-success status.
+    Api {
+        status: u16,
+        errors: Vec<String>,
+    },
+    Http(reqwest::Error),
+    InvalidHeader(InvalidHeaderValue),
+    InvalidResponse(String),
+}
+
+impl StdError for DiscourseError {}
+
+impl From<reqwest::Error> for DiscourseError {
+    fn from(error: reqwest::Error) -> Self {
+        DiscourseError::Http(error)
+    }
+}
+
+impl From<InvalidHeaderValue> for DiscourseError {
+    fn from(error: InvalidHeaderValue) -> Self {
+        DiscourseError::InvalidHeader(error)
+    }
+}
+
+impl Display for DiscourseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DiscourseError::Api { status, errors } if errors.is_empty() => {
+                write!(f, "Discourse API error {}", status)
+            }
+            DiscourseError::Api { status, errors } => {
+                write!(f, "Discourse API error {}: {}", status, errors.join("; "))
+            }
+            DiscourseError::Http(error) => write!(f, "Discourse request failed: {}", error),
+            DiscourseError::InvalidHeader(error) => {
+                write!(f, "Invalid Discourse API key or username: {}", error)
+            }
+            DiscourseError::InvalidResponse(message) => {
+                write!(f, "Invalid Discourse response: {}", message)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorsResponse {
+    errors: Vec<String>,
+}
+
+async fn check_response<T: DeserializeOwned>(resp: Response) -> Result<T, DiscourseError> {
+    let status = resp.status();
+    let body = resp.text().await?;
+    if status.is_success() {
+        return serde_json::from_str(&body)
+            .map_err(|e| DiscourseError::InvalidResponse(e.to_string()));
+    }
+    let errors = match serde_json::from_str::<ErrorsResponse>(&body) {
+        Ok(parsed) => parsed.errors,
+        Err(_) => {
+            println!("Discourse error response: {}", body);
+            Vec::new()
+        }
+    };
+    Err(DiscourseError::Api {
+        status: status.as_u16(),
+        errors,
+    })
+}
+
+#[derive(Deserialize)]
+struct BasicUser {
+    id: i32,
+    username: String,
+    name: Option<String>,
+}
+
+impl From<BasicUser> for ForumUser {
+    fn from(user: BasicUser) -> Self {
+        ForumUser {
+            id: user.id,
+            display_name: user
+                .name
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| user.username.clone()),
+            username: user.username,
+        }
+    }
+}
+
+/// Tags are plain names, or objects when the site enables tag ids.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TopicTag {
+    Name(String),
+    Object { name: String, slug: String },
+}
+
+impl From<TopicTag> for Tag {
+    fn from(tag: TopicTag) -> Self {
+        let (name, slug) = match tag {
+            TopicTag::Name(name) => (name.clone(), name),
+            TopicTag::Object { name, slug } => (name, slug),
+        };
+        Tag {
+            id: name.clone(),
+            name,
+            slug,
+            description: None,
+            primary: false,
+            parent: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TagsResponse {
+    tags: Vec<TagsEntry>,
+}
+
+#[derive(Deserialize)]
+struct TagsEntry {
+    /// The tag name.
+    text: String,
+    description: Option<String>,
+}
+
+impl From<TagsEntry> for Tag {
+    fn from(tag: TagsEntry) -> Self {
+        Tag {
+            id: tag.text.clone(),
+            name: tag.text.clone(),
+            slug: tag.text,
+            description: tag.description.filter(|d| !d.is_empty()),
+            primary: false,
+            parent: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LatestResponse {
+    #[serde(default)]
+    users: Vec<BasicUser>,
+    topic_list: TopicList,
+}
+
+#[derive(Deserialize)]
+struct TopicList {
+    topics: Vec<TopicSummary>,
+}
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    #[serde(default)]
+    topics: Vec<TopicSummary>,
+}
+
+#[derive(Deserialize)]
+struct Poster {
+    user_id: i32,
+}
+
+#[derive(Deserialize)]
+struct TopicSummary {
+    id: i32,
+    title: String,
+    created_at: DateTime<Utc>,
+    last_posted_at: Option<DateTime<Utc>>,
+    posts_count: i32,
+    #[serde(default)]
+    tags: Vec<TopicTag>,
+    #[serde(default)]
+    posters: Vec<Poster>,
+    #[serde(default)]
+    closed: bool,
+    #[serde(default)]
+    archived: bool,
+    #[serde(default)]
+    pinned: bool,
+    #[serde(default = "default_true")]
+    visible: bool,
+    last_poster_username: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+const REGULAR_POST_TYPE: i32 = 1;
+const MODERATOR_ACTION_POST_TYPE: i32 = 2;
+
+fn regular_post_type() -> i32 {
+    REGULAR_POST_TYPE
+}
+
+#[derive(Deserialize)]
+struct TopicResponse {
+    id: i32,
+    title: String,
+    created_at: DateTime<Utc>,
+    last_posted_at: Option<DateTime<Utc>>,
+    posts_count: i32,
+    #[serde(default)]
+    tags: Vec<TopicTag>,
+    details: TopicDetails,
+    post_stream: PostStream,
+}
+
+impl TopicResponse {
+    /// The topic as a discussion, and its first posts.
+    fn into_parts(self) -> (Discussion, PostStream) {
+        let discussion = Discussion {
+            id: self.id,
+            title: self.title,
+            author: self.details.created_by.map(ForumUser::from),
+            created_at: self.created_at,
+            last_posted_at: self.last_posted_at,
+            last_poster: self.details.last_poster.map(ForumUser::from),
+            reply_count: (self.posts_count - 1).max(0),
+            tags: self.tags.into_iter().map(Tag::from).collect(),
+        };
+        (discussion, self.post_stream)
+    }
+}
+
+#[derive(Deserialize)]
+struct TopicDetails {
+    created_by: Option<BasicUser>,
+    last_poster: Option<BasicUser>,
+}
+
+#[derive(Deserialize)]
+struct PostStream {
+    posts: Vec<TopicPost>,
+    /// Ids of every post of the topic, of which `posts` are only the first.
+    #[serde(default)]
+    stream: Vec<i32>,
+}
+
+#[derive(Deserialize)]
+struct TopicPostsResponse {
+    post_stream: PostStream,
+}
+
+#[derive(Deserialize)]
+struct TopicPost {
+    id: i32,
+    #[serde(default = "regular_post_type")]
+    post_type: i32,
+    post_number: i32,
+    created_at: DateTime<Utc>,
+    cooked: String,
+    user_id: Option<i32>,
+    username: Option<String>,
+    name: Option<String>,
+}
+
+impl From<TopicPost> for Post {
+    fn from(post: TopicPost) -> Self {
+        let author = match (post.user_id, post.username) {
+            (Some(id), Some(username)) => Some(
+                BasicUser {
+                    id,
+                    username,
+                    name: post.name,
+                }
+                .into(),
+            ),
+            _ => None,
+        };
+        Post {
+            id: post.id,
+            number: post.post_number,
+            author,
+            created_at: post.created_at,
+            content: parse_html(&post.cooked),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LatestPostsResponse {
+    latest_posts: Vec<LatestPost>,
+}
+
+#[derive(Deserialize)]
+struct LatestPost {
+    #[serde(flatten)]
+    post: TopicPost,
+    topic_id: i32,
+    reply_to_user: Option<ReplyToUser>,
+}
+
+#[derive(Deserialize)]
+struct ReplyToUser {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct UserResponse {
+    user: BasicUser,
+}
+
+/// Usernames of the users mentioned in a cooked post.
+fn mentions(cooked: &str) -> Vec<String> {
+    const MENTION: &str = "class=\"mention\" href=\"/u/";
+    let mut usernames = Vec::new();
+    let mut rest = cooked;
+    while let Some(start) = rest.find(MENTION) {
+        rest = &rest[start + MENTION.len()..];
+        if let Some(end) = rest.find('"') {
+            let username = rest[..end].to_string();
+            if !usernames.contains(&username) {
+                usernames.push(username);
+            }
+        }
+    }
+    usernames
+}
+
+#[derive(Deserialize)]
+struct NotificationsResponse {
+    notifications: Vec<NotificationEntry>,
+}
+
+#[derive(Deserialize)]
+struct NotificationEntry {
+    id: i32,
+    notification_type: i32,
+    read: bool,
+    created_at: DateTime<Utc>,
+    topic_id: Option<i32>,
+    #[serde(default)]
+    data: NotificationData,
+}
+
+#[derive(Deserialize, Default)]
+struct NotificationData {
+    original_post_id: Option<i32>,
+    /// Username of whoever triggered the notification.
+    original_username: Option<String>,
+}
+
+/// Kind of a notification by its type, for those about conversations.
+fn notification_kind(notification_type: i32) -> Option<NotificationKind> {
+    match notification_type {
+        1 | 15 => Some(NotificationKind::Mention),
+        2 | 3 => Some(NotificationKind::Reply),
+        5 => Some(NotificationKind::Like),
+        // A new post in a watched topic.
+        9 => Some(NotificationKind::NewPost),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+struct CreatedPost {
+    id: i32,
+    topic_id: i32,
+}
+
+/// Open topics of a topic list, but those `username` posted last in.
+fn topic_discussions(latest: LatestResponse, username: &str) -> Vec<Discussion> {
+    let users: HashMap<i32, ForumUser> =
+        latest.users.into_iter().map(|u| (u.id, u.into())).collect();
+    let user = |id: Option<i32>| id.and_then(|id| users.get(&id)).cloned();
+    latest
+        .topic_list
+        .topics
+        .into_iter()
+        .filter(|t| t.visible && !t.closed && !t.archived && !t.pinned)
+        .filter(|t| t.last_poster_username.as_deref() != Some(username))
+        .map(|t| {
+            // Discourse lists the original poster first. The descriptions
+            // that say so are translated.
+            let author = t.posters.first().map(|p| p.user_id);
+            let last_poster = users
+                .values()
+                .find(|u| Some(&u.username) == t.last_poster_username.as_ref())
+                .cloned();
+            Discussion {
+                id: t.id,
+                title: t.title,
+                author: user(author),
+                created_at: t.created_at,
+                last_posted_at: t.last_posted_at,
+                last_poster,
+                reply_count: (t.posts_count - 1).max(0),
+                tags: t.tags.into_iter().map(Tag::from).collect(),
+            }
+        })
+        .collect()
+}
+
+/// Comments and moderator actions of a topic, in order.
+fn topic_posts(posts: Vec<TopicPost>) -> Vec<Post> {
+    let mut posts: Vec<Post> = posts
+        .into_iter()
+        .filter(|p| matches!(p.post_type, REGULAR_POST_TYPE | MODERATOR_ACTION_POST_TYPE))
+        .map(Post::from)
+        .collect();
+    posts.sort_by_key(|p| p.number);
+    posts
+}
+
+/// Usernames of the users a post mentions or replies to.
+fn addressed_usernames(latest_post: &LatestPost) -> Vec<String> {
+    let mut usernames = mentions(&latest_post.post.cooked);
+    if let Some(reply_to) = &latest_post.reply_to_user {
+        if !usernames.contains(&reply_to.username) {
+            usernames.push(reply_to.username.clone());
+        }
+    }
+    usernames
+}
+
+/// Body of a request creating a post in a topic, quoting the answered
+/// passage the way the composer does.
+fn reply_body(topic_id: i32, reply_to: Option<&ReplyTo<'_>>, content: &str) -> Value {
+    let mut body = json!({
+        "topic_id": topic_id,
+        "raw": content
+    });
+    if let Some(reply_to) = reply_to {
+        body["reply_to_post_number"] = json!(reply_to.post.number);
+        if let (Some(quote), Some(author)) = (&reply_to.quote, &reply_to.post.author) {
+            body["raw"] = json!(format!(
+                "[quote=\"{}, post:{}, topic:{}\"]\n{}\n[/quote]\n\n{}",
+                author.username, reply_to.post.number, topic_id, quote, content
+            ));
+        }
+    }
+    body
+}
+
+/// Client for the Discourse REST API. The API key must be allowed to act as
+/// any user: each request carries the acting character's username in the
+/// `Api-Username` header.
+pub struct Forum {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    category: i32,
+    /// Usernames by user id, looked up once through the admin API.
+    usernames: Mutex<HashMap<i32, String>>,
+    /// Users by lowercase username, as mentioned or replied to in posts.
+    users: Mutex<HashMap<String, ForumUser>>,
+}
+
+impl Forum {
+    pub fn new(base_url: &str, api_key: &str, category: i32) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            category,
+            usernames: Mutex::new(HashMap::new()),
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get_headers(&self, username: &str) -> Result<HeaderMap, DiscourseError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert("Api-Key", HeaderValue::from_str(&self.api_key)?);
+        headers.insert("Api-Username", HeaderValue::from_str(username)?);
+        Ok(headers)
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        username: &str,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, DiscourseError> {
+        let url = format!("{}{}", self.base_url, path);
+        println!(
+            "Request to Discourse, user {}, GET {} {:?}",
+            username, url, query
+        );
+        let headers = self.get_headers(username)?;
+        let resp = self
+            .client
+            .get(&url)
+            .headers(headers)
+            .query(query)
+            .send()
+            .await?;
+        check_response(resp).await
+    }
+
+    async fn post<T: DeserializeOwned>(
+        &self,
+        username: &str,
+        path: &str,
+        body: &Value,
+    ) -> Result<T, DiscourseError> {
+        let url = format!("{}{}", self.base_url, path);
+        println!("Request to Discourse, user {}, POST {}", username, url);
+        let headers = self.get_headers(username)?;
+        let resp = self
+            .client
+            .post(&url)
+            .headers(headers)
+            .json(body)
+            .send()
+            .await?;
+        check_response(resp).await
+    }
+
+    async fn put<T: DeserializeOwned>(
+        &self,
+        username: &str,
+        path: &str,
+        body: &Value,
+    ) -> Result<T, DiscourseError> {
+        let url = format!("{}{}", self.base_url, path);
+        println!("Request to Discourse, user {}, PUT {}", username, url);
+        let headers = self.get_headers(username)?;
+        let resp = self
+            .client
+            .put(&url)
+            .headers(headers)
+            .json(body)
+            .send()
+            .await?;
+        check_response(resp).await
+    }
+
+    async fn fetch_user(&self, user_id: i32) -> Result<ForumUser, DiscourseError> {
+        let user: BasicUser = self
+            .get(
+                SYSTEM_USERNAME,
+                &format!("/admin/users/{}.json", user_id),
+                &[],
+            )
+            .await?;
+        self.usernames
+            .lock()
+            .unwrap()
+            .insert(user.id, user.username.clone());
+        Ok(user.into())
+    }
+
+    async fn username(&self, user_id: i32) -> Result<String, DiscourseError> {
+        if let Some(username) = self.usernames.lock().unwrap().get(&user_id) {
+            return Ok(username.clone());
+        }
+        Ok(self.fetch_user(user_id).await?.username)
+    }
+
+    async fn user_by_username(&self, username: &str) -> Result<ForumUser, DiscourseError> {
+        let key = username.to_lowercase();
+        if let Some(user) = self.users.lock().unwrap().get(&key) {
+            return Ok(user.clone());
+        }
+        let response: UserResponse = self
+            .get(SYSTEM_USERNAME, &format!("/u/{}.json", username), &[])
+            .await?;
+        self.usernames
+            .lock()
+            .unwrap()
+            .insert(response.user.id, response.user.username.clone());
+        let user: ForumUser = response.user.into();
+        self.users.lock().unwrap().insert(key, user.clone());
+        Ok(user)
+    }
+
+    /// Topic list matching `query`, through the search API when it has
+    /// search terms and a (tag) topic list otherwise.
+    async fn topic_list(
+        &self,
+        username: &str,
+        query: &DiscussionQuery,
+    ) -> Result<LatestResponse, DiscourseError> {
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).max(1);
+        let page = query.offset / limit;
+        if let Some(search) = &query.search {
+            let mut terms = search.clone();
+            // Search has no order by number of posts, so that falls back to
+            // relevance.
+            match query.sort {
+                DiscussionSort::LastPosted => terms.push_str(" order:latest"),
+                DiscussionSort::Newest => terms.push_str(" order:latest_topic"),
+                DiscussionSort::MostComments => {}
+            }
+            if let Some(tag) = &query.tag {
+                terms.push_str(&format!(" tags:{}", tag));
+            }
+            let results: SearchResponse = self
+                .get(
+                    username,
+                    "/search.json",
+                    &[("q", terms), ("page", (page + 1).to_string())],
+                )
+                .await?;
+            return Ok(LatestResponse {
+                users: Vec::new(),
+                topic_list: TopicList {
+                    topics: results.topics,
+                },
+            });
+        }
+        let order = match query.sort {
+            DiscussionSort::LastPosted => "activity",
+            DiscussionSort::MostComments => "posts",
+            DiscussionSort::Newest => "created",
+        };
+        let path = match &query.tag {
+            Some(tag) => format!("/tag/{}/l/latest.json", tag),
+            None => "/latest.json".to_string(),
+        };
+        let params = [
+            ("order", order.to_string()),
+            ("ascending", "false".to_string()),
+            ("page", page.to_string()),
+            ("per_page", limit.to_string()),
+        ];
+        self.get(username, &path, &params).await
+    }
+
+    /// Topics `user_id` could reply to. Search results don't say who posted
+    /// last, so each one is fetched to leave out those where the character
+    /// has the last word.
+    pub async fn list_latest_topics(
+        &self,
+        user_id: i32,
+        query: &DiscussionQuery,
+    ) -> Result<Vec<Discussion>, DiscourseError> {
+        let username = self.username(user_id).await?;
+        let latest = self.topic_list(&username, query).await?;
+        let mut discussions = topic_discussions(latest, &username);
+        if query.search.is_none() {
+            return Ok(discussions);
+        }
+        for discussion in &mut discussions {
+            let topic: TopicResponse = self
+                .get(SYSTEM_USERNAME, &format!("/t/{}.json", discussion.id), &[])
+                .await?;
+            *discussion = topic.into_parts().0;
+        }
+        discussions.retain(|d| {
+            d.last_poster
+                .as_ref()
+                .is_none_or(|u| u.username != username)
+        });
+        Ok(discussions)
+    }
+
+    /// The posts of a topic missing from its first page, a chunk at a time.
+    async fn fetch_remaining_posts(
+        &self,
+        topic_id: i32,
+        post_stream: PostStream,
+    ) -> Result<Vec<Post>, DiscourseError> {
+        let loaded: Vec<i32> = post_stream.posts.iter().map(|p| p.id).collect();
+        let mut posts = post_stream.posts;
+        let missing: Vec<i32> = post_stream
+            .stream
+            .into_iter()
+            .filter(|id| !loaded.contains(id))
+            .collect();
+        for chunk in missing.chunks(POSTS_CHUNK_SIZE) {
+            let query: Vec<_> = chunk
+                .iter()
+                .map(|id| ("post_ids[]", id.to_string()))
+                .collect();
+            let page: TopicPostsResponse = self
+                .get(
+                    SYSTEM_USERNAME,
+                    &format!("/t/{}/posts.json", topic_id),
+                    &query,
+                )
+                .await?;
+            posts.extend(page.post_stream.posts);
+        }
+        Ok(topic_posts(posts))
+    }
+
+    /// The newest posts of the site. Discourse returns a fixed number of them,
+    /// so at most `limit` of those are kept.
+    pub async fn list_latest_posts(&self, limit: u32) -> Result<Vec<ForumPost>, DiscourseError> {
+        let latest: LatestPostsResponse = self.get(SYSTEM_USERNAME, "/posts.json", &[]).await?;
+        let mut posts = Vec::new();
+        for latest_post in latest.latest_posts.into_iter().take(limit as usize) {
+            if latest_post.post.post_type != REGULAR_POST_TYPE {
+                continue;
+            }
+            let mut addressed = Vec::new();
+            for username in addressed_usernames(&latest_post) {
+                match self.user_by_username(&username).await {
+                    Ok(user) => addressed.push(user),
+                    Err(e) => eprintln!("Could not look up user {}: {}", username, e),
+                }
+            }
+            posts.push(ForumPost {
+                discussion_id: latest_post.topic_id,
+                post: latest_post.post.into(),
+                addressed,
+            });
+        }
+        Ok(posts)
+    }
+
+    /// Unread notifications of `user_id` about conversations, oldest first.
+    pub async fn list_notifications(
+        &self,
+        user_id: i32,
+    ) -> Result<Vec<Notification>, DiscourseError> {
+        let username = self.username(user_id).await?;
+        let response: NotificationsResponse = self
+            .get(
+                &username,
+                "/notifications.json",
+                &[("username", username.clone())],
+            )
+            .await?;
+        let mut notifications = Vec::new();
+        for entry in response.notifications {
+            let Some(kind) = notification_kind(entry.notification_type) else {
+                continue;
+            };
+            if entry.read {
+                continue;
+            }
+            let from = match &entry.data.original_username {
+                Some(username) => match self.user_by_username(username).await {
+                    Ok(user) => Some(user),
+                    Err(e) => {
+                        eprintln!("Could not look up user {}: {}", username, e);
+                        None
+                    }
+                },
+                None => None,
+            };
+            notifications.push(Notification {
+                id: entry.id,
+                kind,
+                from,
+                discussion_id: entry.topic_id,
+                post_id: entry.data.original_post_id,
+                created_at: entry.created_at,
+            });
+        }
+        notifications.sort_by_key(|n| n.created_at);
+        Ok(notifications)
+    }
+
+    pub async fn read_notification(
+        &self,
+        user_id: i32,
+        notification_id: i32,
+    ) -> Result<(), DiscourseError> {
+        let username = self.username(user_id).await?;
+        let _: Value = self
+            .put(
+                &username,
+                "/notifications/mark-read.json",
+                &json!({ "id": notification_id }),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn fetch_topic(&self, id: i32) -> Result<DiscussionData, DiscourseError> {
+        let topic: TopicResponse = self
+            .get(SYSTEM_USERNAME, &format!("/t/{}.json", id), &[])
+            .await?;
+        let (discussion, post_stream) = topic.into_parts();
+        let posts = self.fetch_remaining_posts(id, post_stream).await?;
+        Ok(DiscussionData { discussion, posts })
+    }
+
+    /// Every tag of the site. Discourse has no primary tags, the category
+    /// plays that role.
+    pub async fn list_tags(&self, user_id: i32) -> Result<TagCatalog, DiscourseError> {
+        let username = self.username(user_id).await?;
+        let tags: TagsResponse = self.get(&username, "/tags.json", &[]).await?;
+        Ok(TagCatalog {
+            tags: tags.tags.into_iter().map(Tag::from).collect(),
+            rules: TagRules {
+                min_primary: 0,
+                max_primary: 0,
+                min_secondary: 0,
+                max_secondary: MAX_TAGS_PER_TOPIC,
+            },
+        })
+    }
+
+    pub async fn create_topic(
+        &self,
+        user_id: i32,
+        title: &str,
+        content: &str,
+        tags: &[Tag],
+    ) -> Result<i32, DiscourseError> {
+        let username = self.username(user_id).await?;
+        let tags: Vec<&str> = tags.iter().map(|t| t.name.as_str()).collect();
+        let created: CreatedPost = self
+            .post(
+                &username,
+                "/posts.json",
+                &json!({
+                    "title": title,
+                    "raw": content,
+                    "category": self.category,
+                    "tags": tags
+                }),
+            )
+            .await?;
+        Ok(created.topic_id)
+    }
+
+    pub async fn write_post(
+        &self,
+        user_id: i32,
+        topic_id: i32,
+        reply_to: Option<&ReplyTo<'_>>,
+        content: &str,
+    ) -> Result<i32, DiscourseError> {
+        let username = self.username(user_id).await?;
+        let body = reply_body(topic_id, reply_to, content);
+        let created: CreatedPost = self.post(&username, "/posts.json", &body).await?;
+        Ok(created.id)
+    }
+}
+
+#[async_trait]
+impl ForumBackend for Forum {
+    async fn list_discussions(
+        &self,
+        user_id: i32,
+        query: &DiscussionQuery,
+    ) -> Result<Vec<Discussion>, ForumError> {
+        Ok(self.list_latest_topics(user_id, query).await?)
+    }
+
+    async fn fetch_discussion(&self, id: i32) -> Result<DiscussionData, ForumError> {
+        Ok(self.fetch_topic(id).await?)
+    }
+
+    async fn list_tags(&self, user_id: i32) -> Result<TagCatalog, ForumError> {
+        Ok(Forum::list_tags(self, user_id).await?)
+    }
+
+    async fn create_discussion(
+        &self,
+        user_id: i32,
+        title: &str,
+        content: &str,
+        tags: &[Tag],
+    ) -> Result<i32, ForumError> {
+        Ok(self.create_topic(user_id, title, content, tags).await?)
+    }
+
+    async fn reply(
+        &self,
+        user_id: i32,
+        discussion_id: i32,
+        reply_to: Option<&ReplyTo>,
+        content: &str,
+    ) -> Result<i32, ForumError> {
+        Ok(self
+            .write_post(user_id, discussion_id, reply_to, content)
+            .await?)
+    }
+
+    async fn identify(&self, user_id: i32) -> Result<ForumUser, ForumError> {
+        Ok(self.fetch_user(user_id).await?)
+    }
+
+    async fn latest_posts(&self, limit: u32) -> Result<Vec<ForumPost>, ForumError> {
+        Ok(self.list_latest_posts(limit).await?)
+    }
+
+    async fn notifications(&self, user_id: i32) -> Result<Vec<Notification>, ForumError> {
+        Ok(self.list_notifications(user_id).await?)
+    }
+
+    async fn mark_notification_read(
+        &self,
+        user_id: i32,
+        notification_id: i32,
+    ) -> Result<(), ForumError> {
+        Ok(self.read_notification(user_id, notification_id).await?)
+    }
+
+    fn discussion_url(&self, discussion_id: i32) -> String {
+        format!("{}/t/{}", self.base_url, discussion_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic() -> TopicResponse {
+        serde_json::from_value(json!({
+            "id": 5,
+            "title": "Rayleigh",
+            "created_at": "2024-05-01T10:00:00.000Z",
+            "last_posted_at": "2024-05-02T10:00:00.000Z",
+            "posts_count": 4,
+            "tags": ["science", {"id": 3, "name": "Physics", "slug": "physics"}],
+            "details": {
+                "created_by": {"id": 3, "username": "jo", "name": "Jo Human"},
+                "last_poster": {"id": 6, "username": "luke", "name": ""}
+            },
+            "post_stream": {
+                "stream": [50, 51, 52, 53, 54],
+                "posts": [
+                    {
+                        "id": 51,
+                        "post_number": 2,
+                        "created_at": "2024-05-01T11:00:00.000Z",
+                        "user_id": 6,
+                        "username": "luke",
+                        "cooked": "<p>It's <strong>scattering</strong>.</p>"
+                    },
+                    {
+                        "id": 50,
+                        "post_number": 1,
+                        "created_at": "2024-05-01T10:00:00.000Z",
+                        "user_id": 3,
+                        "username": "jo",
+                        "name": "Jo Human",
+                        "cooked": "<p>Why is the sky blue?</p>"
+                    },
+                    {
+                        "id": 52,
+                        "post_type": 3,
+                        "post_number": 3,
+                        "created_at": "2024-05-01T12:00:00.000Z",
+                        "user_id": 1,
+                        "username": "mod",
+                        "cooked": "<p>Moved</p>"
+                    },
+                    {
+                        "id": 53,
+                        "post_type": 2,
+                        "post_number": 4,
+                        "created_at": "2024-05-01T13:00:00.000Z",
+                        "cooked": "<p>Closed</p>"
+                    }
+                ]
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_topics() {
+        let (discussion, post_stream) = topic().into_parts();
+        assert_eq!(discussion.id, 5);
+        assert_eq!(discussion.title, "Rayleigh");
+        assert_eq!(discussion.reply_count, 3);
+        assert_eq!(discussion.author.unwrap().display_name, "Jo Human");
+        // Users without a name go by their username.
+        assert_eq!(discussion.last_poster.unwrap().display_name, "luke");
+        let tags: Vec<&str> = discussion.tags.iter().map(|t| t.slug.as_str()).collect();
+        assert_eq!(tags, ["science", "physics"]);
+        assert_eq!(post_stream.stream, [50, 51, 52, 53, 54]);
+    }
+
+    #[test]
+    fn reads_post_streams() {
+        let (_, post_stream) = topic().into_parts();
+        let posts = topic_posts(post_stream.posts);
+        let ids: Vec<i32> = posts.iter().map(|p| p.id).collect();
+        // Small actions are left out, moderator actions kept.
+        assert_eq!(ids, [50, 51, 53]);
+        assert_eq!(posts[0].content, "Why is the sky blue?");
+        assert_eq!(posts[1].content, "It's **scattering**.");
+        assert_eq!(posts[1].author.as_ref().unwrap().username, "luke");
+        assert!(posts[2].author.is_none());
+    }
+
+    fn latest() -> LatestResponse {
+        serde_json::from_value(json!({
+            "users": [
+                {"id": 3, "username": "jo", "name": "Jo Human"},
+                {"id": 6, "username": "luke", "name": "Luke"}
+            ],
+            "topic_list": {
+                "topics": [
+                    {
+                        "id": 5,
+                        "title": "Rayleigh",
+                        "created_at": "2024-05-01T10:00:00.000Z",
+                        "last_posted_at": "2024-05-02T10:00:00.000Z",
+                        "posts_count": 3,
+                        "tags": ["science"],
+                        "posters": [
+                            {"user_id": 3, "description": "Autor original"},
+                            {"user_id": 6, "description": "Autor mais recente"}
+                        ],
+                        "last_poster_username": "jo"
+                    },
+                    {
+                        "id": 6,
+                        "title": "Answered",
+                        "created_at": "2024-05-01T10:00:00.000Z",
+                        "posts_count": 2,
+                        "last_poster_username": "luke"
+                    },
+                    {
+                        "id": 7,
+                        "title": "Closed",
+                        "created_at": "2024-05-01T10:00:00.000Z",
+                        "posts_count": 1,
+                        "closed": true
+                    },
+                    {
+                        "id": 8,
+                        "title": "About",
+                        "created_at": "2024-05-01T10:00:00.000Z",
+                        "posts_count": 1,
+                        "pinned": true
+                    },
+                    {
+                        "id": 9,
+                        "title": "Unlisted",
+                        "created_at": "2024-05-01T10:00:00.000Z",
+                        "posts_count": 1,
+                        "visible": false
+                    }
+                ]
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn lists_open_topics() {
+        let discussions = topic_discussions(latest(), "leia");
+        let ids: Vec<i32> = discussions.iter().map(|d| d.id).collect();
+        assert_eq!(ids, [5, 6]);
+        let rayleigh = &discussions[0];
+        assert_eq!(rayleigh.reply_count, 2);
+        assert_eq!(rayleigh.author.as_ref().unwrap().username, "jo");
+        assert_eq!(rayleigh.last_poster.as_ref().unwrap().username, "jo");
+        assert_eq!(rayleigh.tags[0].name, "science");
+        assert!(discussions[1].author.is_none());
+    }
+
+    #[test]
+    fn leaves_out_topics_the_character_posted_last_in() {
+        let discussions = topic_discussions(latest(), "luke");
+        let ids: Vec<i32> = discussions.iter().map(|d| d.id).collect();
+        assert_eq!(ids, [5]);
+    }
+
+    #[test]
+    fn finds_mentions() {
+        let cooked = r#"<p><a class="mention" href="/u/luke">@luke</a> and <a class="mention" href="/u/leia">@leia</a>, <a class="mention" href="/u/luke">@luke</a>!</p>"#;
+        assert_eq!(mentions(cooked), ["luke", "leia"]);
+        assert!(mentions("<p>luke@example.com</p>").is_empty());
+    }
+
+    #[test]
+    fn reads_latest_posts_and_who_they_address() {
+        let latest: LatestPostsResponse = serde_json::from_value(json!({
+            "latest_posts": [{
+                "id": 61,
+                "post_number": 5,
+                "created_at": "2024-05-01T10:00:00.000Z",
+                "user_id": 3,
+                "username": "jo",
+                "cooked": "<p><a class=\"mention\" href=\"/u/leia\">@leia</a> agrees</p>",
+                "topic_id": 5,
+                "reply_to_user": {"username": "luke"}
+            }, {
+                "id": 62,
+                "post_number": 6,
+                "created_at": "2024-05-01T10:00:00.000Z",
+                "user_id": 3,
+                "username": "jo",
+                "cooked": "<p><a class=\"mention\" href=\"/u/luke\">@luke</a>?</p>",
+                "topic_id": 5,
+                "reply_to_user": {"username": "luke"}
+            }]
+        }))
+        .unwrap();
+        let post = &latest.latest_posts[0];
+        assert_eq!(post.topic_id, 5);
+        assert_eq!(post.post.post_type, REGULAR_POST_TYPE);
+        assert_eq!(addressed_usernames(post), ["leia", "luke"]);
+        assert_eq!(addressed_usernames(&latest.latest_posts[1]), ["luke"]);
+    }
+
+    #[test]
+    fn maps_notification_types() {
+        assert_eq!(notification_kind(1), Some(NotificationKind::Mention));
+        assert_eq!(notification_kind(15), Some(NotificationKind::Mention));
+        assert_eq!(notification_kind(2), Some(NotificationKind::Reply));
+        assert_eq!(notification_kind(3), Some(NotificationKind::Reply));
+        assert_eq!(notification_kind(5), Some(NotificationKind::Like));
+        assert_eq!(notification_kind(9), Some(NotificationKind::NewPost));
+        assert_eq!(notification_kind(6), None);
+    }
+
+    fn answered(author: Option<&str>) -> Post {
+        Post {
+            id: 51,
+            number: 2,
+            author: author.map(|username| ForumUser {
+                id: 3,
+                username: username.to_string(),
+                display_name: "Jo Human".to_string(),
+            }),
+            created_at: Utc::now(),
+            content: "Hi!\n\nWhy is the sky blue?".to_string(),
+        }
+    }
+
+    #[test]
+    fn quotes_the_answered_post() {
+        let post = answered(Some("jo"));
+        let body = reply_body(5, Some(&ReplyTo::new(&post, true)), "Scattering.");
+        assert_eq!(body["topic_id"], 5);
+        assert_eq!(body["reply_to_post_number"], 2);
+        assert_eq!(
+            body["raw"],
+            "[quote=\"jo, post:2, topic:5\"]\nWhy is the sky blue?\n[/quote]\n\nScattering."
+        );
+    }
+
+    #[test]
+    fn replies_without_quotes() {
+        let post = answered(Some("jo"));
+        let body = reply_body(5, Some(&ReplyTo::new(&post, false)), "Scattering.");
+        assert_eq!(body["reply_to_post_number"], 2);
+        assert_eq!(body["raw"], "Scattering.");
+        // Posts of deleted users can't be attributed.
+        let post = answered(None);
+        let body = reply_body(5, Some(&ReplyTo::new(&post, true)), "Scattering.");
+        assert_eq!(body["raw"], "Scattering.");
+        let body = reply_body(5, None, "Scattering.");
+        assert!(body.get("reply_to_post_number").is_none());
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use serde::Serialize;
+
+/// A write that was not sent to the forum, with everything that produced it.
+#[derive(Serialize, Debug)]
+pub struct DryRunOutput<'a> {
+    pub forum: &'a str,
+    pub character: &'a str,
+    pub user_id: i32,
+    pub kind: &'a str,
+    pub discussion_id: Option<i32>,
+    pub title: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<&'a str>,
+    pub system_message: &'a str,
+    pub prompt: &'a str,
+    pub content: &'a str,
+}
+
+/// Where forum writes go instead of the forum when running with `--dry-run`.
+pub struct DryRun {
+    /// JSON Lines file to append to. Writes are printed to stdout when unset.
+    output: Option<PathBuf>,
+}
+
+impl DryRun {
+    pub fn new(output: Option<PathBuf>) -> Self {
+        Self { output }
+    }
+
+    pub fn emit(&self, output: &DryRunOutput) -> io::Result<()> {
+        match &self.output {
+            Some(path) => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", serde_json::to_string(output)?)?;
+                println!(
+                    "Dry run: {} by {} written to {}",
+                    output.kind,
+                    output.character,
+                    path.display()
+                );
+            }
+            None => {
+                println!(
+                    "===== Dry run: {} by {} ({}) on {} =====",
+                    output.kind, output.character, output.user_id, output.forum
+                );
+                if let Some(discussion_id) = output.discussion_id {
+                    println!("Discussion: {}", discussion_id);
+                }
+                println!("--- System message ---\n{}", output.system_message);
+                println!("--- Prompt ---\n{}", output.prompt);
+                if let Some(title) = output.title {
+                    println!("--- Title ---\n{}", title);
+                }
+                if !output.tags.is_empty() {
+                    println!("--- Tags ---\n{}", output.tags.join(", "));
+                }
+                println!("--- Content ---\n{}", output.content);
+            }
+        }
+        Ok(())
+    }
+}
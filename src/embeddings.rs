@@ -0,0 +1,490 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    deployment::Deployment,
+    forum::DiscussionData,
+    injection::{delimit_recollection, inline},
+    llm::{send_json, LlmConfig, LlmError, ProviderKind, DEFAULT_OLLAMA_URL, DEFAULT_OPENAI_URL},
+    store::{Embedding, Memory, Store},
+};
+
+pub const DISCUSSION: &str = "discussion";
+pub const MEMORY: &str = "memory";
+/// Characters of the opening post that go into a discussion's embedding.
+const OPENING_POST_CHARS: usize = 2000;
+
+fn default_related() -> u32 {
+    3
+}
+
+fn default_min_similarity() -> f32 {
+    0.5
+}
+
+/// Embedding model used to find related discussions and memories, the
+/// `[forums.<name>.embeddings]` table.
+#[derive(Deserialize, Debug, Clone)]
+pub struct EmbeddingConfig {
+    /// `openai` or `ollama`. Defaults to the forum's LLM provider, whose
+    /// `base_url` and `api_key_env` are then used too.
+    pub provider: Option<ProviderKind>,
+    pub model: String,
+    pub base_url: Option<String>,
+    pub api_key_env: Option<String>,
+    /// Older discussions linked in reply prompts.
+    #[serde(default = "default_related")]
+    pub related: u32,
+    /// Least cosine similarity of a related discussion or memory.
+    #[serde(default = "default_min_similarity")]
+    pub min_similarity: f32,
+}
+
+impl EmbeddingConfig {
+    pub fn build(&self, llm: &LlmConfig) -> Result<Embeddings, LlmError> {
+        let provider = self.provider.unwrap_or(llm.provider);
+        let same_provider = provider == llm.provider;
+        let inherited = |value: &Option<String>| value.clone().filter(|_| same_provider);
+        let config = LlmConfig {
+            provider,
+            model: self.model.clone(),
+            base_url: self.base_url.clone().or_else(|| inherited(&llm.base_url)),
+            api_key_env: self
+                .api_key_env
+                .clone()
+                .or_else(|| inherited(&llm.api_key_env)),
+            ..llm.clone()
+        };
+        let api_key = config.api_key();
+        let client = reqwest::Client::builder()
+            .timeout(config.retry.timeout())
+            .build()?;
+        let embedder: Box<dyn Embedder> = match provider {
+            ProviderKind::OpenAi => {
+                let base_url = config.base_url.as_deref().unwrap_or(DEFAULT_OPENAI_URL);
+                if api_key.is_none() && base_url == DEFAULT_OPENAI_URL {
+                    return Err(LlmError::MissingApiKey);
+                }
+                Box::new(OpenAiEmbedder {
+                    client,
+                    base_url: base_url.trim_end_matches('/').to_string(),
+                    api_key,
+                    model: self.model.clone(),
+                })
+            }
+            ProviderKind::Ollama => Box::new(OllamaEmbedder {
+                client,
+                base_url: config
+                    .base_url
+                    .as_deref()
+                    .unwrap_or(DEFAULT_OLLAMA_URL)
+                    .trim_end_matches('/')
+                    .to_string(),
+                model: self.model.clone(),
+            }),
+            ProviderKind::Anthropic => return Err(LlmError::NoEmbeddings("Anthropic".to_string())),
+        };
+        Ok(Embeddings {
+            embedder,
+            model: self.model.clone(),
+            related: self.related,
+            min_similarity: self.min_similarity,
+        })
+    }
+}
+
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, LlmError>;
+}
+
+fn vector(value: &Value) -> Result<Vec<f32>, LlmError> {
+    let vector: Vec<f32> = value
+        .as_array()
+        .ok_or(LlmError::MissingContent)?
+        .iter()
+        .filter_map(|v| v.as_f64())
+        .map(|v| v as f32)
+        .collect();
+    if vector.is_empty() {
+        return Err(LlmError::MissingContent);
+    }
+    Ok(vector)
+}
+
+/// OpenAI embeddings API, or any server that mimics it.
+pub struct OpenAiEmbedder {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+#[async_trait]
+impl Embedder for OpenAiEmbedder {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, LlmError> {
+        let url = format!("{}/embeddings", self.base_url);
+        let mut request = self.client.post(url).json(&json!({
+            "model": self.model,
+            "input": text
+        }));
+        if let Some(api_key) = &self.api_key {
+            let value = HeaderValue::from_str(&format!("Bearer {}", api_key))
+                .map_err(|_| LlmError::MissingApiKey)?;
+            request = request.header(AUTHORIZATION, value);
+        }
+        println!("Request to embeddings (OpenAI), model {}", self.model);
+        let res = send_json(request).await?;
+        vector(&res["data"][0]["embedding"])
+    }
+}
+
+/// Ollama's native embeddings API.
+pub struct OllamaEmbedder {
+    client: reqwest::Client,
+    base_url: String,
+    model: String,
+}
+
+#[async_trait]
+impl Embedder for OllamaEmbedder {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, LlmError> {
+        let url = format!("{}/api/embed", self.base_url);
+        println!("Request to embeddings (Ollama), model {}", self.model);
+        let res = send_json(self.client.post(url).json(&json!({
+            "model": self.model,
+            "input": text
+        })))
+        .await?;
+        vector(&res["embeddings"][0])
+    }
+}
+
+/// FNV-1a hash of `text`, in hex. Unlike `DefaultHasher`, it is the same
+/// across Rust releases, so stored hashes stay comparable.
+fn text_hash(text: &str) -> String {
+    let hash = text.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+/// A forum's embedding model, used to index discussions and memories in the
+/// store and to find those related to a discussion.
+pub struct Embeddings {
+    embedder: Box<dyn Embedder>,
+    pub model: String,
+    related: u32,
+    min_similarity: f32,
+}
+
+impl Embeddings {
+    /// Embeds the title and opening post of a discussion and stores the
+    /// embedding, which is also returned. A stored embedding is reused
+    /// unless the title or opening post changed.
+    pub async fn index_discussion(
+        &self,
+        store: &Store,
+        forum: &str,
+        data: &DiscussionData,
+    ) -> Result<Vec<f32>, Box<dyn Error>> {
+        let ref_id = i64::from(data.discussion.id);
+        let opening: String = data
+            .posts
+            .first()
+            .map(|post| post.content.chars().take(OPENING_POST_CHARS).collect())
+            .unwrap_or_default();
+        let text = format!("{}\n\n{}", data.discussion.title, opening);
+        let hash = text_hash(&text);
+        if let Some(stored) = store.embedding(forum, DISCUSSION, ref_id, &self.model)? {
+            if stored.text_hash.as_ref() == Some(&hash) {
+                return Ok(stored.vector);
+            }
+        }
+        let vector = self.embedder.embed(&text).await?;
+        store.put_embedding(&Embedding {
+            forum: forum.to_string(),
+            kind: DISCUSSION.to_string(),
+            ref_id,
+            user_id: None,
+            model: self.model.clone(),
+            title: data.discussion.title.clone(),
+            text_hash: Some(hash),
+            vector: vector.clone(),
+        })?;
+        Ok(vector)
+    }
+
+    pub async fn index_memory(&self, store: &Store, memory: &Memory) -> Result<(), Box<dyn Error>> {
+        let vector = self.embedder.embed(&memory.summary).await?;
+        store.put_embedding(&Embedding {
+            forum: memory.forum.clone(),
+            kind: MEMORY.to_string(),
+            ref_id: memory.id,
+            user_id: Some(memory.user_id),
+            model: self.model.clone(),
+            title: memory.title.clone(),
+            text_hash: Some(text_hash(&memory.summary)),
+            vector,
+        })?;
+        Ok(())
+    }
+
+    /// Stored embeddings of `kind` at least `min_similarity` similar to
+    /// `query`, most similar first.
+    pub fn nearest(
+        &self,
+        store: &Store,
+        forum: &str,
+        kind: &str,
+        user_id: Option<i32>,
+        query: &[f32],
+        limit: usize,
+    ) -> rusqlite::Result<Vec<Embedding>> {
+        let mut scored: Vec<(f32, Embedding)> = store
+            .embeddings(forum, kind, &self.model, user_id)?
+            .into_iter()
+            .map(|e| (cosine_similarity(query, &e.vector), e))
+            .filter(|(similarity, _)| *similarity >= self.min_similarity)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        Ok(scored.into_iter().take(limit).map(|(_, e)| e).collect())
+    }
+}
+
+/// Embeds a discussion about to be replied to, returning the embedding to
+/// look up related things with. Failures are reported and give `None`.
+pub async fn index_discussion(deployment: &Deployment, data: &DiscussionData) -> Option<Vec<f32>> {
+    let embeddings = deployment.embeddings.as_ref()?;
+    match embeddings
+        .index_discussion(&deployment.store, &deployment.name, data)
+        .await
+    {
+        Ok(vector) => Some(vector),
+        Err(e) => {
+            eprintln!("Could not embed discussion {}: {}", data.discussion.id, e);
+            None
+        }
+    }
+}
+
+/// Older discussions related to the one with embedding `query`, with links,
+/// as a prompt, or an empty string.
+pub fn related_discussions(
+    deployment: &Deployment,
+    data: &DiscussionData,
+    query: Option<&[f32]>,
+) -> String {
+    let (Some(embeddings), Some(query)) = (&deployment.embeddings, query) else {
+        return String::new();
+    };
+    // One more, since the discussion itself is indexed.
+    let limit = embeddings.related as usize + 1;
+    let related = match embeddings.nearest(
+        &deployment.store,
+        &deployment.name,
+        DISCUSSION,
+        None,
+        query,
+        limit,
+    ) {
+        Ok(related) => related,
+        Err(e) => {
+            eprintln!("Could not look up related discussions: {}", e);
+            return String::new();
+        }
+    };
+    let list: Vec<String> = related
+        .iter()
+        .filter(|e| e.ref_id != i64::from(data.discussion.id))
+        .take(embeddings.related as usize)
+        .map(|e| {
+            let url = deployment.forum.discussion_url(e.ref_id as i32);
+            format!("- [{}]({})", inline(&e.title), url)
+        })
+        .collect();
+    if list.is_empty() {
+        return String::new();
+    }
+    deployment
+        .lang
+        .get_related_prompt(&delimit_recollection(&list.join("\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::forum::{Discussion, Post};
+
+    /// Embeds a text as its length and counts the calls.
+    struct CountingEmbedder(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Embedder for CountingEmbedder {
+        async fn embed(&self, text: &str) -> Result<Vec<f32>, LlmError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![text.len() as f32, 1.0])
+        }
+    }
+
+    fn embeddings(calls: Arc<AtomicUsize>) -> Embeddings {
+        Embeddings {
+            embedder: Box::new(CountingEmbedder(calls)),
+            model: "test-embed".to_string(),
+            related: 3,
+            min_similarity: 0.5,
+        }
+    }
+
+    fn discussion(title: &str, opening: &str) -> DiscussionData {
+        DiscussionData {
+            discussion: Discussion {
+                id: 4,
+                title: title.to_string(),
+                author: None,
+                created_at: Utc::now(),
+                last_posted_at: None,
+                last_poster: None,
+                reply_count: 0,
+                tags: Vec::new(),
+            },
+            posts: vec![Post {
+                id: 10,
+                number: 1,
+                author: None,
+                created_at: Utc::now(),
+                content: opening.to_string(),
+            }],
+        }
+    }
+
+    fn embedding(ref_id: i64, user_id: Option<i32>, vector: Vec<f32>) -> Embedding {
+        Embedding {
+            forum: "test".to_string(),
+            kind: MEMORY.to_string(),
+            ref_id,
+            user_id,
+            model: "test-embed".to_string(),
+            title: format!("Memory {}", ref_id),
+            text_hash: None,
+            vector,
+        }
+    }
+
+    #[test]
+    fn measures_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 1.0], &[-1.0, -1.0]) + 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0, 2.0], &[1.0, 2.0, 3.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 2.0]), 0.0);
+        assert_eq!(cosine_similarity(&[], &[]), 0.0);
+    }
+
+    #[test]
+    fn parses_vectors() {
+        assert_eq!(
+            vector(&json!([0.5, -1, 2.25])).unwrap(),
+            vec![0.5, -1.0, 2.25]
+        );
+        assert_eq!(vector(&json!([1, "x", null, 2])).unwrap(), vec![1.0, 2.0]);
+        assert!(vector(&json!([])).is_err());
+        assert!(vector(&json!(["x"])).is_err());
+        assert!(vector(&json!({"embedding": [1.0]})).is_err());
+        assert!(vector(&Value::Null).is_err());
+    }
+
+    #[test]
+    fn hashes_texts_stably() {
+        assert_eq!(text_hash(""), "cbf29ce484222325");
+        assert_eq!(text_hash("a"), "af63dc4c8601ec8c");
+        assert_ne!(text_hash("Droids\n\nFor sale"), text_hash("Droids\n\nSold"));
+    }
+
+    #[test]
+    fn finds_nearest_above_the_threshold() {
+        let store = Store::in_memory().unwrap();
+        for embedding in [
+            embedding(1, Some(6), vec![1.0, 0.0]),
+            embedding(2, Some(6), vec![1.0, 1.0]),
+            embedding(3, Some(6), vec![0.0, 1.0]),
+            embedding(4, Some(6), vec![1.0, 0.2]),
+            embedding(5, Some(7), vec![1.0, 0.0]),
+        ] {
+            store.put_embedding(&embedding).unwrap();
+        }
+        let embeddings = embeddings(Arc::default());
+        let nearest = |user_id, limit| {
+            embeddings
+                .nearest(&store, "test", MEMORY, user_id, &[1.0, 0.0], limit)
+                .unwrap()
+                .iter()
+                .map(|e| e.ref_id)
+                .collect::<Vec<i64>>()
+        };
+        // 3 is orthogonal to the query, below `min_similarity`.
+        assert_eq!(nearest(Some(6), 10), vec![1, 4, 2]);
+        assert_eq!(nearest(Some(6), 2), vec![1, 4]);
+        assert_eq!(nearest(Some(7), 10), vec![5]);
+        assert_eq!(nearest(None, 10).len(), 4);
+        assert!(embeddings
+            .nearest(&store, "test", DISCUSSION, None, &[1.0, 0.0], 10)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn embeds_discussions_again_only_when_they_change() {
+        let store = Store::in_memory().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let embeddings = embeddings(calls.clone());
+        let index = |data| embeddings.index_discussion(&store, "test", data);
+
+        let original = discussion("Droids", "For sale");
+        let vector = index(&original).await.unwrap();
+        assert_eq!(index(&original).await.unwrap(), vector);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let edited = discussion("Droids", "For sale, cheap");
+        assert_ne!(index(&edited).await.unwrap(), vector);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        index(&discussion("Cheap droids", "For sale, cheap"))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let stored = store
+            .embedding("test", DISCUSSION, 4, "test-embed")
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.title, "Cheap droids");
+        assert_eq!(
+            stored.text_hash.unwrap(),
+            text_hash("Cheap droids\n\nFor sale, cheap")
+        );
+    }
+}
@@ -1,125 +1,615 @@
 extern crate reqwest;
 extern crate serde;
 
-use std::{collections::HashMap, error::Error};
+use std::{
+    collections::HashMap,
+    error::Error as StdError,
+    fmt::{self, Display, Formatter},
+};
 
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use html2md::parse_html;
-use rand::seq::SliceRandom;
 use reqwest::{
-    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    header::{HeaderMap, HeaderValue, InvalidHeaderValue, AUTHORIZATION, CONTENT_TYPE},
     Response,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize};
 use serde_json::{json, Value};
-use simple_error::SimpleError;
 
-fn get_flarum_api_key() -> String {
-    dotenvy::dotenv().ok();
-    dotenvy::var("FLARUM_API_KEY").unwrap_or_else(|_| panic!("FLARUM_API_KEY must be set"))
+use crate::forum::{
+    Discussion, DiscussionData, DiscussionQuery, DiscussionSort, ForumBackend, ForumError,
+    ForumPost, ForumUser, Notification, NotificationKind, Post, ReplyTo, Tag, TagCatalog, TagRules,
+};
+
+/// Location of the offending value in the request document.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiErrorSource {
+    pub pointer: Option<String>,
+}
+
+/// One entry of a JSON:API `errors` array.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiError {
+    pub status: Option<String>,
+    pub code: Option<String>,
+    pub detail: Option<String>,
+    pub source: Option<ApiErrorSource>,
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.code.as_deref().unwrap_or("unknown_error"))?;
+        if let Some(status) = &self.status {
+            write!(f, " [{}]", status)?;
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, ": {}", detail)?;
+        }
+        if let Some(pointer) = self.source.as_ref().and_then(|s| s.pointer.as_ref()) {
+            write!(f, " ({})", pointer)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiErrorsResponse {
+    errors: Vec<ApiError>,
+}
+
+#[derive(Debug)]
+pub enum FlarumError {
+    /// The API answered with a non-success status. `errors` is empty when
+    /// the body was not a JSON:API error document.
+    Api {
+        status: u16,
+        errors: Vec<ApiError>,
+    },
+    Http(reqwest::Error),
+    InvalidHeader(InvalidHeaderValue),
+    InvalidResponse(String),
+}
+
+impl StdError for FlarumError {}
+
+impl From<reqwest::Error> for FlarumError {
+    fn from(error: reqwest::Error) -> Self {
+        FlarumError::Http(error)
+    }
+}
+
+impl From<InvalidHeaderValue> for FlarumError {
+    fn from(error: InvalidHeaderValue) -> Self {
+        FlarumError::InvalidHeader(error)
+    }
+}
+
+impl Display for FlarumError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            FlarumError::Api { status, errors } => {
+                if errors.is_empty() {
+                    return write!(f, "Flarum API error {}", status);
+                }
+                write!(f, "Flarum API error")?;
+                for (i, error) in errors.iter().enumerate() {
+                    write!(f, "{} {}", if i == 0 { ":" } else { ";" }, error)?;
+                }
+                Ok(())
+            }
+            FlarumError::Http(error) => write!(f, "Flarum request failed: {}", error),
+            FlarumError::InvalidHeader(error) => write!(f, "Invalid Flarum API key: {}", error),
+            FlarumError::InvalidResponse(message) => {
+                write!(f, "Invalid Flarum response: {}", message)
+            }
+        }
+    }
+}
+
+/// Prefixes a reply with a mention of the post it answers, in the
+/// `@"Display Name"#p123` syntax of the mentions extension, or with a quote
+/// of the passage introduced by such a mention.
+fn with_mention(reply_to: &ReplyTo, content: &str) -> String {
+    let post = reply_to.post;
+    let Some(author) = &post.author else {
+        return content.to_string();
+    };
+    let mention = format!("@\"{}\"#p{}", author.display_name, post.id);
+    match &reply_to.quote {
+        Some(quote) => {
+            let quote: Vec<String> = quote.lines().map(|l| format!("> {}", l)).collect();
+            format!("> {} wrote:\n{}\n\n{}", mention, quote.join("\n"), content)
+        }
+        None => format!("{} {}", mention, content),
+    }
+}
+
+/// Turns a response into its JSON:API document, or into `FlarumError::Api`
+/// when the status is not a success.
+async fn check_response<T: DeserializeOwned>(resp: Response) -> Result<T, FlarumError> {
+    let status = resp.status();
+    let body = resp.text().await?;
+    if status.is_success() {
+        return serde_json::from_str(&body)
+            .map_err(|e| FlarumError::InvalidResponse(e.to_string()));
+    }
+    let errors = match serde_json::from_str::<ApiErrorsResponse>(&body) {
+        Ok(parsed) => parsed.errors,
+        Err(_) => {
+            println!("Flarum error response: {}", body);
+            Vec::new()
+        }
+    };
+    Err(FlarumError::Api {
+        status: status.as_u16(),
+        errors,
+    })
+}
+
+/// Largest page size Flarum allows.
+const POSTS_PAGE_SIZE: usize = 50;
+
+/// Query string of a discussion listing.
+fn query_params(query: &DiscussionQuery) -> Vec<(&'static str, String)> {
+    let sort = match query.sort {
+        DiscussionSort::LastPosted => "-lastPostedAt",
+        DiscussionSort::MostComments => "-commentCount",
+        DiscussionSort::Newest => "-createdAt",
+    };
+    let mut params = vec![
+        ("sort", sort.to_string()),
+        ("page[offset]", query.offset.to_string()),
+    ];
+    if let Some(limit) = query.limit {
+        params.push(("page[limit]", limit.to_string()));
+    }
+    if let Some(tag) = &query.tag {
+        params.push(("filter[tag]", tag.clone()));
+    }
+    if let Some(search) = &query.search {
+        params.push(("filter[q]", search.clone()));
+    }
+    params
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Discussion {
-    pub id: i32,
-    pub title: String,
+fn parse_id(id: &str) -> Result<i32, FlarumError> {
+    id.parse()
+        .map_err(|_| FlarumError::InvalidResponse(format!("invalid resource id {:?}", id)))
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Resource identifier object.
+#[derive(Deserialize, Debug, Clone)]
 struct IdType {
     id: String,
     #[serde(rename = "type")]
     otype: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct DataSingleIdType {
-    data: IdType,
+/// Resource linkage of a relationship: a single resource (`null` when
+/// empty) or a list of resources.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Linkage {
+    Many(Vec<IdType>),
+    One(Option<IdType>),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct FlarumObj {
-    pub id: String,
+impl Default for Linkage {
+    fn default() -> Self {
+        Linkage::One(None)
+    }
+}
+
+/// Relationships that weren't requested come without `data`.
+#[derive(Deserialize, Debug, Clone, Default)]
+struct Relationship {
+    #[serde(default)]
+    data: Linkage,
+}
+
+/// A resource object with attributes of type `A`.
+#[derive(Deserialize, Debug, Clone)]
+struct FlarumObj<A> {
+    id: String,
     #[serde(rename = "type")]
-    pub otype: String,
-    pub attributes: HashMap<String, Value>,
+    otype: String,
+    attributes: A,
+    #[serde(default)]
+    relationships: HashMap<String, Relationship>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Post {
-    pub content: String,
+impl<A> FlarumObj<A> {
+    fn id(&self) -> Result<i32, FlarumError> {
+        parse_id(&self.id)
+    }
+
+    fn to_one(&self, name: &str) -> Option<&IdType> {
+        match self.relationships.get(name).map(|r| &r.data) {
+            Some(Linkage::One(id)) => id.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn to_many(&self, name: &str) -> &[IdType] {
+        match self.relationships.get(name).map(|r| &r.data) {
+            Some(Linkage::Many(ids)) => ids,
+            _ => &[],
+        }
+    }
 }
 
-pub struct Forum {
-    client: reqwest::Client,
-    base_url: String,
+/// The `included` array of a document. Attributes are kept untyped until a
+/// resource is looked up, since it mixes resources of every type.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+struct Included(Vec<FlarumObj<Value>>);
+
+impl Included {
+    fn typed<A: DeserializeOwned>(obj: &FlarumObj<Value>) -> Result<FlarumObj<A>, FlarumError> {
+        let attributes = serde_json::from_value(obj.attributes.clone()).map_err(|e| {
+            FlarumError::InvalidResponse(format!("{} {}: {}", obj.otype, obj.id, e))
+        })?;
+        Ok(FlarumObj {
+            id: obj.id.clone(),
+            otype: obj.otype.clone(),
+            attributes,
+            relationships: obj.relationships.clone(),
+        })
+    }
+
+    /// The included resource `id` refers to, or `None` if it wasn't included.
+    fn get<A: DeserializeOwned>(&self, id: &IdType) -> Result<Option<FlarumObj<A>>, FlarumError> {
+        self.0
+            .iter()
+            .find(|obj| obj.otype == id.otype && obj.id == id.id)
+            .map(Self::typed)
+            .transpose()
+    }
+
+    fn user(&self, id: Option<&IdType>) -> Result<Option<ForumUser>, FlarumError> {
+        let Some(id) = id else {
+            return Ok(None);
+        };
+        self.get::<UserAttributes>(id)?
+            .map(|user| user.try_into())
+            .transpose()
+    }
+
+    fn tags(&self, ids: &[IdType]) -> Result<Vec<Tag>, FlarumError> {
+        let mut tags = Vec::new();
+        for id in ids {
+            if let Some(tag) = self.get::<TagAttributes>(id)? {
+                tags.push(tag.into());
+            }
+        }
+        Ok(tags)
+    }
+
+    fn discussion(&self, d: FlarumObj<DiscussionAttributes>) -> Result<Discussion, FlarumError> {
+        Ok(Discussion {
+            id: d.id()?,
+            author: self.user(d.to_one("user"))?,
+            last_poster: self.user(d.to_one("lastPostedUser"))?,
+            tags: self.tags(d.to_many("tags"))?,
+            reply_count: (d.attributes.comment_count - 1).max(0),
+            created_at: d.attributes.created_at,
+            last_posted_at: d.attributes.last_posted_at,
+            title: d.attributes.title,
+        })
+    }
+
+    fn post(&self, p: FlarumObj<PostAttributes>) -> Result<Option<Post>, FlarumError> {
+        let Some(content_html) = &p.attributes.content_html else {
+            return Ok(None);
+        };
+        Ok(Some(Post {
+            id: p.id()?,
+            number: p.attributes.number,
+            author: self.user(p.to_one("user"))?,
+            created_at: p.attributes.created_at,
+            content: parse_html(content_html),
+        }))
+    }
+}
+
+/// A JSON:API document whose primary data is `T`.
+#[derive(Deserialize, Debug, Clone)]
+struct Document<T> {
+    data: T,
+    #[serde(default)]
+    included: Included,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct DiscussionAttributes {
+    title: String,
+    comment_count: i32,
+    created_at: DateTime<Utc>,
+    last_posted_at: Option<DateTime<Utc>>,
+    /// Only present with the lock and sticky extensions.
+    #[serde(default)]
+    is_locked: bool,
+    #[serde(default)]
+    is_sticky: bool,
+    #[serde(default)]
+    is_hidden: bool,
+    #[serde(default = "default_true")]
+    can_reply: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct PostAttributes {
+    number: i32,
+    created_at: DateTime<Utc>,
+    /// Only comments have content; other posts record events such as a
+    /// renamed or retagged discussion.
+    content_html: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct NotificationAttributes {
+    content_type: String,
+    created_at: DateTime<Utc>,
+    is_read: bool,
+}
+
+/// Kind of a notification by its type, for those about conversations.
+fn notification_kind(content_type: &str) -> Option<NotificationKind> {
+    match content_type {
+        "userMentioned" | "groupMentioned" => Some(NotificationKind::Mention),
+        "postMentioned" => Some(NotificationKind::Reply),
+        "postLiked" => Some(NotificationKind::Like),
+        // Sent by the subscriptions extension for followed discussions.
+        "newPost" => Some(NotificationKind::NewPost),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct UserAttributes {
+    username: String,
+    display_name: Option<String>,
+}
+
+impl TryFrom<FlarumObj<UserAttributes>> for ForumUser {
+    type Error = FlarumError;
+
+    fn try_from(user: FlarumObj<UserAttributes>) -> Result<Self, Self::Error> {
+        Ok(ForumUser {
+            id: user.id()?,
+            display_name: user
+                .attributes
+                .display_name
+                .unwrap_or_else(|| user.attributes.username.clone()),
+            username: user.attributes.username,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TagAttributes {
+    name: String,
+    slug: String,
+    description: Option<String>,
+    /// Set for primary tags and their children, `null` for secondary tags.
+    position: Option<i32>,
+    #[serde(default = "default_true")]
+    can_start_discussion: bool,
+}
+
+impl From<FlarumObj<TagAttributes>> for Tag {
+    fn from(tag: FlarumObj<TagAttributes>) -> Self {
+        let parent = tag.to_one("parent").map(|p| p.id.clone());
+        Tag {
+            primary: tag.attributes.position.is_some() && parent.is_none(),
+            parent,
+            id: tag.id,
+            name: tag.attributes.name,
+            slug: tag.attributes.slug,
+            description: tag.attributes.description.filter(|d| !d.is_empty()),
+        }
+    }
+}
+
+/// A tag count setting, which Flarum serializes as a string.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Setting {
+    Number(usize),
+    Text(String),
+}
+
+impl Setting {
+    fn value(setting: &Option<Setting>, default: usize) -> usize {
+        match setting {
+            Some(Setting::Number(n)) => *n,
+            Some(Setting::Text(text)) => text.parse().unwrap_or(default),
+            None => default,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct DiscussionsResponse {
-    data: Vec<FlarumObj>,
+/// Attributes of the forum resource at the API root. The tag counts are
+/// added by the tags extension.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ForumAttributes {
+    min_primary_tags: Option<Setting>,
+    max_primary_tags: Option<Setting>,
+    min_secondary_tags: Option<Setting>,
+    max_secondary_tags: Option<Setting>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct DiscussionPostsResponse {
-    included: Vec<FlarumObj>,
+impl From<ForumAttributes> for TagRules {
+    fn from(forum: ForumAttributes) -> Self {
+        // Defaults of the tags extension.
+        TagRules {
+            min_primary: Setting::value(&forum.min_primary_tags, 1),
+            max_primary: Setting::value(&forum.max_primary_tags, 1),
+            min_secondary: Setting::value(&forum.min_secondary_tags, 0),
+            max_secondary: Setting::value(&forum.max_secondary_tags, 3),
+        }
+    }
+}
+
+/// Primary data of a create response, of which only the id is needed.
+#[derive(Deserialize, Debug, Clone)]
+struct Created {
+    id: String,
 }
 
-pub struct DiscussionData {
-    pub title: String,
-    pub recent_posts: Vec<Post>,
+/// Relationships of the newest posts. Flarum only sends the linkage of
+/// included relationships, so `discussion` must be asked for although its
+/// attributes aren't needed.
+const LATEST_POSTS_INCLUDE: &str = "user,discussion,mentionsUsers,mentionsPosts,mentionsPosts.user";
+/// Relationships of notifications, with the discussion of post subjects.
+const NOTIFICATIONS_INCLUDE: &str = "fromUser,subject,subject.discussion";
+
+/// Comments of a `/posts` document, with the users they address.
+fn latest_posts(
+    document: Document<Vec<FlarumObj<PostAttributes>>>,
+) -> Result<Vec<ForumPost>, FlarumError> {
+    let included = &document.included;
+    let mut posts = Vec::new();
+    for p in document.data {
+        let Some(discussion) = p.to_one("discussion") else {
+            continue;
+        };
+        let discussion_id = parse_id(&discussion.id)?;
+        let mut addressed = Vec::new();
+        for id in p.to_many("mentionsUsers") {
+            addressed.extend(included.user(Some(id))?);
+        }
+        for id in p.to_many("mentionsPosts") {
+            if let Some(mentioned) = included.get::<PostAttributes>(id)? {
+                addressed.extend(included.user(mentioned.to_one("user"))?);
+            }
+        }
+        if let Some(post) = included.post(p)? {
+            posts.push(ForumPost {
+                discussion_id,
+                post,
+                addressed,
+            });
+        }
+    }
+    Ok(posts)
+}
+
+/// Unread notifications about conversations of a `/notifications`
+/// document, oldest first.
+fn notifications(
+    document: Document<Vec<FlarumObj<NotificationAttributes>>>,
+) -> Result<Vec<Notification>, FlarumError> {
+    let included = &document.included;
+    let mut notifications = Vec::new();
+    for n in document.data {
+        let Some(kind) = notification_kind(&n.attributes.content_type) else {
+            continue;
+        };
+        if n.attributes.is_read {
+            continue;
+        }
+        let (mut discussion_id, mut post_id) = (None, None);
+        match n.to_one("subject") {
+            Some(subject) if subject.otype == "posts" => {
+                post_id = Some(parse_id(&subject.id)?);
+                if let Some(post) = included.get::<Value>(subject)? {
+                    discussion_id = post
+                        .to_one("discussion")
+                        .map(|d| parse_id(&d.id))
+                        .transpose()?;
+                }
+            }
+            Some(subject) if subject.otype == "discussions" => {
+                discussion_id = Some(parse_id(&subject.id)?);
+            }
+            _ => {}
+        }
+        notifications.push(Notification {
+            id: n.id()?,
+            kind,
+            from: included.user(n.to_one("fromUser"))?,
+            discussion_id,
+            post_id,
+            created_at: n.attributes.created_at,
+        });
+    }
+    notifications.sort_by_key(|n| n.created_at);
+    Ok(notifications)
+}
+
+pub struct Forum {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    default_tag: String,
 }
 
 impl Forum {
-    pub fn new(baseurl: &str) -> Self {
+    pub fn new(baseurl: &str, api_key: &str, default_tag: &str) -> Self {
         Self {
             base_url: baseurl.to_string(),
             client: reqwest::Client::new(),
+            api_key: api_key.to_string(),
+            default_tag: default_tag.to_string(),
         }
     }
 
-    fn get_headers(&self, user_id: Option<i32>) -> Result<HeaderMap, Box<dyn Error>> {
+    fn get_headers(&self, user_id: Option<i32>) -> Result<HeaderMap, FlarumError> {
         let mut headers = HeaderMap::new();
         headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        let api_key = get_flarum_api_key();
         match user_id {
             Some(user_id) => {
                 headers.insert(
                     AUTHORIZATION,
                     HeaderValue::from_str(
-                        format!("Token {}; userId={}", &api_key, user_id).as_str(),
+                        format!("Token {}; userId={}", &self.api_key, user_id).as_str(),
                     )?,
                 );
             }
             None => {
                 headers.insert(
                     AUTHORIZATION,
-                    HeaderValue::from_str(format!("Token {}", &api_key).as_str())?,
+                    HeaderValue::from_str(format!("Token {}", &self.api_key).as_str())?,
                 );
             }
         }
         Ok(headers)
     }
 
-    async fn get(&self, user_id: Option<i32>, url: &str) -> Result<Value, Box<dyn Error>> {
-        println!("Request fo Flarum, user {:?}, GET {}", user_id, url);
+    async fn get<T: DeserializeOwned>(
+        &self,
+        user_id: Option<i32>,
+        url: &str,
+        query: &[(&str, String)],
+    ) -> Result<Document<T>, FlarumError> {
+        println!(
+            "Request fo Flarum, user {:?}, GET {} {:?}",
+            user_id, url, query
+        );
         let headers = self.get_headers(user_id)?;
-        let value = self
+        let resp = self
             .client
             .get(url)
             .headers(headers)
+            .query(query)
             .send()
-            .await?
-            .json::<Value>()
             .await?;
-        Ok(value)
+        check_response(resp).await
     }
 
-    async fn post(
-        &self,
-        user_id: i32,
-        url: &str,
-        corpo: &Value,
-    ) -> Result<Response, Box<dyn Error>> {
+    async fn post(&self, user_id: i32, url: &str, corpo: &Value) -> Result<i32, FlarumError> {
         println!("Request fo Flarum, user {:?}, POST {}", user_id, url);
         let headers = self.get_headers(Some(user_id))?;
         let resp = self
@@ -129,89 +619,150 @@ impl Forum {
             .json(corpo)
             .send()
             .await?;
-        Ok(resp)
+        let created: Document<Created> = check_response(resp).await?;
+        parse_id(&created.data.id)
+    }
+
+    async fn patch(&self, user_id: i32, url: &str, corpo: &Value) -> Result<(), FlarumError> {
+        println!("Request fo Flarum, user {:?}, PATCH {}", user_id, url);
+        let headers = self.get_headers(Some(user_id))?;
+        let resp = self
+            .client
+            .patch(url)
+            .headers(headers)
+            .json(corpo)
+            .send()
+            .await?;
+        check_response::<Value>(resp).await?;
+        Ok(())
     }
 
     pub async fn list_recent_discussions(
         &self,
         user_id: i32,
-    ) -> Result<Vec<Discussion>, Box<dyn Error>> {
+        query: &DiscussionQuery,
+    ) -> Result<Vec<Discussion>, FlarumError> {
         let url = format!("{}/discussions", self.base_url);
-        let value = self.get(Some(user_id), &url).await?;
-        let discussions: Vec<_> = value["data"]
-            .as_array()
-            .ok_or_else(|| SimpleError::new("Invalid response"))?
-            .iter()
-            .filter_map(|d| {
-                if d["relationships"]["lastPostedUser"]["data"]["id"]
-                    .as_str()
-                    .and_then(|s| s.parse::<i32>().ok())
-                    == Some(user_id)
-                {
-                    return None;
-                }
-                if d["attributes"]["isLocked"].as_bool() == Some(true) {
-                    return None;
-                }
-                if d["attributes"]["isSticky"].as_bool() == Some(true) {
-                    return None;
-                }
-                if d["attributes"]["isHidden"].as_bool() == Some(true) {
-                    return None;
-                }
-                if d["attributes"]["canReply"].as_bool() == Some(false) {
-                    return None;
-                }
-                let id = d["id"].as_str().and_then(|s| s.parse::<i32>().ok());
-                let title = d["attributes"]["title"].as_str();
-                title.map(|t| {
-                    id.map(|i| Discussion {
-                        id: i,
-                        title: t.to_string(),
-                    })
-                })
-            })
-            .flatten()
-            .collect();
+        let document: Document<Vec<FlarumObj<DiscussionAttributes>>> =
+            self.get(Some(user_id), &url, &query_params(query)).await?;
+        let user_id = user_id.to_string();
+        let mut discussions = Vec::new();
+        for d in document.data {
+            let attributes = &d.attributes;
+            if d.to_one("lastPostedUser").map(|u| &u.id) == Some(&user_id)
+                || attributes.is_locked
+                || attributes.is_sticky
+                || attributes.is_hidden
+                || !attributes.can_reply
+            {
+                continue;
+            }
+            discussions.push(document.included.discussion(d)?);
+        }
         Ok(discussions)
     }
 
-    pub async fn get_random_discussion(
+    pub async fn fetch_discussion(&self, id: i32) -> Result<DiscussionData, FlarumError> {
+        let url = format!("{}/discussions/{}", self.base_url, id);
+        // The posts embedded by default are only a window of the discussion,
+        // so they are fetched separately.
+        let include = [("include", "user,lastPostedUser,tags".to_string())];
+        let document: Document<FlarumObj<DiscussionAttributes>> =
+            self.get(None, &url, &include).await?;
+        Ok(DiscussionData {
+            discussion: document.included.discussion(document.data)?,
+            posts: self.fetch_posts(id).await?,
+        })
+    }
+
+    /// Every comment of a discussion, in order, a page at a time.
+    pub async fn fetch_posts(&self, discussion_id: i32) -> Result<Vec<Post>, FlarumError> {
+        let url = format!("{}/posts", self.base_url);
+        let mut posts = Vec::new();
+        let mut offset = 0;
+        loop {
+            let query = [
+                ("filter[discussion]", discussion_id.to_string()),
+                ("sort", "number".to_string()),
+                ("page[offset]", offset.to_string()),
+                ("page[limit]", POSTS_PAGE_SIZE.to_string()),
+            ];
+            let document: Document<Vec<FlarumObj<PostAttributes>>> =
+                self.get(None, &url, &query).await?;
+            let count = document.data.len();
+            for post in document.data {
+                posts.extend(document.included.post(post)?);
+            }
+            if count < POSTS_PAGE_SIZE {
+                break;
+            }
+            offset += POSTS_PAGE_SIZE;
+        }
+        posts.sort_by_key(|p| p.number);
+        Ok(posts)
+    }
+
+    pub async fn list_latest_posts(&self, limit: u32) -> Result<Vec<ForumPost>, FlarumError> {
+        let url = format!("{}/posts", self.base_url);
+        let query = [
+            ("filter[type]", "comment".to_string()),
+            ("sort", "-createdAt".to_string()),
+            ("page[limit]", limit.to_string()),
+            ("include", LATEST_POSTS_INCLUDE.to_string()),
+        ];
+        latest_posts(self.get(None, &url, &query).await?)
+    }
+
+    /// Unread notifications of `user_id` about conversations, oldest first.
+    pub async fn list_notifications(&self, user_id: i32) -> Result<Vec<Notification>, FlarumError> {
+        let url = format!("{}/notifications", self.base_url);
+        let query = [
+            ("page[limit]", POSTS_PAGE_SIZE.to_string()),
+            ("include", NOTIFICATIONS_INCLUDE.to_string()),
+        ];
+        notifications(self.get(Some(user_id), &url, &query).await?)
+    }
+
+    pub async fn read_notification(
         &self,
         user_id: i32,
-    ) -> Result<Option<Discussion>, Box<dyn Error>> {
-        let discussions = self.list_recent_discussions(user_id).await?;
-        let mut rng = rand::thread_rng();
-        let random_discussion = discussions.choose(&mut rng).cloned();
-        Ok(random_discussion)
+        notification_id: i32,
+    ) -> Result<(), FlarumError> {
+        let url = format!("{}/notifications/{}", self.base_url, notification_id);
+        let corpo = json!({
+            "data": {
+                "type": "notifications",
+                "id": notification_id.to_string(),
+                "attributes": { "isRead": true }
+            }
+        });
+        self.patch(user_id, &url, &corpo).await
     }
 
-    pub async fn fetch_discussion(&self, id: i32) -> Result<DiscussionData, Box<dyn Error>> {
-        let url = format!("{}/discussions/{}", self.base_url, id);
-        let response = self.get(None, &url).await?;
-        let title = response["data"]["attributes"]
-            .as_str()
-            .unwrap_or("No title")
-            .to_string();
-
-        let mut posts: Vec<_> = response["included"]
-            .as_array()
-            .ok_or_else(|| SimpleError::new("Could not get included posts"))?
-            .iter()
-            .filter(|p| p["type"] == "posts")
-            .collect();
-        posts.sort_by_key(|p| p["attributes"]["number"].as_i64().unwrap_or(0));
+    pub async fn fetch_user(&self, user_id: i32) -> Result<ForumUser, FlarumError> {
+        let url = format!("{}/users/{}", self.base_url, user_id);
+        let document: Document<FlarumObj<UserAttributes>> =
+            self.get(Some(user_id), &url, &[]).await?;
+        document.data.try_into()
+    }
 
-        let posts: Vec<_> = posts
-            .iter()
-            .map(|p| Post {
-                content: parse_html(p["attributes"]["contentHtml"].as_str().unwrap_or("")),
-            })
+    /// Tags `user_id` may start discussions with, along with the forum's tag
+    /// count settings.
+    pub async fn list_tags(&self, user_id: i32) -> Result<TagCatalog, FlarumError> {
+        let url = format!("{}/tags", self.base_url);
+        let document: Document<Vec<FlarumObj<TagAttributes>>> =
+            self.get(Some(user_id), &url, &[]).await?;
+        let tags = document
+            .data
+            .into_iter()
+            .filter(|t| t.attributes.can_start_discussion)
+            .map(Tag::from)
             .collect();
-
-        Ok(DiscussionData {
-            title,
-            recent_posts: posts,
+        let forum: Document<FlarumObj<ForumAttributes>> =
+            self.get(Some(user_id), &self.base_url, &[]).await?;
+        Ok(TagCatalog {
+            tags,
+            rules: forum.data.attributes.into(),
         })
     }
 
@@ -220,8 +771,16 @@ impl Forum {
         user_id: i32,
         title: &str,
         content: &str,
-    ) -> Result<(), Box<dyn Error>> {
+        tags: &[Tag],
+    ) -> Result<i32, FlarumError> {
         let url = format!("{}/discussions", self.base_url);
+        let tags: Vec<Value> = if tags.is_empty() {
+            vec![json!({"type": "tags", "id": self.default_tag})]
+        } else {
+            tags.iter()
+                .map(|tag| json!({"type": "tags", "id": tag.id}))
+                .collect()
+        };
         self.post(
             user_id,
             &url,
@@ -234,31 +793,465 @@ impl Forum {
                     },
                     "relationships": {
                         "tags": {
-                            "data": [
-                                {
-                                    "type": "tags",
-                                    "id": "1"
-                                }
-                            ]
+                            "data": tags
                         }
                     }
                 }
             }),
         )
-        .await?;
-        Ok(())
+        .await
     }
 
     pub async fn write_post(
         &self,
         user_id: i32,
         discussion_id: i32,
+        reply_to: Option<&ReplyTo<'_>>,
         content: &str,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<i32, FlarumError> {
+        let content = match reply_to {
+            Some(reply_to) => with_mention(reply_to, content),
+            None => content.to_string(),
+        };
         let url = format!("{}/posts", self.base_url);
         self.post(user_id, &url, &json!(
                 {"data":{"type":"posts","attributes":{"content":content},"relationships":{"discussion":{"data":{"type":"discussions","id":discussion_id.to_string()}}}}}
-            )).await?;
-        Ok(())
+            )).await
+    }
+}
+
+#[async_trait]
+impl ForumBackend for Forum {
+    async fn list_discussions(
+        &self,
+        user_id: i32,
+        query: &DiscussionQuery,
+    ) -> Result<Vec<Discussion>, ForumError> {
+        Ok(self.list_recent_discussions(user_id, query).await?)
+    }
+
+    async fn fetch_discussion(&self, id: i32) -> Result<DiscussionData, ForumError> {
+        Ok(Forum::fetch_discussion(self, id).await?)
+    }
+
+    async fn list_tags(&self, user_id: i32) -> Result<TagCatalog, ForumError> {
+        Ok(Forum::list_tags(self, user_id).await?)
+    }
+
+    async fn create_discussion(
+        &self,
+        user_id: i32,
+        title: &str,
+        content: &str,
+        tags: &[Tag],
+    ) -> Result<i32, ForumError> {
+        Ok(self
+            .create_new_discussion(user_id, title, content, tags)
+            .await?)
+    }
+
+    async fn reply(
+        &self,
+        user_id: i32,
+        discussion_id: i32,
+        reply_to: Option<&ReplyTo>,
+        content: &str,
+    ) -> Result<i32, ForumError> {
+        Ok(self
+            .write_post(user_id, discussion_id, reply_to, content)
+            .await?)
+    }
+
+    async fn identify(&self, user_id: i32) -> Result<ForumUser, ForumError> {
+        Ok(self.fetch_user(user_id).await?)
+    }
+
+    async fn latest_posts(&self, limit: u32) -> Result<Vec<ForumPost>, ForumError> {
+        Ok(self.list_latest_posts(limit).await?)
+    }
+
+    async fn notifications(&self, user_id: i32) -> Result<Vec<Notification>, ForumError> {
+        Ok(self.list_notifications(user_id).await?)
+    }
+
+    async fn mark_notification_read(
+        &self,
+        user_id: i32,
+        notification_id: i32,
+    ) -> Result<(), ForumError> {
+        Ok(self.read_notification(user_id, notification_id).await?)
+    }
+
+    fn discussion_url(&self, discussion_id: i32) -> String {
+        let site = self.base_url.trim_end_matches('/').trim_end_matches("/api");
+        format!("{}/d/{}", site, discussion_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(author: Option<&str>, content: &str) -> Post {
+        Post {
+            id: 42,
+            number: 3,
+            author: author.map(|name| ForumUser {
+                id: 7,
+                username: name.to_lowercase(),
+                display_name: name.to_string(),
+            }),
+            created_at: Utc::now(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn mentions_the_answered_post() {
+        let post = post(Some("Jo Human"), "Why is the sky blue?");
+        let reply_to = ReplyTo::new(&post, false);
+        assert_eq!(
+            with_mention(&reply_to, "Because of Rayleigh scattering."),
+            "@\"Jo Human\"#p42 Because of Rayleigh scattering."
+        );
+    }
+
+    #[test]
+    fn quotes_the_answered_passage() {
+        let post = post(
+            Some("Jo Human"),
+            "Hi!\n\nWhy is the sky blue?\nAnd the sea?",
+        );
+        let reply_to = ReplyTo::new(&post, true);
+        assert_eq!(
+            with_mention(&reply_to, "Scattering."),
+            "> @\"Jo Human\"#p42 wrote:\n> Why is the sky blue?\n> And the sea?\n\nScattering."
+        );
+    }
+
+    #[test]
+    fn posts_of_deleted_users_are_not_mentioned() {
+        let post = post(None, "Why is the sky blue?");
+        let reply_to = ReplyTo::new(&post, true);
+        assert_eq!(with_mention(&reply_to, "Scattering."), "Scattering.");
+    }
+
+    fn document() -> Document<Vec<FlarumObj<DiscussionAttributes>>> {
+        serde_json::from_value(serde_json::json!({
+            "data": [{
+                "type": "discussions",
+                "id": "5",
+                "attributes": {
+                    "title": "Rayleigh",
+                    "commentCount": 3,
+                    "createdAt": "2024-05-01T10:00:00+00:00",
+                    "lastPostedAt": "2024-05-02T10:00:00+00:00"
+                },
+                "relationships": {
+                    "user": {"data": {"type": "users", "id": "7"}},
+                    "lastPostedUser": {"data": null},
+                    "tags": {"data": [
+                        {"type": "tags", "id": "1"},
+                        {"type": "tags", "id": "9"}
+                    ]},
+                    "firstPost": {}
+                }
+            }],
+            "included": [
+                {
+                    "type": "users",
+                    "id": "7",
+                    "attributes": {"username": "jo", "displayName": "Jo Human"}
+                },
+                {
+                    "type": "users",
+                    "id": "8",
+                    "attributes": {"username": "sam"}
+                },
+                {
+                    "type": "tags",
+                    "id": "1",
+                    "attributes": {
+                        "name": "Science",
+                        "slug": "science",
+                        "description": "",
+                        "position": 0
+                    }
+                },
+                {
+                    "type": "tags",
+                    "id": "2",
+                    "attributes": {"name": "Physics", "slug": "physics", "position": 1},
+                    "relationships": {"parent": {"data": {"type": "tags", "id": "1"}}}
+                },
+                {
+                    "type": "posts",
+                    "id": "7",
+                    "attributes": {
+                        "number": 2,
+                        "createdAt": "2024-05-01T11:00:00+00:00",
+                        "contentHtml": "<p>Scattering.</p>"
+                    },
+                    "relationships": {"user": {"data": {"type": "users", "id": "8"}}}
+                },
+                {
+                    "type": "posts",
+                    "id": "8",
+                    "attributes": {
+                        "number": 3,
+                        "createdAt": "2024-05-01T12:00:00+00:00"
+                    }
+                }
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn id(otype: &str, id: &str) -> IdType {
+        IdType {
+            id: id.to_string(),
+            otype: otype.to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_linkage() {
+        let document = document();
+        let d = &document.data[0];
+        assert_eq!(d.to_one("user").map(|u| u.id.as_str()), Some("7"));
+        assert!(d.to_one("lastPostedUser").is_none());
+        assert!(d.to_one("firstPost").is_none());
+        assert!(d.to_one("missing").is_none());
+        let tags: Vec<&str> = d.to_many("tags").iter().map(|t| t.id.as_str()).collect();
+        assert_eq!(tags, ["1", "9"]);
+        assert!(d.to_many("user").is_empty());
+    }
+
+    #[test]
+    fn looks_up_included_resources_by_type_and_id() {
+        let included = document().included;
+        let user = included.user(Some(&id("users", "7"))).unwrap().unwrap();
+        assert_eq!((user.id, user.username.as_str()), (7, "jo"));
+        assert_eq!(user.display_name, "Jo Human");
+        let user = included.user(Some(&id("users", "8"))).unwrap().unwrap();
+        assert_eq!(user.display_name, "sam");
+        assert!(included.user(Some(&id("users", "9"))).unwrap().is_none());
+        assert!(included.user(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn reports_included_resources_of_the_wrong_shape() {
+        // Post 7 is found under the id of user 7, but has no username.
+        let included = document().included;
+        assert!(included.user(Some(&id("posts", "7"))).is_err());
+    }
+
+    #[test]
+    fn builds_discussions_from_included_resources() {
+        let mut document = document();
+        let discussion = document
+            .included
+            .discussion(document.data.remove(0))
+            .unwrap();
+        assert_eq!(discussion.id, 5);
+        assert_eq!(discussion.title, "Rayleigh");
+        assert_eq!(discussion.reply_count, 2);
+        assert_eq!(discussion.author.unwrap().username, "jo");
+        assert!(discussion.last_poster.is_none());
+        // Tag 9 wasn't included.
+        let tags: Vec<&str> = discussion.tags.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(tags, ["Science"]);
+        assert!(discussion.tags[0].primary);
+        assert_eq!(discussion.tags[0].description, None);
+    }
+
+    #[test]
+    fn reads_tag_parents() {
+        let included = document().included;
+        let tags = included.tags(&[id("tags", "2")]).unwrap();
+        assert_eq!(tags[0].parent.as_deref(), Some("1"));
+        assert!(!tags[0].primary);
+    }
+
+    #[test]
+    fn skips_event_posts() {
+        let included = document().included;
+        let comment = included.get(&id("posts", "7")).unwrap().unwrap();
+        let post = included.post(comment).unwrap().unwrap();
+        assert_eq!((post.id, post.number), (7, 2));
+        assert_eq!(post.content, "Scattering.");
+        assert_eq!(post.author.unwrap().username, "sam");
+        let event = included.get(&id("posts", "8")).unwrap().unwrap();
+        assert!(included.post(event).unwrap().is_none());
+    }
+
+    #[test]
+    fn documents_may_omit_included() {
+        let document: Document<Created> =
+            serde_json::from_str(r#"{"data": {"type": "posts", "id": "12"}}"#).unwrap();
+        assert_eq!(document.data.id, "12");
+        assert!(document.included.0.is_empty());
+    }
+
+    #[test]
+    fn asks_for_the_discussion_of_latest_posts() {
+        assert!(LATEST_POSTS_INCLUDE
+            .split(',')
+            .any(|path| path == "discussion"));
+    }
+
+    #[test]
+    fn reads_latest_posts_and_who_they_address() {
+        let document = serde_json::from_value(serde_json::json!({
+            "data": [
+                {
+                    "type": "posts",
+                    "id": "31",
+                    "attributes": {
+                        "number": 4,
+                        "createdAt": "2024-05-01T12:00:00+00:00",
+                        "contentHtml": "<p>@Luke what do you think?</p>"
+                    },
+                    "relationships": {
+                        "user": {"data": {"type": "users", "id": "3"}},
+                        "discussion": {"data": {"type": "discussions", "id": "5"}},
+                        "mentionsUsers": {"data": [{"type": "users", "id": "7"}]},
+                        "mentionsPosts": {"data": [{"type": "posts", "id": "30"}]}
+                    }
+                },
+                {
+                    "type": "posts",
+                    "id": "29",
+                    "attributes": {
+                        "number": 9,
+                        "createdAt": "2024-05-01T11:00:00+00:00",
+                        "contentHtml": "<p>No discussion linkage</p>"
+                    }
+                }
+            ],
+            "included": [
+                {"type": "users", "id": "3", "attributes": {"username": "jo"}},
+                {"type": "users", "id": "7", "attributes": {"username": "luke"}},
+                {"type": "users", "id": "8", "attributes": {"username": "leia"}},
+                {"type": "discussions", "id": "5", "attributes": {"title": "Rayleigh"}},
+                {
+                    "type": "posts",
+                    "id": "30",
+                    "attributes": {"number": 3, "createdAt": "2024-05-01T11:30:00+00:00"},
+                    "relationships": {"user": {"data": {"type": "users", "id": "8"}}}
+                }
+            ]
+        }))
+        .unwrap();
+        let posts = latest_posts(document).unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].discussion_id, 5);
+        assert_eq!(posts[0].post.id, 31);
+        assert_eq!(posts[0].post.author.as_ref().unwrap().username, "jo");
+        let addressed: Vec<&str> = posts[0]
+            .addressed
+            .iter()
+            .map(|u| u.username.as_str())
+            .collect();
+        assert_eq!(addressed, ["luke", "leia"]);
+    }
+
+    #[test]
+    fn maps_notification_types() {
+        assert_eq!(
+            notification_kind("userMentioned"),
+            Some(NotificationKind::Mention)
+        );
+        assert_eq!(
+            notification_kind("groupMentioned"),
+            Some(NotificationKind::Mention)
+        );
+        assert_eq!(
+            notification_kind("postMentioned"),
+            Some(NotificationKind::Reply)
+        );
+        assert_eq!(notification_kind("postLiked"), Some(NotificationKind::Like));
+        assert_eq!(
+            notification_kind("newPost"),
+            Some(NotificationKind::NewPost)
+        );
+        assert_eq!(notification_kind("discussionRenamed"), None);
+    }
+
+    #[test]
+    fn asks_for_the_discussion_of_post_subjects() {
+        assert!(NOTIFICATIONS_INCLUDE
+            .split(',')
+            .any(|path| path == "subject.discussion"));
+    }
+
+    #[test]
+    fn reads_unread_notifications_oldest_first() {
+        let notification = |id: &str, content_type: &str, at: &str, read: bool, subject| {
+            serde_json::json!({
+                "type": "notifications",
+                "id": id,
+                "attributes": {"contentType": content_type, "createdAt": at, "isRead": read},
+                "relationships": {
+                    "fromUser": {"data": {"type": "users", "id": "3"}},
+                    "subject": {"data": subject}
+                }
+            })
+        };
+        let document = serde_json::from_value(serde_json::json!({
+            "data": [
+                notification(
+                    "3",
+                    "postMentioned",
+                    "2024-05-01T12:00:00+00:00",
+                    false,
+                    serde_json::json!({"type": "posts", "id": "31"})
+                ),
+                notification(
+                    "2",
+                    "newPost",
+                    "2024-05-01T11:00:00+00:00",
+                    false,
+                    serde_json::json!({"type": "discussions", "id": "6"})
+                ),
+                notification(
+                    "4",
+                    "userMentioned",
+                    "2024-05-01T13:00:00+00:00",
+                    true,
+                    serde_json::json!({"type": "posts", "id": "31"})
+                ),
+                notification(
+                    "5",
+                    "discussionRenamed",
+                    "2024-05-01T13:00:00+00:00",
+                    false,
+                    serde_json::json!({"type": "discussions", "id": "5"})
+                )
+            ],
+            "included": [
+                {"type": "users", "id": "3", "attributes": {"username": "jo"}},
+                {
+                    "type": "posts",
+                    "id": "31",
+                    "attributes": {"number": 4, "createdAt": "2024-05-01T12:00:00+00:00"},
+                    "relationships": {
+                        "discussion": {"data": {"type": "discussions", "id": "5"}}
+                    }
+                },
+                {"type": "discussions", "id": "5", "attributes": {"title": "Rayleigh"}}
+            ]
+        }))
+        .unwrap();
+        let notifications = notifications(document).unwrap();
+        let ids: Vec<i32> = notifications.iter().map(|n| n.id).collect();
+        assert_eq!(ids, [2, 3]);
+        let new_post = &notifications[0];
+        assert_eq!(new_post.kind, NotificationKind::NewPost);
+        assert_eq!((new_post.discussion_id, new_post.post_id), (Some(6), None));
+        let reply = &notifications[1];
+        assert_eq!(reply.kind, NotificationKind::Reply);
+        assert_eq!((reply.discussion_id, reply.post_id), (Some(5), Some(31)));
+        assert_eq!(reply.from.as_ref().unwrap().username, "jo");
     }
 }
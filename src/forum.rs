@@ -0,0 +1,572 @@
+use std::{error::Error as StdError, str::FromStr};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+pub type ForumError = Box<dyn StdError>;
+
+/// Forum software a deployment talks to, the `backend` key of a forum table.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Flarum,
+    Discourse,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tag {
+    /// Tag id on Flarum, tag name on Discourse.
+    pub id: String,
+    pub name: String,
+    pub slug: String,
+    pub description: Option<String>,
+    /// Flarum's top-level tags with a position. Every other tag, including
+    /// children of primary tags, is secondary.
+    pub primary: bool,
+    /// Id of the primary tag this one is a child of.
+    pub parent: Option<String>,
+}
+
+/// How many tags of each kind a new discussion must have.
+#[derive(Debug, Clone, Copy)]
+pub struct TagRules {
+    pub min_primary: usize,
+    pub max_primary: usize,
+    pub min_secondary: usize,
+    pub max_secondary: usize,
+}
+
+/// The tags a character may start discussions with.
+#[derive(Debug, Clone)]
+pub struct TagCatalog {
+    pub tags: Vec<Tag>,
+    pub rules: TagRules,
+}
+
+fn push_unique<'a>(tags: &mut Vec<&'a Tag>, tag: &'a Tag) {
+    if !tags.iter().any(|t| t.id == tag.id) {
+        tags.push(tag);
+    }
+}
+
+impl TagCatalog {
+    /// The tag with this slug or name, ignoring case.
+    pub fn find(&self, name: &str) -> Option<&Tag> {
+        self.tags
+            .iter()
+            .find(|t| t.slug.eq_ignore_ascii_case(name) || t.name.eq_ignore_ascii_case(name))
+    }
+
+    fn by_id(&self, id: &str) -> Option<&Tag> {
+        self.tags.iter().find(|t| t.id == id)
+    }
+
+    /// Turns the requested tags into a set the forum accepts: parents of
+    /// child tags are added, extra tags of each kind are dropped in request
+    /// order, and missing ones are filled in from `preferred`, then from the
+    /// whole catalog.
+    pub fn select(&self, requested: &[&Tag], preferred: &[&Tag]) -> Vec<Tag> {
+        let rules = self.rules;
+        let mut primary = Vec::new();
+        let mut secondary = Vec::new();
+        for &tag in requested {
+            if tag.primary {
+                push_unique(&mut primary, tag);
+                continue;
+            }
+            if let Some(parent) = tag.parent.as_deref().and_then(|id| self.by_id(id)) {
+                push_unique(&mut primary, parent);
+            }
+            push_unique(&mut secondary, tag);
+        }
+
+        primary.truncate(rules.max_primary);
+        let candidates: Vec<&Tag> = preferred.iter().copied().chain(self.tags.iter()).collect();
+        for &tag in candidates.iter().filter(|t| t.primary) {
+            if primary.len() >= rules.min_primary.min(rules.max_primary) {
+                break;
+            }
+            push_unique(&mut primary, tag);
+        }
+
+        let parent_kept = |tag: &Tag| match &tag.parent {
+            Some(parent) => primary.iter().any(|p| &p.id == parent),
+            None => true,
+        };
+        secondary.retain(|t| parent_kept(t));
+        secondary.truncate(rules.max_secondary);
+        for &tag in candidates.iter().filter(|t| !t.primary && parent_kept(t)) {
+            if secondary.len() >= rules.min_secondary.min(rules.max_secondary) {
+                break;
+            }
+            push_unique(&mut secondary, tag);
+        }
+
+        primary.into_iter().chain(secondary).cloned().collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Discussion {
+    pub id: i32,
+    pub title: String,
+    /// `None` when the author's account was deleted or the forum didn't
+    /// include it in the response.
+    pub author: Option<ForumUser>,
+    pub created_at: DateTime<Utc>,
+    pub last_posted_at: Option<DateTime<Utc>>,
+    pub last_poster: Option<ForumUser>,
+    /// Number of posts after the first one.
+    pub reply_count: i32,
+    pub tags: Vec<Tag>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Post {
+    pub id: i32,
+    /// Position of the post in its discussion, starting at 1.
+    pub number: i32,
+    /// `None` when the author's account was deleted.
+    pub author: Option<ForumUser>,
+    pub created_at: DateTime<Utc>,
+    pub content: String,
+}
+
+/// A post from the stream of a forum's newest posts.
+#[derive(Debug, Clone)]
+pub struct ForumPost {
+    pub discussion_id: i32,
+    pub post: Post,
+    /// Users the post mentions and authors of the posts it replies to.
+    pub addressed: Vec<ForumUser>,
+}
+
+/// Why a character was notified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationKind {
+    /// Someone mentioned the character.
+    Mention,
+    /// Someone replied to one of its posts.
+    Reply,
+    /// Someone liked one of its posts.
+    Like,
+    /// A new post in a discussion it follows.
+    NewPost,
+}
+
+impl NotificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::Mention => "mention",
+            NotificationKind::Reply => "reply",
+            NotificationKind::Like => "like",
+            NotificationKind::NewPost => "new_post",
+        }
+    }
+}
+
+/// An unread notification of a character about a conversation that
+/// involves it.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub id: i32,
+    pub kind: NotificationKind,
+    /// Who triggered it.
+    pub from: Option<ForumUser>,
+    pub discussion_id: Option<i32>,
+    /// The mentioning, replying or new post, or the liked one.
+    pub post_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The post a reply answers, and the passage of it to quote, if any.
+pub struct ReplyTo<'a> {
+    pub post: &'a Post,
+    pub quote: Option<String>,
+}
+
+impl<'a> ReplyTo<'a> {
+    pub fn new(post: &'a Post, quote: bool) -> Self {
+        Self {
+            post,
+            quote: if quote {
+                quote_passage(&post.content)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+const MAX_QUOTE_CHARS: usize = 300;
+
+/// The last paragraph of a post that isn't itself a quote, shortened to
+/// `MAX_QUOTE_CHARS`. That is usually the point a reply answers.
+fn quote_passage(content: &str) -> Option<String> {
+    let paragraph = content
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|p| !p.is_empty() && !p.starts_with('>'))
+        .last()?;
+    if paragraph.chars().count() <= MAX_QUOTE_CHARS {
+        return Some(paragraph.to_string());
+    }
+    let truncated: String = paragraph.chars().take(MAX_QUOTE_CHARS).collect();
+    let cut = truncated.rfind(' ').unwrap_or(truncated.len());
+    Some(format!("{}…", truncated[..cut].trim_end()))
+}
+
+pub struct DiscussionData {
+    pub discussion: Discussion,
+    /// Every post of the discussion, in order.
+    pub posts: Vec<Post>,
+}
+
+impl DiscussionData {
+    /// The opening post followed by the last `last` posts, which is what
+    /// prompts show of long discussions.
+    pub fn window(&self, last: usize) -> Vec<&Post> {
+        let Some((first, rest)) = self.posts.split_first() else {
+            return Vec::new();
+        };
+        let mut window = vec![first];
+        window.extend(&rest[rest.len().saturating_sub(last)..]);
+        window
+    }
+}
+
+/// The forum account a character posts as.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForumUser {
+    pub id: i32,
+    pub username: String,
+    pub display_name: String,
+}
+
+/// Order of a discussion listing, most recent or largest first.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscussionSort {
+    #[default]
+    LastPosted,
+    MostComments,
+    Newest,
+}
+
+impl FromStr for DiscussionSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last_posted" => Ok(DiscussionSort::LastPosted),
+            "most_comments" => Ok(DiscussionSort::MostComments),
+            "newest" => Ok(DiscussionSort::Newest),
+            other => Err(format!(
+                "Unknown sort {}, expected last_posted, most_comments or newest",
+                other
+            )),
+        }
+    }
+}
+
+/// Which page of which discussions to list.
+#[derive(Debug, Clone, Default)]
+pub struct DiscussionQuery {
+    pub offset: u32,
+    /// Page size, or the forum's default when `None`.
+    pub limit: Option<u32>,
+    pub sort: DiscussionSort,
+    /// Only discussions with this tag (slug on Flarum, name on Discourse).
+    pub tag: Option<String>,
+    /// Only discussions matching this full-text search.
+    pub search: Option<String>,
+}
+
+impl DiscussionQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn offset(mut self, offset: u32) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn sort(mut self, sort: DiscussionSort) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tag = Some(tag.to_string());
+        self
+    }
+
+    pub fn search(mut self, search: &str) -> Self {
+        self.search = Some(search.to_string());
+        self
+    }
+}
+
+fn default_sorts() -> Vec<DiscussionSort> {
+    vec![DiscussionSort::LastPosted]
+}
+
+fn default_pages() -> u32 {
+    1
+}
+
+fn default_page_size() -> u32 {
+    20
+}
+
+/// Where characters look for discussions to reply to, the
+/// `[forums.<name>.discussions]` table. Each time, a sort, a tag and one of
+/// the first `pages` pages are picked at random.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ListingConfig {
+    #[serde(default = "default_sorts")]
+    pub sorts: Vec<DiscussionSort>,
+    #[serde(default = "default_pages")]
+    pub pages: u32,
+    #[serde(default = "default_page_size")]
+    pub page_size: u32,
+    /// Restrict replies to these tags. Empty means every tag.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Default for ListingConfig {
+    fn default() -> Self {
+        Self {
+            sorts: default_sorts(),
+            pages: default_pages(),
+            page_size: default_page_size(),
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl ListingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.pages == 0 {
+            return Err("discussions.pages must be at least 1".to_string());
+        }
+        if !(1..=50).contains(&self.page_size) {
+            return Err("discussions.page_size must be between 1 and 50".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn random_query(&self) -> DiscussionQuery {
+        let mut rng = rand::thread_rng();
+        let page = rng.gen_range(0..self.pages);
+        let mut query = DiscussionQuery::new()
+            .offset(page * self.page_size)
+            .limit(self.page_size)
+            .sort(self.sorts.choose(&mut rng).copied().unwrap_or_default());
+        if let Some(tag) = self.tags.choose(&mut rng) {
+            query = query.tag(tag);
+        }
+        query
+    }
+}
+
+/// A forum platform the characters can post on. Every call is made on
+/// behalf of the character whose forum user id is given.
+#[async_trait]
+pub trait ForumBackend: Send + Sync {
+    /// Discussions matching `query` that `user_id` may reply to, excluding
+    /// those where it has the last word.
+    async fn list_discussions(
+        &self,
+        user_id: i32,
+        query: &DiscussionQuery,
+    ) -> Result<Vec<Discussion>, ForumError>;
+
+    /// A discussion along with all of its posts.
+    async fn fetch_discussion(&self, id: i32) -> Result<DiscussionData, ForumError>;
+
+    /// Tags `user_id` may start discussions with.
+    async fn list_tags(&self, user_id: i32) -> Result<TagCatalog, ForumError>;
+
+    /// Creates a discussion and returns its id. Without tags, the forum's
+    /// default tag or category is used.
+    async fn create_discussion(
+        &self,
+        user_id: i32,
+        title: &str,
+        content: &str,
+        tags: &[Tag],
+    ) -> Result<i32, ForumError>;
+
+    /// Replies to a discussion, in answer to `reply_to` when given, and
+    /// returns the new post's id.
+    async fn reply(
+        &self,
+        user_id: i32,
+        discussion_id: i32,
+        reply_to: Option<&ReplyTo>,
+        content: &str,
+    ) -> Result<i32, ForumError>;
+
+    /// The account behind `user_id`.
+    async fn identify(&self, user_id: i32) -> Result<ForumUser, ForumError>;
+
+    /// The newest comments of the whole forum, newest first.
+    async fn latest_posts(&self, limit: u32) -> Result<Vec<ForumPost>, ForumError>;
+
+    /// Unread notifications of `user_id` about conversations involving it,
+    /// oldest first. Other kinds of notifications are left out.
+    async fn notifications(&self, user_id: i32) -> Result<Vec<Notification>, ForumError>;
+
+    /// Marks a notification of `user_id` as read.
+    async fn mark_notification_read(
+        &self,
+        user_id: i32,
+        notification_id: i32,
+    ) -> Result<(), ForumError>;
+
+    /// Web address of a discussion, for links in posts.
+    fn discussion_url(&self, discussion_id: i32) -> String;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(id: &str, primary: bool, parent: Option<&str>) -> Tag {
+        Tag {
+            id: id.to_string(),
+            name: id.to_string(),
+            slug: id.to_lowercase(),
+            description: None,
+            primary,
+            parent: parent.map(str::to_string),
+        }
+    }
+
+    fn catalog(min_primary: usize, max_primary: usize, min_secondary: usize) -> TagCatalog {
+        TagCatalog {
+            tags: vec![
+                tag("Science", true, None),
+                tag("Art", true, None),
+                tag("Physics", false, Some("Science")),
+                tag("Painting", false, Some("Art")),
+                tag("News", false, None),
+                tag("Meta", false, None),
+            ],
+            rules: TagRules {
+                min_primary,
+                max_primary,
+                min_secondary,
+                max_secondary: 1,
+            },
+        }
+    }
+
+    fn select(catalog: &TagCatalog, requested: &[&str], preferred: &[&str]) -> Vec<String> {
+        let tags = |names: &[&str]| -> Vec<&Tag> {
+            names.iter().map(|n| catalog.find(n).unwrap()).collect()
+        };
+        catalog
+            .select(&tags(requested), &tags(preferred))
+            .into_iter()
+            .map(|t| t.id)
+            .collect()
+    }
+
+    #[test]
+    fn finds_tags_by_slug_or_name() {
+        let catalog = catalog(1, 1, 0);
+        assert_eq!(catalog.find("PHYSICS").unwrap().id, "Physics");
+        assert!(catalog.find("Chemistry").is_none());
+    }
+
+    #[test]
+    fn adds_parents_of_child_tags() {
+        let catalog = catalog(1, 1, 0);
+        assert_eq!(select(&catalog, &["Physics"], &[]), ["Science", "Physics"]);
+    }
+
+    #[test]
+    fn drops_extra_tags_in_request_order() {
+        let catalog = catalog(1, 1, 0);
+        assert_eq!(
+            select(&catalog, &["Art", "Science", "Meta", "News"], &[]),
+            ["Art", "Meta"]
+        );
+    }
+
+    #[test]
+    fn fills_missing_tags_from_preferred_first() {
+        let catalog = catalog(1, 1, 1);
+        assert_eq!(
+            select(&catalog, &[], &["Painting", "Art"]),
+            ["Art", "Painting"]
+        );
+        assert_eq!(select(&catalog, &[], &[]), ["Science", "Physics"]);
+    }
+
+    #[test]
+    fn drops_children_of_dropped_parents() {
+        let optional = catalog(1, 1, 0);
+        assert_eq!(
+            select(&optional, &["Science", "Painting"], &[]),
+            ["Science"]
+        );
+        // Nor can the secondary tag filled in instead be a child of Art.
+        let required = catalog(1, 1, 1);
+        assert_eq!(
+            select(&required, &["Science", "Painting"], &[]),
+            ["Science", "Physics"]
+        );
+    }
+
+    #[test]
+    fn never_requires_more_tags_than_allowed() {
+        let catalog = catalog(2, 1, 0);
+        assert_eq!(select(&catalog, &[], &[]), ["Science"]);
+    }
+
+    #[test]
+    fn quotes_the_last_paragraph() {
+        let content = "First point.\n\nSecond point,\non two lines.\n\n";
+        assert_eq!(
+            quote_passage(content).as_deref(),
+            Some("Second point,\non two lines.")
+        );
+    }
+
+    #[test]
+    fn does_not_quote_quotes() {
+        let content = "My answer.\n\n> What someone else said";
+        assert_eq!(quote_passage(content).as_deref(), Some("My answer."));
+        assert_eq!(quote_passage("> Only a quote"), None);
+        assert_eq!(quote_passage("  \n\n "), None);
+    }
+
+    #[test]
+    fn shortens_long_passages_at_a_word() {
+        let content = "word ".repeat(100);
+        let quote = quote_passage(&content).unwrap();
+        assert!(quote.ends_with("word…"));
+        assert!(quote.chars().count() <= MAX_QUOTE_CHARS + 1);
+    }
+
+    #[test]
+    fn shortens_multi_byte_passages() {
+        let content = "ação ".repeat(100);
+        let quote = quote_passage(&content).unwrap();
+        assert!(quote.ends_with("ação…"));
+        assert!(quote.chars().count() <= MAX_QUOTE_CHARS + 1);
+    }
+}
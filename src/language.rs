@@ -1,8 +1,52 @@
-use crate::characters::Character;
+use crate::{forum::TagRules, relationships::RelationshipKind};
 
-pub trait Language {
-    fn get_character_list(&self) -> Vec<Character>;
+pub trait Language: Send + Sync {
+    fn parody_system_message(&self, name: &str) -> String;
+    fn persona_system_message(&self, persona: &str) -> String;
     fn get_new_topic_title_prompt(&self) -> String;
     fn get_new_topic_prompt(&self, title: &str) -> String;
+    fn get_tags_prompt(
+        &self,
+        title: &str,
+        tag_list: &str,
+        preferred: &str,
+        rules: &TagRules,
+    ) -> String;
     fn get_reply_prompt(&self, title: &str, history: &str) -> String;
+    /// Opens a reply conversation, followed by the posts as messages.
+    fn get_chat_reply_prompt(&self, title: &str) -> String;
+    /// Closes a reply conversation, after the last post.
+    fn get_chat_reply_instruction(&self) -> String;
+    /// Introduces the memories recalled before replying, one per line.
+    fn get_memories_prompt(&self, memories: &str) -> String;
+    /// Tells a character what it thinks of the character it replies to.
+    /// `affinity` goes from -1 (hatred) to 1 (adoration).
+    fn get_relationship_prompt(
+        &self,
+        name: &str,
+        kind: Option<RelationshipKind>,
+        affinity: f64,
+    ) -> String;
+    /// Asks for the tone of a reply to `name` as a number from -1 to 1.
+    fn get_tone_prompt(&self, name: &str, reply: &str) -> String;
+    /// Introduces older discussions related to the one being replied to, one
+    /// markdown link per line.
+    fn get_related_prompt(&self, discussions: &str) -> String;
+    /// Asks for a summary of a post to remember. `replied_to` is the author
+    /// and content of the post it answered.
+    fn get_remember_prompt(
+        &self,
+        title: &str,
+        replied_to: Option<(&str, &str)>,
+        content: &str,
+    ) -> String;
+    /// System message of the LLM judging generated posts before they are
+    /// published.
+    fn get_moderation_system_message(&self) -> String;
+    /// Asks whether a post is acceptable, to be answered with `OK` or with
+    /// `REJECT` and a reason.
+    fn get_moderation_prompt(&self, content: &str) -> String;
+    /// Tells the LLM that `<post>` tags hold what forum users wrote, which
+    /// is data and never instructions.
+    fn get_untrusted_notice(&self) -> String;
 }
@@ -0,0 +1,405 @@
+use std::{cell::RefCell, collections::HashSet, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use futures::{stream::FuturesUnordered, StreamExt};
+use rand::Rng;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{watch, Notify},
+    time::{sleep, Instant},
+};
+
+use crate::{
+    characters::Character,
+    deployment::Deployment,
+    forum::ForumPost,
+    scheduler::{sleep_or_shutdown, Pacer},
+    selection::SelectionContext,
+};
+
+/// Newest posts fetched per poll. Posts beyond that between two polls are
+/// missed.
+const POLL_LIMIT: u32 = 50;
+/// Bytes of a webhook request that are read. Its content is ignored.
+const WEBHOOK_REQUEST_BYTES: usize = 8192;
+
+fn default_poll_interval() -> u64 {
+    60
+}
+
+fn default_min_delay() -> f64 {
+    2.0
+}
+
+fn default_max_delay() -> f64 {
+    10.0
+}
+
+/// Reacting to new posts that address a character, the
+/// `[forums.<name>.listener]` table. Only the daemon listens.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ListenerConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval_secs: u64,
+    /// A character answers a post addressed to it after a random delay
+    /// between these.
+    #[serde(default = "default_min_delay")]
+    pub min_delay_minutes: f64,
+    #[serde(default = "default_max_delay")]
+    pub max_delay_minutes: f64,
+    /// Address, e.g. `127.0.0.1:8787`, where any POST request triggers a
+    /// poll right away. Meant for a forum webhook.
+    pub webhook: Option<String>,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            poll_interval_secs: default_poll_interval(),
+            min_delay_minutes: default_min_delay(),
+            max_delay_minutes: default_max_delay(),
+            webhook: None,
+        }
+    }
+}
+
+impl ListenerConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.poll_interval_secs == 0 {
+            return Err("listener.poll_interval_secs must be at least 1".to_string());
+        }
+        if !(self.min_delay_minutes.is_finite() && self.min_delay_minutes >= 0.0) {
+            return Err("listener.min_delay_minutes must be a non-negative number".to_string());
+        }
+        if !(self.max_delay_minutes.is_finite() && self.max_delay_minutes >= self.min_delay_minutes)
+        {
+            return Err(
+                "listener.max_delay_minutes must not be less than min_delay_minutes".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    fn response_delay(&self) -> Duration {
+        let minutes = if self.max_delay_minutes > self.min_delay_minutes {
+            rand::thread_rng().gen_range(self.min_delay_minutes..=self.max_delay_minutes)
+        } else {
+            self.min_delay_minutes
+        };
+        Duration::from_secs_f64(minutes * 60.0)
+    }
+}
+
+/// Answers webhook requests, waking up the listener on each POST.
+async fn serve_webhook(listener: TcpListener, wake: Arc<Notify>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Webhook connection failed: {}", e);
+                continue;
+            }
+        };
+        let wake = wake.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer_webhook(stream, &wake).await {
+                eprintln!("Webhook request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn answer_webhook(mut stream: TcpStream, wake: &Notify) -> std::io::Result<()> {
+    let mut buffer = vec![0; WEBHOOK_REQUEST_BYTES];
+    let read = stream.read(&mut buffer).await?;
+    let status = if buffer[..read].starts_with(b"POST ") {
+        wake.notify_one();
+        "204 No Content"
+    } else {
+        "405 Method Not Allowed"
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn newest_id(posts: &[ForumPost]) -> Option<i32> {
+    posts.iter().map(|p| p.post.id).max()
+}
+
+/// Posts after the cursor `seen`, oldest first, and the cursor past them.
+fn take_new(posts: Vec<ForumPost>, seen: i32) -> (Vec<ForumPost>, i32) {
+    let newest = newest_id(&posts).map_or(seen, |newest| newest.max(seen));
+    let mut new_posts: Vec<ForumPost> = posts.into_iter().filter(|p| p.post.id > seen).collect();
+    new_posts.sort_by_key(|p| p.post.id);
+    (new_posts, newest)
+}
+
+/// User ids of the characters a post addresses, none when a character or a
+/// deleted user wrote it.
+fn addressed_characters(forum_post: &ForumPost, is_character: impl Fn(i32) -> bool) -> Vec<i32> {
+    match &forum_post.post.author {
+        Some(author) if !is_character(author.id) => forum_post
+            .addressed
+            .iter()
+            .map(|user| user.id)
+            .filter(|&id| is_character(id))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Has `character` answer a post after `delay`, then as soon as the forum's
+/// pace allows, unless characters already replied to the discussion as often
+/// as they may today. Gives back what it was scheduled for.
+async fn respond(
+    deployment: &Deployment,
+    pacer: &RefCell<Pacer<'_>>,
+    character: Character,
+    discussion_id: i32,
+    post_id: i32,
+    delay: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) -> (i32, i32) {
+    let done = (character.user_id, discussion_id);
+    if !sleep_or_shutdown(delay, &mut shutdown).await {
+        return done;
+    }
+    loop {
+        let hold_off = pacer.borrow_mut().hold_off(Utc::now());
+        let Some(wait) = hold_off else {
+            break;
+        };
+        if !sleep_or_shutdown(wait, &mut shutdown).await {
+            return done;
+        }
+    }
+    match SelectionContext::new(deployment) {
+        Ok(context) if deployment.selector.capped(discussion_id, &context) => {
+            println!(
+                "[{}] {} leaves discussion {} alone, it had enough replies today",
+                deployment.name, character.name, discussion_id
+            );
+            return done;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("[{}] Could not read recent replies: {}", deployment.name, e);
+            return done;
+        }
+    }
+    // Other answers may be written meanwhile, so the post counts right away.
+    pacer.borrow_mut().record_post();
+    println!(
+        "[{}] {} answers post {} of discussion {}",
+        deployment.name, character.name, post_id, discussion_id
+    );
+    match character
+        .answer_post(deployment, discussion_id, post_id)
+        .await
+    {
+        Ok(_) => println!("[{}] Success!", deployment.name),
+        Err(e) => eprintln!("[{}] Error: {}", deployment.name, e),
+    }
+    done
+}
+
+/// Watches the forum for new posts by humans and has each character they
+/// mention or reply to answer after a delay. Characters with an answer
+/// already pending in a discussion aren't scheduled again there.
+pub async fn run_listener(
+    deployment: &Deployment,
+    config: &ListenerConfig,
+    pacer: &RefCell<Pacer<'_>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    if !config.enabled {
+        return;
+    }
+    let wake = Arc::new(Notify::new());
+    let webhook = match &config.webhook {
+        Some(address) => match TcpListener::bind(address).await {
+            Ok(listener) => {
+                println!("[{}] Webhook listening on {}", deployment.name, address);
+                Some(tokio::spawn(serve_webhook(listener, wake.clone())))
+            }
+            Err(e) => {
+                eprintln!(
+                    "[{}] Could not listen on {}: {}",
+                    deployment.name, address, e
+                );
+                None
+            }
+        },
+        None => None,
+    };
+    let poll_interval = Duration::from_secs(config.poll_interval_secs);
+    // Posts up to this id were seen. Unknown until the first poll, which
+    // only catches up.
+    let mut cursor: Option<i32> = None;
+    let mut scheduled: HashSet<(i32, i32)> = HashSet::new();
+    let mut pending = FuturesUnordered::new();
+    let timer = sleep(Duration::ZERO);
+    tokio::pin!(timer);
+    loop {
+        tokio::select! {
+            _ = &mut timer => {}
+            _ = wake.notified() => {}
+            Some(done) = pending.next(), if !pending.is_empty() => {
+                scheduled.remove(&done);
+                continue;
+            }
+            _ = shutdown.changed() => break,
+        }
+        timer.as_mut().reset(Instant::now() + poll_interval);
+        let posts = match deployment.forum.latest_posts(POLL_LIMIT).await {
+            Ok(posts) => posts,
+            Err(e) => {
+                eprintln!("[{}] Could not fetch new posts: {}", deployment.name, e);
+                continue;
+            }
+        };
+        let Some(seen) = cursor else {
+            cursor = Some(newest_id(&posts).unwrap_or(0));
+            println!("[{}] Listening for new posts", deployment.name);
+            continue;
+        };
+        let (new_posts, seen) = take_new(posts, seen);
+        cursor = Some(seen);
+        for forum_post in &new_posts {
+            let Some(author) = &forum_post.post.author else {
+                continue;
+            };
+            let addressed = addressed_characters(forum_post, |id| {
+                deployment.characters.iter().any(|c| c.user_id == id)
+            });
+            for user_id in addressed {
+                let Some(character) = deployment.characters.iter().find(|c| c.user_id == user_id)
+                else {
+                    continue;
+                };
+                if !scheduled.insert((character.user_id, forum_post.discussion_id)) {
+                    continue;
+                }
+                let delay = config.response_delay();
+                println!(
+                    "[{}] {} addressed {} in discussion {}, answering in {} minutes",
+                    deployment.name,
+                    author.username,
+                    character.name,
+                    forum_post.discussion_id,
+                    delay.as_secs() / 60
+                );
+                pending.push(respond(
+                    deployment,
+                    pacer,
+                    character.clone(),
+                    forum_post.discussion_id,
+                    forum_post.post.id,
+                    delay,
+                    shutdown.clone(),
+                ));
+            }
+        }
+    }
+    if let Some(webhook) = webhook {
+        webhook.abort();
+    }
+    // Answers still waiting give up on shutdown, those being written finish.
+    while pending.next().await.is_some() {}
+    println!("[{}] Stopped listening", deployment.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forum::{ForumUser, Post};
+
+    const CHARACTERS: [i32; 2] = [2, 5];
+
+    fn user(id: i32) -> ForumUser {
+        ForumUser {
+            id,
+            username: format!("user{}", id),
+            display_name: format!("User {}", id),
+        }
+    }
+
+    fn forum_post(id: i32, author: Option<i32>, addressed: &[i32]) -> ForumPost {
+        ForumPost {
+            discussion_id: 9,
+            post: Post {
+                id,
+                number: 1,
+                author: author.map(user),
+                created_at: Utc::now(),
+                content: "Hi".to_string(),
+            },
+            addressed: addressed.iter().copied().map(user).collect(),
+        }
+    }
+
+    fn ids(posts: &[ForumPost]) -> Vec<i32> {
+        posts.iter().map(|p| p.post.id).collect()
+    }
+
+    fn is_character(id: i32) -> bool {
+        CHARACTERS.contains(&id)
+    }
+
+    #[test]
+    fn takes_posts_after_the_cursor_oldest_first() {
+        let posts = [12, 10, 11, 9]
+            .map(|id| forum_post(id, Some(3), &[]))
+            .to_vec();
+        let (new_posts, cursor) = take_new(posts, 10);
+        assert_eq!(ids(&new_posts), [11, 12]);
+        assert_eq!(cursor, 12);
+    }
+
+    #[test]
+    fn keeps_the_cursor_without_new_posts() {
+        let (new_posts, cursor) = take_new(Vec::new(), 10);
+        assert!(new_posts.is_empty());
+        assert_eq!(cursor, 10);
+        // Posts deleted since the last poll can leave only older ones.
+        let (new_posts, cursor) = take_new(vec![forum_post(8, Some(3), &[])], 10);
+        assert!(new_posts.is_empty());
+        assert_eq!(cursor, 10);
+    }
+
+    #[test]
+    fn starts_the_cursor_at_the_newest_post() {
+        let posts = vec![forum_post(4, None, &[]), forum_post(7, None, &[])];
+        assert_eq!(newest_id(&posts), Some(7));
+        assert_eq!(newest_id(&[]), None);
+    }
+
+    #[test]
+    fn finds_the_characters_a_human_addresses() {
+        let post = forum_post(1, Some(3), &[2, 4, 5]);
+        assert_eq!(addressed_characters(&post, is_character), [2, 5]);
+        let post = forum_post(1, Some(3), &[4]);
+        assert!(addressed_characters(&post, is_character).is_empty());
+    }
+
+    #[test]
+    fn characters_do_not_answer_each_other() {
+        let post = forum_post(1, Some(2), &[5]);
+        assert!(addressed_characters(&post, is_character).is_empty());
+    }
+
+    #[test]
+    fn posts_of_deleted_users_are_not_answered() {
+        let post = forum_post(1, None, &[5]);
+        assert!(addressed_characters(&post, is_character).is_empty());
+    }
+}
@@ -1,87 +1,986 @@
-use lazy_static::lazy_static;
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use async_trait::async_trait;
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER};
 use reqwest::Error as ReqwestError;
+use reqwest::{RequestBuilder, Response};
+use serde::Deserialize;
 use serde_json::{json, Value};
 use std::env;
 use std::error::Error as StdError;
 use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+use tiktoken_rs::model::get_context_size;
 impl StdError for LlmError {}
 
-lazy_static! {
-    static ref AI_MODEL: String = {
-        dotenvy::dotenv().ok();
-        dotenvy::var("AI_MODEL").unwrap_or_else(|_| panic!("AI_MODEL must be set"))
-    };
-}
+pub const DEFAULT_OPENAI_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+const DEFAULT_ANTHROPIC_URL: &str = "https://api.anthropic.com/v1";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// Room left for the answer in the context window, also the most tokens
+/// Anthropic is asked for.
+const RESPONSE_TOKENS: u32 = 1024;
+/// Longest wait a server can ask for before a request is retried.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
 
 #[derive(Debug)]
 pub enum LlmError {
     MissingApiKey,
+    MissingModel,
     MissingContent,
+    UnknownProvider(String),
+    /// The provider has no embeddings API.
+    NoEmbeddings(String),
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    ServerError {
+        status: u16,
+        message: String,
+    },
+    ContextTooLong(String),
+    ContentFiltered(String),
+    /// Any other non-success response, e.g. a bad request or exhausted quota.
+    Api {
+        status: u16,
+        message: String,
+    },
+    Timeout,
     ReqwestError(ReqwestError),
 }
 
 impl From<ReqwestError> for LlmError {
     fn from(error: ReqwestError) -> Self {
-        LlmError::ReqwestError(error)
+        if error.is_timeout() {
+            LlmError::Timeout
+        } else {
+            LlmError::ReqwestError(error)
+        }
+    }
+}
+
+impl LlmError {
+    /// Whether the same request may succeed if sent again later.
+    fn is_retryable(&self) -> bool {
+        match self {
+            LlmError::RateLimited { .. } | LlmError::ServerError { .. } | LlmError::Timeout => true,
+            LlmError::ReqwestError(error) => error.is_connect(),
+            _ => false,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            LlmError::RateLimited { retry_after } => *retry_after,
+            _ => None,
+        }
     }
 }
 
 impl Display for LlmError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
-            LlmError::MissingApiKey => write!(f, "Missing OpenAI API Key"),
+            LlmError::MissingApiKey => write!(f, "Missing LLM API Key"),
+            LlmError::MissingModel => write!(f, "Missing LLM model name"),
             LlmError::MissingContent => write!(f, "Missing content in LLM response"),
+            LlmError::UnknownProvider(name) => write!(f, "Unknown LLM provider: {}", name),
+            LlmError::NoEmbeddings(name) => write!(f, "{} has no embeddings API", name),
+            LlmError::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(f, "LLM rate limited, retry after {:?}", retry_after),
+            LlmError::RateLimited { retry_after: None } => write!(f, "LLM rate limited"),
+            LlmError::ServerError { status, message } => {
+                write!(f, "LLM server error {}: {}", status, message)
+            }
+            LlmError::ContextTooLong(message) => write!(f, "LLM context too long: {}", message),
+            LlmError::ContentFiltered(message) => {
+                write!(f, "LLM content filtered: {}", message)
+            }
+            LlmError::Api { status, message } => write!(f, "LLM API error {}: {}", status, message),
+            LlmError::Timeout => write!(f, "LLM request timed out"),
             LlmError::ReqwestError(error) => write!(f, "Reqwest error: {}", error),
         }
     }
 }
 
-pub async fn get_llm_response(
-    system_message: &str,
-    user_message: &str,
-) -> Result<String, LlmError> {
-    let api_key = env::var("OPENAI_API_KEY").map_err(|_| LlmError::MissingApiKey)?;
-    let chat_url = "https://api.openai.com/v1/chat/completions";
-
-    let messages = json!([
-        {
-            "role": "system",
-            "content": system_message
-        },
-        {
-            "role": "user",
-            "content": user_message
-        }
-    ]);
-
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("Bearer {}", api_key))
-            .map_err(|_| LlmError::MissingApiKey)?,
-    );
-
-    println!("Request to LLM");
-    let client = reqwest::Client::new();
-    let ai_model: String = AI_MODEL.to_string();
-    let res: Value = client
-        .post(chat_url)
-        .headers(headers)
-        .json(&json!({
-            "model": ai_model,
-            "messages": messages
-        }))
-        .send()
-        .await?
-        .json()
-        .await?;
+/// A wait asked for by a server, capped to `MAX_RETRY_AFTER`.
+fn server_wait(seconds: f64) -> Option<Duration> {
+    if seconds.is_nan() {
+        return None;
+    }
+    Duration::try_from_secs_f64(seconds.clamp(0.0, MAX_RETRY_AFTER.as_secs_f64())).ok()
+}
 
-    if let Some(content) = res["choices"][0]["message"]["content"].as_str() {
-        Ok(content.to_string())
+/// Parses the Go-style durations of OpenAI's `x-ratelimit-reset-*` headers,
+/// such as `1s`, `6m0s` or `20ms`.
+fn parse_reset_duration(value: &str) -> Option<Duration> {
+    let mut seconds = 0.0;
+    let mut number = String::new();
+    let mut chars = value.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 3600.0,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                0.001
+            }
+            'm' => 60.0,
+            's' => 1.0,
+            _ => return None,
+        };
+        seconds += number.parse::<f64>().ok()? * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        seconds += number.parse::<f64>().ok()?;
+    }
+    server_wait(seconds)
+}
+
+/// How long the server asked us to wait, from `Retry-After` or, failing
+/// that, from the reset time of whichever OpenAI rate limit ran out.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(wait) = header(RETRY_AFTER.as_str())
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .and_then(server_wait)
+    {
+        return Some(wait);
+    }
+    let resets: Vec<(bool, Duration)> = ["requests", "tokens"]
+        .iter()
+        .filter_map(|limit| {
+            let reset = parse_reset_duration(header(&format!("x-ratelimit-reset-{}", limit))?)?;
+            let exhausted = header(&format!("x-ratelimit-remaining-{}", limit)) == Some("0");
+            Some((exhausted, reset))
+        })
+        .collect();
+    resets
+        .iter()
+        .filter(|(exhausted, _)| *exhausted)
+        .chain(resets.iter())
+        .map(|(_, reset)| *reset)
+        .next()
+}
+
+async fn error_from_response(resp: Response) -> LlmError {
+    let status = resp.status().as_u16();
+    let retry_after = retry_after(resp.headers());
+    let body = resp.text().await.unwrap_or_default();
+    classify_error(status, retry_after, &body)
+}
+
+/// Tells what went wrong from the status and body of an error response.
+fn classify_error(status: u16, retry_after: Option<Duration>, body: &str) -> LlmError {
+    let json: Value = serde_json::from_str(body).unwrap_or(Value::Null);
+    let message = json["error"]["message"]
+        .as_str()
+        .or(json["error"].as_str())
+        .unwrap_or(body)
+        .to_string();
+    let code = json["error"]["code"]
+        .as_str()
+        .or(json["error"]["type"].as_str())
+        .unwrap_or("");
+    let lower = message.to_lowercase();
+    if status == 429 && code != "insufficient_quota" {
+        LlmError::RateLimited { retry_after }
+    } else if status >= 500 {
+        LlmError::ServerError { status, message }
+    } else if code == "context_length_exceeded"
+        || lower.contains("context length")
+        || lower.contains("prompt is too long")
+    {
+        LlmError::ContextTooLong(message)
+    } else if code == "content_filter"
+        || code == "content_policy_violation"
+        || lower.contains("content management policy")
+    {
+        LlmError::ContentFiltered(message)
     } else {
-        println!("LLM response: {:?}", res);
-        Err(LlmError::MissingContent)
+        LlmError::Api { status, message }
+    }
+}
+
+/// Sends a request and returns its JSON body, classifying error responses.
+pub async fn send_json(request: RequestBuilder) -> Result<Value, LlmError> {
+    let resp = request.send().await?;
+    if !resp.status().is_success() {
+        return Err(error_from_response(resp).await);
+    }
+    Ok(resp.json().await?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn user(content: &str) -> Self {
+        Self {
+            role: Role::User,
+            content: content.to_string(),
+        }
+    }
+
+    pub fn assistant(content: &str) -> Self {
+        Self {
+            role: Role::Assistant,
+            content: content.to_string(),
+        }
+    }
+}
+
+/// A chat completion request, independent of the provider wire format.
+#[derive(Debug, Clone)]
+pub struct ChatRequest {
+    pub system: String,
+    pub messages: Vec<ChatMessage>,
+    /// Overrides the provider's configured model.
+    pub model: Option<String>,
+}
+
+impl ChatRequest {
+    pub fn new(system: &str, user_message: &str) -> Self {
+        Self {
+            system: system.to_string(),
+            messages: vec![ChatMessage::user(user_message)],
+            model: None,
+        }
+    }
+
+    /// A request with no messages yet, to be filled with `push`.
+    pub fn empty(system: &str) -> Self {
+        Self {
+            system: system.to_string(),
+            messages: Vec::new(),
+            model: None,
+        }
+    }
+
+    /// Adds a message. Consecutive messages with the same role are merged,
+    /// since Anthropic requires roles to alternate.
+    pub fn push(&mut self, message: ChatMessage) {
+        match self.messages.last_mut() {
+            Some(last) if last.role == message.role => {
+                last.content.push_str("\n\n");
+                last.content.push_str(&message.content);
+            }
+            _ => self.messages.push(message),
+        }
+    }
+
+    /// The messages as plain text, for logging.
+    pub fn transcript(&self) -> String {
+        match self.messages.as_slice() {
+            [message] => message.content.clone(),
+            messages => messages
+                .iter()
+                .map(|m| format!("[{}]\n{}", m.role.as_str(), m.content))
+                .collect::<Vec<String>>()
+                .join("\n\n"),
+        }
+    }
+
+    pub fn with_model(mut self, model: Option<&str>) -> Self {
+        self.model = model.map(|m| m.to_string());
+        self
+    }
+
+    fn model<'a>(&'a self, default: &'a str) -> &'a str {
+        self.model.as_deref().unwrap_or(default)
+    }
+
+    fn messages_json(&self) -> Vec<Value> {
+        self.messages
+            .iter()
+            .map(|m| json!({ "role": m.role.as_str(), "content": m.content }))
+            .collect()
+    }
+
+    /// Messages with the system message as the first entry, as used by
+    /// OpenAI-compatible and Ollama endpoints.
+    fn messages_with_system_json(&self) -> Vec<Value> {
+        let mut messages = vec![json!({ "role": "system", "content": self.system })];
+        messages.extend(self.messages_json());
+        messages
+    }
+}
+
+/// Generated text, along with what produced it.
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub content: String,
+    pub model: String,
+    pub prompt_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+}
+
+impl Completion {
+    fn new(content: &str, model: &str, prompt_tokens: &Value, completion_tokens: &Value) -> Self {
+        Self {
+            content: content.to_string(),
+            model: model.to_string(),
+            prompt_tokens: prompt_tokens.as_u64().map(|t| t as u32),
+            completion_tokens: completion_tokens.as_u64().map(|t| t as u32),
+        }
+    }
+}
+
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, LlmError>;
+}
+
+/// OpenAI chat completions API, or any server that mimics it (llama.cpp,
+/// vLLM, LM Studio...).
+pub struct OpenAiProvider {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+impl OpenAiProvider {
+    pub fn new(
+        client: reqwest::Client,
+        base_url: &str,
+        api_key: Option<String>,
+        model: &str,
+    ) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model: model.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, LlmError> {
+        let chat_url = format!("{}/chat/completions", self.base_url);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        if let Some(api_key) = &self.api_key {
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", api_key))
+                    .map_err(|_| LlmError::MissingApiKey)?,
+            );
+        }
+
+        let model = request.model(&self.model);
+        println!("Request to LLM (OpenAI), model {}", model);
+        let res = send_json(self.client.post(chat_url).headers(headers).json(&json!({
+            "model": model,
+            "messages": request.messages_with_system_json()
+        })))
+        .await?;
+
+        if res["choices"][0]["finish_reason"] == "content_filter" {
+            return Err(LlmError::ContentFiltered(
+                "completion stopped by the content filter".to_string(),
+            ));
+        }
+        if let Some(content) = res["choices"][0]["message"]["content"].as_str() {
+            Ok(Completion::new(
+                content,
+                model,
+                &res["usage"]["prompt_tokens"],
+                &res["usage"]["completion_tokens"],
+            ))
+        } else {
+            println!("LLM response: {:?}", res);
+            Err(LlmError::MissingContent)
+        }
+    }
+}
+
+/// Ollama's native chat API.
+pub struct OllamaProvider {
+    client: reqwest::Client,
+    base_url: String,
+    model: String,
+}
+
+impl OllamaProvider {
+    pub fn new(client: reqwest::Client, base_url: &str, model: &str) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OllamaProvider {
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, LlmError> {
+        let chat_url = format!("{}/api/chat", self.base_url);
+
+        let model = request.model(&self.model);
+        println!("Request to LLM (Ollama), model {}", model);
+        let res = send_json(self.client.post(chat_url).json(&json!({
+            "model": model,
+            "messages": request.messages_with_system_json(),
+            "stream": false
+        })))
+        .await?;
+
+        if let Some(content) = res["message"]["content"].as_str() {
+            Ok(Completion::new(
+                content,
+                model,
+                &res["prompt_eval_count"],
+                &res["eval_count"],
+            ))
+        } else {
+            println!("LLM response: {:?}", res);
+            Err(LlmError::MissingContent)
+        }
+    }
+}
+
+/// Anthropic-style messages API.
+pub struct AnthropicProvider {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    model: String,
+    max_tokens: u32,
+}
+
+impl AnthropicProvider {
+    pub fn new(client: reqwest::Client, base_url: &str, api_key: &str, model: &str) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            model: model.to_string(),
+            max_tokens: RESPONSE_TOKENS,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, LlmError> {
+        let chat_url = format!("{}/messages", self.base_url);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(
+            "x-api-key",
+            HeaderValue::from_str(&self.api_key).map_err(|_| LlmError::MissingApiKey)?,
+        );
+        headers.insert(
+            "anthropic-version",
+            HeaderValue::from_static(ANTHROPIC_VERSION),
+        );
+
+        let model = request.model(&self.model);
+        println!("Request to LLM (Anthropic), model {}", model);
+        let res = send_json(self.client.post(chat_url).headers(headers).json(&json!({
+            "model": model,
+            "max_tokens": self.max_tokens,
+            "system": request.system,
+            "messages": request.messages_json()
+        })))
+        .await?;
+
+        let content = res["content"].as_array().map(|blocks| {
+            blocks
+                .iter()
+                .filter(|b| b["type"] == "text")
+                .filter_map(|b| b["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("")
+        });
+        match content {
+            Some(content) if !content.is_empty() => Ok(Completion::new(
+                &content,
+                model,
+                &res["usage"]["input_tokens"],
+                &res["usage"]["output_tokens"],
+            )),
+            _ => {
+                println!("LLM response: {:?}", res);
+                Err(LlmError::MissingContent)
+            }
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    4
+}
+
+fn default_initial_backoff() -> f64 {
+    1.0
+}
+
+fn default_max_backoff() -> f64 {
+    60.0
+}
+
+fn default_timeout() -> f64 {
+    120.0
+}
+
+/// How failed LLM requests are retried, the `[forums.<name>.llm.retry]` table.
+/// Rate limits, server errors, timeouts and connection failures are retried;
+/// anything else fails immediately.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff_secs: f64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff_secs: f64,
+    /// Timeout of a single request.
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            initial_backoff_secs: default_initial_backoff(),
+            max_backoff_secs: default_max_backoff(),
+            timeout_secs: default_timeout(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.timeout_secs > 0.0 && Duration::try_from_secs_f64(self.timeout_secs).is_ok()) {
+            return Err("llm.retry.timeout_secs must be a positive number".to_string());
+        }
+        // Backoffs share the cap put on server-requested waits.
+        let valid = |value: f64| (0.0..=MAX_RETRY_AFTER.as_secs_f64()).contains(&value);
+        if !valid(self.initial_backoff_secs) {
+            return Err(
+                "llm.retry.initial_backoff_secs must be between 0 and 3600 seconds".to_string(),
+            );
+        }
+        if !valid(self.max_backoff_secs) {
+            return Err(
+                "llm.retry.max_backoff_secs must be between 0 and 3600 seconds".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Timeout of a single request, the default one if the policy wasn't
+    /// validated.
+    pub fn timeout(&self) -> Duration {
+        Duration::try_from_secs_f64(self.timeout_secs)
+            .unwrap_or_else(|_| Duration::from_secs_f64(default_timeout()))
+    }
+
+    /// Makes `call` until it succeeds, fails for good or runs out of
+    /// attempts. `what` names the call in the log.
+    pub async fn retry<T, F, Fut>(&self, what: &str, mut call: F) -> Result<T, LlmError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, LlmError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match call().await {
+                Err(e) if e.is_retryable() && attempt < self.max_attempts => {
+                    let delay = e.retry_after().unwrap_or_else(|| self.backoff(attempt));
+                    println!(
+                        "{} attempt {} failed ({}), retrying in {:.1}s",
+                        what,
+                        attempt,
+                        e,
+                        delay.as_secs_f64()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Exponential backoff for the given (1-based) failed attempt, with
+    /// jitter so that parallel runs don't retry in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self.initial_backoff_secs * 2f64.powi(attempt as i32 - 1);
+        let capped = exponential.min(self.max_backoff_secs);
+        server_wait(capped * rand::thread_rng().gen_range(0.5..=1.0)).unwrap_or(MAX_RETRY_AFTER)
+    }
+}
+
+/// Wraps a provider, retrying transient failures according to a policy.
+pub struct RetryingProvider {
+    inner: Box<dyn LlmProvider>,
+    policy: RetryPolicy,
+}
+
+impl RetryingProvider {
+    pub fn new(inner: Box<dyn LlmProvider>, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+#[async_trait]
+impl LlmProvider for RetryingProvider {
+    async fn complete(&self, request: &ChatRequest) -> Result<Completion, LlmError> {
+        self.policy
+            .retry("LLM", || self.inner.complete(request))
+            .await
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    #[default]
+    OpenAi,
+    Ollama,
+    Anthropic,
+}
+
+impl FromStr for ProviderKind {
+    type Err = LlmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "openai" => Ok(ProviderKind::OpenAi),
+            "ollama" => Ok(ProviderKind::Ollama),
+            "anthropic" => Ok(ProviderKind::Anthropic),
+            other => Err(LlmError::UnknownProvider(other.to_string())),
+        }
+    }
+}
+
+/// Which provider to talk to and how, as found in a forum's `[llm]` table.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LlmConfig {
+    #[serde(default)]
+    pub provider: ProviderKind,
+    pub model: String,
+    pub base_url: Option<String>,
+    /// Name of the environment variable holding the API key. Defaults to
+    /// `OPENAI_API_KEY` or `ANTHROPIC_API_KEY` depending on the provider.
+    pub api_key_env: Option<String>,
+    /// Most tokens a prompt may take, system message included. Defaults to
+    /// the model's context size, when tiktoken knows it, minus room for the
+    /// answer.
+    pub max_prompt_tokens: Option<usize>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+impl LlmConfig {
+    /// Reads the provider selected by `LLM_PROVIDER` (`openai`, `ollama` or
+    /// `anthropic`, defaulting to `openai`). `LLM_BASE_URL` overrides the
+    /// provider's default endpoint and `AI_MODEL` selects the model.
+    pub fn from_env() -> Result<Self, LlmError> {
+        dotenvy::dotenv().ok();
+        let provider = match env::var("LLM_PROVIDER") {
+            Ok(name) => name.parse()?,
+            Err(_) => ProviderKind::default(),
+        };
+        Ok(Self {
+            provider,
+            model: env::var("AI_MODEL").map_err(|_| LlmError::MissingModel)?,
+            base_url: env::var("LLM_BASE_URL").ok(),
+            api_key_env: None,
+            max_prompt_tokens: None,
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    /// Token budget of a prompt sent to `model`.
+    pub fn prompt_budget(&self, model: &str) -> usize {
+        self.max_prompt_tokens
+            .unwrap_or_else(|| get_context_size(model).saturating_sub(RESPONSE_TOKENS as usize))
+    }
+
+    pub fn api_key(&self) -> Option<String> {
+        let var = match (&self.api_key_env, self.provider) {
+            (Some(var), _) => var.as_str(),
+            (None, ProviderKind::OpenAi) => "OPENAI_API_KEY",
+            (None, ProviderKind::Anthropic) => "ANTHROPIC_API_KEY",
+            (None, ProviderKind::Ollama) => return None,
+        };
+        env::var(var).ok()
+    }
+
+    pub fn build_provider(&self) -> Result<Box<dyn LlmProvider>, LlmError> {
+        let api_key = self.api_key();
+        let client = reqwest::Client::builder()
+            .timeout(self.retry.timeout())
+            .build()?;
+        let provider: Box<dyn LlmProvider> = match self.provider {
+            ProviderKind::OpenAi => {
+                let base_url = self.base_url.as_deref().unwrap_or(DEFAULT_OPENAI_URL);
+                if api_key.is_none() && base_url == DEFAULT_OPENAI_URL {
+                    return Err(LlmError::MissingApiKey);
+                }
+                Box::new(OpenAiProvider::new(client, base_url, api_key, &self.model))
+            }
+            ProviderKind::Ollama => {
+                let base_url = self.base_url.as_deref().unwrap_or(DEFAULT_OLLAMA_URL);
+                Box::new(OllamaProvider::new(client, base_url, &self.model))
+            }
+            ProviderKind::Anthropic => {
+                let base_url = self.base_url.as_deref().unwrap_or(DEFAULT_ANTHROPIC_URL);
+                let api_key = api_key.ok_or(LlmError::MissingApiKey)?;
+                Box::new(AnthropicProvider::new(
+                    client,
+                    base_url,
+                    &api_key,
+                    &self.model,
+                ))
+            }
+        };
+        Ok(Box::new(RetryingProvider::new(
+            provider,
+            self.retry.clone(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn parses_reset_durations() {
+        assert_eq!(parse_reset_duration("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(
+            parse_reset_duration("20ms"),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(
+            parse_reset_duration("2m3.5s"),
+            Some(Duration::from_secs_f64(123.5))
+        );
+        assert_eq!(
+            parse_reset_duration("2.5"),
+            Some(Duration::from_secs_f64(2.5))
+        );
+        assert_eq!(parse_reset_duration("soon"), None);
+        assert_eq!(parse_reset_duration("1e300s"), None);
+    }
+
+    #[test]
+    fn caps_reset_durations() {
+        assert_eq!(parse_reset_duration("1h0m1s"), Some(MAX_RETRY_AFTER));
+        assert_eq!(parse_reset_duration("9999999999h"), Some(MAX_RETRY_AFTER));
+    }
+
+    #[test]
+    fn reads_retry_after() {
+        let wait = retry_after(&headers(&[("retry-after", " 2 ")]));
+        assert_eq!(wait, Some(Duration::from_secs(2)));
+        let wait = retry_after(&headers(&[("retry-after", "-5")]));
+        assert_eq!(wait, Some(Duration::ZERO));
+        assert_eq!(retry_after(&headers(&[])), None);
+    }
+
+    #[test]
+    fn caps_absurd_retry_after() {
+        for value in ["inf", "1e300", "99999999"] {
+            let wait = retry_after(&headers(&[("retry-after", value)]));
+            assert_eq!(wait, Some(MAX_RETRY_AFTER), "{}", value);
+        }
+        assert_eq!(retry_after(&headers(&[("retry-after", "NaN")])), None);
+    }
+
+    #[test]
+    fn prefers_the_exhausted_rate_limit() {
+        let wait = retry_after(&headers(&[
+            ("x-ratelimit-reset-requests", "1s"),
+            ("x-ratelimit-remaining-requests", "10"),
+            ("x-ratelimit-reset-tokens", "6m0s"),
+            ("x-ratelimit-remaining-tokens", "0"),
+        ]));
+        assert_eq!(wait, Some(Duration::from_secs(360)));
+        let wait = retry_after(&headers(&[
+            ("x-ratelimit-reset-requests", "1s"),
+            ("x-ratelimit-reset-tokens", "6m0s"),
+        ]));
+        assert_eq!(wait, Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn retry_after_header_wins_over_resets() {
+        let wait = retry_after(&headers(&[
+            ("retry-after", "3"),
+            ("x-ratelimit-reset-tokens", "6m0s"),
+            ("x-ratelimit-remaining-tokens", "0"),
+        ]));
+        assert_eq!(wait, Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn classifies_rate_limits() {
+        let error = classify_error(429, Some(Duration::from_secs(2)), "");
+        assert!(matches!(
+            error,
+            LlmError::RateLimited { retry_after: Some(d) } if d == Duration::from_secs(2)
+        ));
+        assert!(error.is_retryable());
+    }
+
+    #[test]
+    fn exhausted_quota_is_not_a_rate_limit() {
+        let body =
+            r#"{"error": {"message": "You exceeded your quota", "code": "insufficient_quota"}}"#;
+        let error = classify_error(429, None, body);
+        assert!(matches!(
+            &error,
+            LlmError::Api { status: 429, message } if message == "You exceeded your quota"
+        ));
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn classifies_server_errors() {
+        let error = classify_error(503, None, "upstream unavailable");
+        assert!(matches!(
+            &error,
+            LlmError::ServerError { status: 503, message } if message == "upstream unavailable"
+        ));
+        assert!(error.is_retryable());
+    }
+
+    #[test]
+    fn classifies_context_too_long() {
+        let openai =
+            r#"{"error": {"message": "Too many tokens", "code": "context_length_exceeded"}}"#;
+        assert!(matches!(
+            classify_error(400, None, openai),
+            LlmError::ContextTooLong(_)
+        ));
+        let anthropic = r#"{"error": {"type": "invalid_request_error", "message": "prompt is too long: 300000 tokens"}}"#;
+        assert!(matches!(
+            classify_error(400, None, anthropic),
+            LlmError::ContextTooLong(_)
+        ));
+        let ollama = r#"{"error": "exceeds the context length"}"#;
+        assert!(matches!(
+            classify_error(400, None, ollama),
+            LlmError::ContextTooLong(m) if m == "exceeds the context length"
+        ));
+    }
+
+    #[test]
+    fn classifies_content_filters() {
+        let body = r#"{"error": {"message": "Filtered", "code": "content_filter"}}"#;
+        let error = classify_error(400, None, body);
+        assert!(matches!(error, LlmError::ContentFiltered(_)));
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn other_errors_keep_their_body() {
+        assert!(matches!(
+            classify_error(401, None, "not json"),
+            LlmError::Api { status: 401, message } if message == "not json"
+        ));
+    }
+
+    #[test]
+    fn validates_timeouts() {
+        let policy = |timeout_secs| RetryPolicy {
+            timeout_secs,
+            ..RetryPolicy::default()
+        };
+        assert!(policy(30.0).validate().is_ok());
+        for timeout in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e300] {
+            assert!(policy(timeout).validate().is_err(), "{}", timeout);
+        }
+    }
+
+    #[test]
+    fn validates_backoffs() {
+        let policy = |initial_backoff_secs, max_backoff_secs| RetryPolicy {
+            initial_backoff_secs,
+            max_backoff_secs,
+            ..RetryPolicy::default()
+        };
+        assert!(policy(0.0, 3600.0).validate().is_ok());
+        for backoff in [-1.0, f64::NAN, f64::INFINITY, 3601.0, 1e300] {
+            assert!(policy(backoff, 60.0).validate().is_err(), "{}", backoff);
+            assert!(policy(1.0, backoff).validate().is_err(), "{}", backoff);
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            initial_backoff_secs: 2.0,
+            max_backoff_secs: 10.0,
+            ..RetryPolicy::default()
+        };
+        let within = |attempt, low: f64, high: f64| {
+            let backoff = policy.backoff(attempt).as_secs_f64();
+            assert!((low..=high).contains(&backoff), "{}: {}", attempt, backoff);
+        };
+        within(1, 1.0, 2.0);
+        within(2, 2.0, 4.0);
+        within(3, 4.0, 8.0);
+        within(4, 5.0, 10.0);
+        within(200, 5.0, 10.0);
+    }
+
+    #[test]
+    fn never_panics_on_unvalidated_backoffs() {
+        let policy = RetryPolicy {
+            initial_backoff_secs: 1e300,
+            max_backoff_secs: 1e300,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(3), MAX_RETRY_AFTER);
+        let policy = RetryPolicy {
+            initial_backoff_secs: -5.0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::ZERO);
     }
 }
@@ -1,18 +1,41 @@
-use characters::get_character;
+use std::{error::Error, path::PathBuf};
+
+use config::Config;
+use deployment::Deployment;
 use dotenvy::dotenv;
-use english::EnglishLanguage;
-use flarum::Forum;
-use language::Language;
-use portuguese::PortugueseLanguage;
+use dry_run::DryRun;
+use embeddings::DISCUSSION;
+use forum::{DiscussionQuery, DiscussionSort};
+use memory::format_memory;
+use scheduler::run_daemon;
+use selection::SelectionContext;
+use simple_error::SimpleError;
+use store::{HistoryFilter, Store};
 use structopt::StructOpt;
 
 mod characters;
+mod config;
+mod deployment;
 mod dice_roll;
+mod discourse;
+mod dry_run;
+mod embeddings;
 mod english;
 mod flarum;
+mod forum;
+mod injection;
 mod language;
+mod listener;
 mod llm;
+mod memory;
+mod moderation;
+mod personas;
 mod portuguese;
+mod relationships;
+mod scheduler;
+mod selection;
+mod store;
+mod tokens;
 
 #[derive(StructOpt, Debug)]
 struct Opt {
@@ -25,41 +48,480 @@ struct Opt {
     #[structopt(short = "c", long = "create_new_topic")]
     create_new_topic: bool,
 
+    /// Name of the forum in the config file
+    #[structopt(short = "f", long = "forum")]
+    forum: Option<String>,
+
+    /// Deprecated alias for --forum
     #[structopt(short = "l", long = "language")]
     language: Option<String>,
+
+    #[structopt(long = "config", default_value = "autoforum.toml", parse(from_os_str))]
+    config: PathBuf,
+
+    /// Perform reads and LLM calls but print writes instead of posting them
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+
+    /// Append dry-run writes to this JSON Lines file instead of stdout (implies --dry-run)
+    #[structopt(long = "dry-run-output", parse(from_os_str))]
+    dry_run_output: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() {
-    _ = dotenv();
-    let opt = Opt::from_args();
-    let lang: Box<dyn Language>;
-    let forum;
-    if opt.language == Some("pt".to_string()) {
-        lang = Box::new(PortugueseLanguage);
-        forum = Forum::new("https://forumbr.fbmac.net/api");
-    } else {
-        lang = Box::new(EnglishLanguage);
-        forum = Forum::new("https://forum.fbmac.net/api");
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Keep running, acting on each forum (or only --forum) on its configured schedule
+    Daemon,
+    /// List the forum's characters and check the account behind each user id
+    Characters,
+    /// List the discussions a character (-u, or a random one) could reply to
+    Discussions {
+        /// last_posted, most_comments or newest
+        #[structopt(long, default_value = "last_posted")]
+        sort: DiscussionSort,
+
+        /// Only discussions with this tag
+        #[structopt(long)]
+        tag: Option<String>,
+
+        /// Only discussions matching this search
+        #[structopt(long)]
+        search: Option<String>,
+
+        #[structopt(long, default_value = "0")]
+        offset: u32,
+
+        #[structopt(long)]
+        limit: Option<u32>,
+    },
+    /// Show recorded actions, filtered by --forum, --user_id and --discussion_id
+    History {
+        /// Only show failed actions
+        #[structopt(long)]
+        failed: bool,
+
+        #[structopt(short = "n", long, default_value = "20")]
+        limit: u32,
+
+        /// Also print prompts and generated content
+        #[structopt(short = "v", long)]
+        verbose: bool,
+    },
+    /// Show moderation decisions, filtered by --forum, --user_id and --discussion_id
+    Moderation {
+        /// Only show texts that were flagged, regenerated or rejected
+        #[structopt(long)]
+        objected: bool,
+
+        #[structopt(short = "n", long, default_value = "20")]
+        limit: u32,
+
+        /// Also print the moderated texts
+        #[structopt(short = "v", long)]
+        verbose: bool,
+    },
+    /// Show what characters (or only -u) think of each other
+    Relationships,
+    /// Embed the newest discussions so they can be linked as related discussions
+    Index {
+        /// Pages of discussions to go through
+        #[structopt(long, default_value = "5")]
+        pages: u32,
+
+        #[structopt(long, default_value = "20")]
+        page_size: u32,
+    },
+    /// Show the unread notifications of a character (-u, or every one) and mark them read
+    Inbox {
+        /// Leave the notifications unread
+        #[structopt(long)]
+        peek: bool,
+    },
+    /// Show what a character (-u) remembers, memories of --discussion_id first
+    Memories {
+        #[structopt(short = "n", long, default_value = "20")]
+        limit: u32,
+    },
+}
+
+impl Opt {
+    fn forum_name(&self) -> Option<&str> {
+        self.forum.as_deref().or(self.language.as_deref())
+    }
+
+    fn dry_run(&self) -> Option<DryRun> {
+        if self.dry_run || self.dry_run_output.is_some() {
+            Some(DryRun::new(self.dry_run_output.clone()))
+        } else {
+            None
+        }
     }
-    let car = get_character(lang.as_ref(), opt.user_id);
-    let result;
+}
+
+async fn run_once(opt: &Opt, config: &Config) -> Result<(), Box<dyn Error>> {
+    let deployment = Deployment::new(config, config.forum(opt.forum_name())?, opt.dry_run())?;
+    let car = deployment
+        .character(opt.user_id)
+        .ok_or_else(|| SimpleError::new(format!("No character with user id {:?}", opt.user_id)))?;
     if opt.create_new_topic {
-        result = car.create_new_topic(lang.as_ref(), &forum).await;
+        car.create_new_topic(&deployment).await
     } else {
         match opt.discussion_id {
-            Some(discussion_id) => {
-                result = car
-                    .post_on_discussion(lang.as_ref(), &forum, discussion_id)
-                    .await;
+            Some(discussion_id) => car.post_on_discussion(&deployment, discussion_id).await,
+            None => car.interact_with_forum(&deployment).await,
+        }
+    }
+}
+
+fn history(
+    opt: &Opt,
+    config: &Config,
+    failed: bool,
+    limit: u32,
+    verbose: bool,
+) -> Result<(), Box<dyn Error>> {
+    let store = Store::open(&config.state_db)?;
+    let filter = HistoryFilter {
+        forum: opt.forum_name().map(|f| f.to_string()),
+        user_id: opt.user_id,
+        discussion_id: opt.discussion_id,
+        failed_only: failed,
+        limit,
+    };
+    let opt_num = |n: Option<i32>| n.map_or("-".to_string(), |n| n.to_string());
+    let opt_tokens = |n: Option<u32>| n.map_or("?".to_string(), |n| n.to_string());
+    for action in store.history(&filter)? {
+        println!(
+            "#{} {} [{}] {} ({}) {} discussion={} post={} model={} tokens={}+{} {}ms {}",
+            action.id,
+            action.created_at,
+            action.forum,
+            action.character,
+            action.user_id,
+            action.kind,
+            opt_num(action.discussion_id),
+            opt_num(action.post_id),
+            action.model.as_deref().unwrap_or("-"),
+            opt_tokens(action.prompt_tokens),
+            opt_tokens(action.completion_tokens),
+            action.latency_ms,
+            action.outcome,
+        );
+        if let Some(error) = &action.error {
+            println!("    error: {}", error);
+        }
+        if verbose {
+            println!("    prompt:\n{}\n", action.prompt);
+            if let Some(content) = &action.content {
+                println!("    content:\n{}\n", content);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn moderation(
+    opt: &Opt,
+    config: &Config,
+    objected: bool,
+    limit: u32,
+    verbose: bool,
+) -> Result<(), Box<dyn Error>> {
+    let store = Store::open(&config.state_db)?;
+    let filter = HistoryFilter {
+        forum: opt.forum_name().map(|f| f.to_string()),
+        user_id: opt.user_id,
+        discussion_id: opt.discussion_id,
+        failed_only: objected,
+        limit,
+    };
+    for record in store.moderation_log(&filter)? {
+        println!(
+            "#{} {} [{}] {} ({}) {} discussion={} attempt={} {}",
+            record.id,
+            record.created_at,
+            record.forum,
+            record.character,
+            record.user_id,
+            record.kind,
+            record
+                .discussion_id
+                .map_or("-".to_string(), |n| n.to_string()),
+            record.attempt,
+            record.decision,
+        );
+        for reason in record.reasons.iter().flat_map(|r| r.lines()) {
+            println!("    {}", reason);
+        }
+        if verbose {
+            println!("    content:\n{}\n", record.content);
+        }
+    }
+    Ok(())
+}
+
+fn memories(opt: &Opt, config: &Config, limit: u32) -> Result<(), Box<dyn Error>> {
+    let forum = config.forum(opt.forum_name())?;
+    let user_id = opt
+        .user_id
+        .ok_or_else(|| SimpleError::new("--user_id is required"))?;
+    let store = Store::open(&config.state_db)?;
+    for memory in store.recall(&forum.name, user_id, opt.discussion_id, &[], limit)? {
+        let person = memory.person.as_deref().unwrap_or("-");
+        println!("#{} with={} {}", memory.id, person, format_memory(&memory));
+    }
+    Ok(())
+}
+
+async fn characters(opt: &Opt, config: &Config) -> Result<(), Box<dyn Error>> {
+    let deployment = Deployment::new(config, config.forum(opt.forum_name())?, None)?;
+    for character in &deployment.characters {
+        match deployment.forum.identify(character.user_id).await {
+            Ok(user) => println!(
+                "{} {} -> @{} ({})",
+                character.user_id, character.name, user.username, user.display_name
+            ),
+            Err(e) => println!("{} {} -> Error: {}", character.user_id, character.name, e),
+        }
+    }
+    Ok(())
+}
+
+async fn inbox(opt: &Opt, config: &Config, peek: bool) -> Result<(), Box<dyn Error>> {
+    let deployment = Deployment::new(config, config.forum(opt.forum_name())?, opt.dry_run())?;
+    for character in &deployment.characters {
+        if opt.user_id.is_some_and(|id| id != character.user_id) {
+            continue;
+        }
+        let notifications = match character.inbox(&deployment, peek).await {
+            Ok(notifications) => notifications,
+            Err(e) => {
+                println!("{} {} -> Error: {}", character.user_id, character.name, e);
+                continue;
             }
-            None => {
-                result = car.interact_with_forum(lang.as_ref(), &forum).await;
+        };
+        println!("{} {}:", character.user_id, character.name);
+        let opt_num = |n: Option<i32>| n.map_or("-".to_string(), |n| n.to_string());
+        for notification in notifications {
+            println!(
+                "    #{} {} {} from {} discussion={} post={}",
+                notification.id,
+                notification.created_at,
+                notification.kind.as_str(),
+                notification
+                    .from
+                    .as_ref()
+                    .map_or("?".to_string(), |u| u.username.clone()),
+                opt_num(notification.discussion_id),
+                opt_num(notification.post_id),
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn discussions(
+    opt: &Opt,
+    config: &Config,
+    query: DiscussionQuery,
+) -> Result<(), Box<dyn Error>> {
+    let deployment = Deployment::new(config, config.forum(opt.forum_name())?, None)?;
+    let character = deployment
+        .character(opt.user_id)
+        .ok_or_else(|| SimpleError::new(format!("No character with user id {:?}", opt.user_id)))?;
+    println!("As {} ({}):", character.name, character.user_id);
+    let context = SelectionContext::new(&deployment)?;
+    for discussion in deployment
+        .forum
+        .list_discussions(character.user_id, &query)
+        .await?
+    {
+        let tags: Vec<&str> = discussion.tags.iter().map(|t| t.name.as_str()).collect();
+        println!(
+            "{} {} [{}] replies={} last={} by {} weight={:.4}",
+            discussion.id,
+            discussion.title,
+            tags.join(", "),
+            discussion.reply_count,
+            discussion
+                .last_posted_at
+                .map_or("-".to_string(), |t| t.to_rfc3339()),
+            discussion
+                .last_poster
+                .as_ref()
+                .map_or("?".to_string(), |u| u.username.clone()),
+            deployment.selector.weight(&discussion, &context),
+        );
+    }
+    Ok(())
+}
+
+fn relationships(opt: &Opt, config: &Config) -> Result<(), Box<dyn Error>> {
+    let deployment = Deployment::new(config, config.forum(opt.forum_name())?, None)?;
+    let affinities = deployment.store.affinities(&deployment.name, opt.user_id)?;
+    let name = |user_id: i32| {
+        deployment
+            .characters
+            .iter()
+            .find(|c| c.user_id == user_id)
+            .map_or(user_id.to_string(), |c| c.name.clone())
+    };
+    for character in &deployment.characters {
+        if opt.user_id.is_some_and(|id| id != character.user_id) {
+            continue;
+        }
+        for relationship in &character.relationships {
+            let affinity = affinities
+                .iter()
+                .find(|a| a.user_id == character.user_id && a.other_id == relationship.user_id);
+            println!(
+                "{} -> {}: {}, affinity {:.2} after {} interactions",
+                character.name,
+                relationship.name,
+                relationship.kind.as_str(),
+                affinity.map_or(relationship.kind.initial_affinity(), |a| a.affinity),
+                affinity.map_or(0, |a| a.interactions),
+            );
+        }
+    }
+    for affinity in affinities.iter().filter(|a| {
+        let character = deployment
+            .characters
+            .iter()
+            .find(|c| c.user_id == a.user_id);
+        character.is_none_or(|c| c.relationship(a.other_id).is_none())
+    }) {
+        println!(
+            "{} -> {}: affinity {:.2} after {} interactions, last {}",
+            name(affinity.user_id),
+            name(affinity.other_id),
+            affinity.affinity,
+            affinity.interactions,
+            affinity.updated_at,
+        );
+    }
+    Ok(())
+}
+
+async fn index(
+    opt: &Opt,
+    config: &Config,
+    pages: u32,
+    page_size: u32,
+) -> Result<(), Box<dyn Error>> {
+    let deployment = Deployment::new(config, config.forum(opt.forum_name())?, None)?;
+    let embeddings = deployment
+        .embeddings
+        .as_ref()
+        .ok_or_else(|| SimpleError::new("No [embeddings] table for this forum"))?;
+    let character = deployment
+        .character(opt.user_id)
+        .ok_or_else(|| SimpleError::new(format!("No character with user id {:?}", opt.user_id)))?;
+    let mut indexed = 0;
+    for page in 0..pages {
+        let query = DiscussionQuery::new()
+            .sort(DiscussionSort::Newest)
+            .offset(page * page_size)
+            .limit(page_size);
+        let discussions = deployment
+            .forum
+            .list_discussions(character.user_id, &query)
+            .await?;
+        if discussions.is_empty() {
+            break;
+        }
+        for discussion in discussions {
+            let id = i64::from(discussion.id);
+            if deployment.store.has_embedding(
+                &deployment.name,
+                DISCUSSION,
+                id,
+                &embeddings.model,
+            )? {
+                continue;
             }
+            let data = deployment.forum.fetch_discussion(discussion.id).await?;
+            embeddings
+                .index_discussion(&deployment.store, &deployment.name, &data)
+                .await?;
+            println!("{} {}", discussion.id, discussion.title);
+            indexed += 1;
         }
     }
+    println!("Indexed {} discussions", indexed);
+    Ok(())
+}
+
+async fn daemon(opt: &Opt, config: &Config) -> Result<(), Box<dyn Error>> {
+    let forum_configs = match opt.forum_name() {
+        Some(name) => vec![config.forum(Some(name))?],
+        None => config.forums.values().collect(),
+    };
+    let mut deployments = Vec::new();
+    for forum_config in forum_configs {
+        deployments.push((
+            Deployment::new(config, forum_config, opt.dry_run())?,
+            forum_config.schedule.clone(),
+            forum_config.listener.clone(),
+        ));
+    }
+    run_daemon(&deployments).await;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    _ = dotenv();
+    let opt = Opt::from_args();
+    let result = match Config::load(&opt.config) {
+        Ok(config) => match opt.command {
+            Some(Command::Daemon) => daemon(&opt, &config).await,
+            Some(Command::Characters) => characters(&opt, &config).await,
+            Some(Command::Discussions {
+                sort,
+                ref tag,
+                ref search,
+                offset,
+                limit,
+            }) => {
+                let mut query = DiscussionQuery::new().sort(sort).offset(offset);
+                if let Some(limit) = limit {
+                    query = query.limit(limit);
+                }
+                if let Some(tag) = tag {
+                    query = query.tag(tag);
+                }
+                if let Some(search) = search {
+                    query = query.search(search);
+                }
+                discussions(&opt, &config, query).await
+            }
+            Some(Command::History {
+                failed,
+                limit,
+                verbose,
+            }) => history(&opt, &config, failed, limit, verbose),
+            Some(Command::Moderation {
+                objected,
+                limit,
+                verbose,
+            }) => moderation(&opt, &config, objected, limit, verbose),
+            Some(Command::Relationships) => relationships(&opt, &config),
+            Some(Command::Index { pages, page_size }) => {
+                index(&opt, &config, pages, page_size).await
+            }
+            Some(Command::Memories { limit }) => memories(&opt, &config, limit),
+            Some(Command::Inbox { peek }) => inbox(&opt, &config, peek).await,
+            None => run_once(&opt, &config).await,
+        },
+        Err(e) => Err(e.into()),
+    };
     match result {
-        Ok(_) => println!("Success!"),
-        Err(e) => eprintln!("Error: {:?}", e),
+        Ok(_) if opt.command.is_none() => println!("Success!"),
+        Ok(_) => {}
+        Err(e) => eprintln!("Error: {}", e),
     }
 }
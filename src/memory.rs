@@ -0,0 +1,202 @@
+use serde::Deserialize;
+
+use crate::{
+    characters::Character,
+    deployment::Deployment,
+    embeddings::MEMORY,
+    forum::DiscussionData,
+    injection::{delimit_recollection, inline},
+    store::Memory,
+};
+
+fn default_recall() -> u32 {
+    5
+}
+
+/// Long-term memory of the characters, the `[forums.<name>.memory]` table.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MemoryConfig {
+    /// Summarize each post into its author's memory and recall memories
+    /// when replying.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Memories shown in a reply prompt.
+    #[serde(default = "default_recall")]
+    pub recall: u32,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            recall: default_recall(),
+        }
+    }
+}
+
+/// What the character remembers that is relevant to a discussion, as a
+/// prompt, or an empty string. Memories of the discussion and of the people
+/// in it come first. With embeddings, memories similar to `query`, the
+/// discussion's embedding, are added.
+pub fn recall(
+    deployment: &Deployment,
+    character: &Character,
+    data: &DiscussionData,
+    query: Option<&[f32]>,
+) -> String {
+    if !deployment.memory.enabled || deployment.memory.recall == 0 {
+        return String::new();
+    }
+    let mut person_ids: Vec<i32> = data
+        .posts
+        .iter()
+        .filter_map(|post| post.author.as_ref().map(|a| a.id))
+        .filter(|id| *id != character.user_id)
+        .collect();
+    person_ids.sort_unstable();
+    person_ids.dedup();
+    let mut memories = match deployment.store.recall(
+        &deployment.name,
+        character.user_id,
+        Some(data.discussion.id),
+        &person_ids,
+        deployment.memory.recall,
+    ) {
+        Ok(memories) => memories,
+        Err(e) => {
+            eprintln!("Could not recall memories: {}", e);
+            return String::new();
+        }
+    };
+    if let (Some(embeddings), Some(query)) = (&deployment.embeddings, query) {
+        let similar = embeddings
+            .nearest(
+                &deployment.store,
+                &deployment.name,
+                MEMORY,
+                Some(character.user_id),
+                query,
+                deployment.memory.recall as usize,
+            )
+            .and_then(|similar| {
+                let ids: Vec<i64> = similar
+                    .iter()
+                    .map(|e| e.ref_id)
+                    .filter(|id| memories.iter().all(|m| m.id != *id))
+                    .collect();
+                deployment.store.memories(&ids)
+            });
+        match similar {
+            Ok(similar) => memories = merge(memories, similar),
+            Err(e) => eprintln!("Could not recall similar memories: {}", e),
+        }
+    }
+    if memories.is_empty() {
+        return String::new();
+    }
+    deployment
+        .lang
+        .get_memories_prompt(&delimit_recollection(&format_memories(&memories)))
+}
+
+/// Recalled and similar memories without duplicates, newest first.
+fn merge(mut memories: Vec<Memory>, similar: Vec<Memory>) -> Vec<Memory> {
+    for memory in similar {
+        if memories.iter().all(|m| m.id != memory.id) {
+            memories.push(memory);
+        }
+    }
+    memories.sort_by_key(|m| std::cmp::Reverse(m.id));
+    memories
+}
+
+/// Memories given newest first, listed oldest first.
+fn format_memories(memories: &[Memory]) -> String {
+    memories
+        .iter()
+        .rev()
+        .map(format_memory)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// A memory on one line. Its title and summary come from forum posts.
+pub fn format_memory(memory: &Memory) -> String {
+    let date = memory.created_at.get(..10).unwrap_or(&memory.created_at);
+    format!(
+        "- {} [{}]: {}",
+        date,
+        inline(&memory.title),
+        inline(&memory.summary)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory(id: i64, created_at: &str, title: &str, summary: &str) -> Memory {
+        Memory {
+            id,
+            created_at: created_at.to_string(),
+            forum: "test".to_string(),
+            user_id: 6,
+            discussion_id: Some(1),
+            title: title.to_string(),
+            person_id: None,
+            person: None,
+            summary: summary.to_string(),
+        }
+    }
+
+    fn ids(memories: &[Memory]) -> Vec<i64> {
+        memories.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn merges_similar_memories_newest_first() {
+        let recalled = vec![memory(7, "", "", ""), memory(3, "", "", "")];
+        let similar = vec![
+            memory(5, "", "", ""),
+            memory(7, "", "", ""),
+            memory(9, "", "", ""),
+        ];
+        assert_eq!(ids(&merge(recalled, similar)), vec![9, 7, 5, 3]);
+        assert_eq!(ids(&merge(Vec::new(), Vec::new())), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn lists_memories_oldest_first() {
+        let memories = merge(
+            vec![memory(1, "2024-01-01 10:00:00", "Old", "first")],
+            vec![memory(2, "2024-02-01 10:00:00", "New", "second")],
+        );
+        assert_eq!(
+            format_memories(&memories),
+            "- 2024-01-01 [Old]: first\n- 2024-02-01 [New]: second"
+        );
+    }
+
+    #[test]
+    fn formats_memories_on_one_line() {
+        let memory = memory(
+            1,
+            "2024-03-05 18:30:12",
+            "Droids\n  for sale",
+            "Luke asked\nabout R2.",
+        );
+        assert_eq!(
+            format_memory(&memory),
+            "- 2024-03-05 [Droids for sale]: Luke asked about R2."
+        );
+    }
+
+    #[test]
+    fn keeps_short_dates_whole() {
+        assert_eq!(
+            format_memory(&memory(1, "today", "T", "S")),
+            "- today [T]: S"
+        );
+        assert_eq!(format_memory(&memory(1, "", "T", "S")), "-  [T]: S");
+    }
+}
@@ -0,0 +1,646 @@
+use std::{
+    env,
+    error::Error as StdError,
+    fmt::{self, Display, Formatter},
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use regex::{Regex, RegexBuilder};
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    characters::Character,
+    deployment::Deployment,
+    injection::{redact, LeakCheck},
+    llm::{send_json, ChatRequest, LlmConfig, LlmError, RetryPolicy, DEFAULT_OPENAI_URL},
+    store::{ActionRecord, ModerationRecord},
+};
+
+const DEFAULT_OPENAI_MODERATION_MODEL: &str = "omni-moderation-latest";
+
+/// What happens to a text a check objects to, from the mildest.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Publish it anyway, but record it for review.
+    Flag,
+    /// Generate another text, up to `max_regenerations` times, then reject.
+    Regenerate,
+    /// Don't publish anything.
+    Reject,
+}
+
+impl ModerationAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationAction::Flag => "flag",
+            ModerationAction::Regenerate => "regenerate",
+            ModerationAction::Reject => "reject",
+        }
+    }
+}
+
+fn default_max_regenerations() -> u32 {
+    2
+}
+
+fn default_reject() -> ModerationAction {
+    ModerationAction::Reject
+}
+
+fn default_regenerate() -> ModerationAction {
+    ModerationAction::Regenerate
+}
+
+fn default_openai_model() -> String {
+    DEFAULT_OPENAI_MODERATION_MODEL.to_string()
+}
+
+/// Checks generated texts go through before being published, the
+/// `[forums.<name>.moderation]` table. Texts are always checked for leaks
+/// of the system message and API keys.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ModerationConfig {
+    #[serde(default = "default_max_regenerations")]
+    pub max_regenerations: u32,
+    pub policy: Option<PolicyConfig>,
+    pub openai: Option<OpenAiModerationConfig>,
+    pub judge: Option<JudgeConfig>,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            max_regenerations: default_max_regenerations(),
+            policy: None,
+            openai: None,
+            judge: None,
+        }
+    }
+}
+
+/// Words and regular expressions a text must not contain.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PolicyConfig {
+    /// Matched as whole words, ignoring case.
+    #[serde(default)]
+    pub words: Vec<String>,
+    /// Regular expressions, matched ignoring case.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    #[serde(default = "default_reject")]
+    pub action: ModerationAction,
+}
+
+/// OpenAI's moderation endpoint, or any server that mimics it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct OpenAiModerationConfig {
+    #[serde(default = "default_openai_model")]
+    pub model: String,
+    pub base_url: Option<String>,
+    /// Defaults to `OPENAI_API_KEY`.
+    pub api_key_env: Option<String>,
+    #[serde(default = "default_regenerate")]
+    pub action: ModerationAction,
+}
+
+/// A second opinion of the forum's LLM, without the character's persona.
+#[derive(Deserialize, Debug, Clone)]
+pub struct JudgeConfig {
+    /// Overrides the forum's LLM model for judging.
+    pub model: Option<String>,
+    #[serde(default = "default_regenerate")]
+    pub action: ModerationAction,
+}
+
+impl ModerationConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(policy) = &self.policy {
+            policy.regexes()?;
+        }
+        Ok(())
+    }
+
+    /// The forum's moderator. `secrets` are the API keys texts must not
+    /// contain.
+    pub fn build(&self, llm: &LlmConfig, secrets: Vec<String>) -> Result<Moderator, LlmError> {
+        let mut checks: Vec<Box<dyn ContentCheck>> = vec![Box::new(LeakCheck {
+            secrets: secrets.clone(),
+        })];
+        if let Some(policy) = &self.policy {
+            checks.push(Box::new(PolicyCheck {
+                // Validated when the config was loaded.
+                regexes: policy.regexes().unwrap_or_default(),
+                action: policy.action,
+            }));
+        }
+        if let Some(openai) = &self.openai {
+            let base_url = openai.base_url.as_deref().unwrap_or(DEFAULT_OPENAI_URL);
+            let api_key_env = openai.api_key_env.as_deref().unwrap_or("OPENAI_API_KEY");
+            let api_key = env::var(api_key_env).ok();
+            if api_key.is_none() && base_url == DEFAULT_OPENAI_URL {
+                return Err(LlmError::MissingApiKey);
+            }
+            checks.push(Box::new(OpenAiModeration {
+                client: reqwest::Client::builder()
+                    .timeout(llm.retry.timeout())
+                    .build()?,
+                base_url: base_url.trim_end_matches('/').to_string(),
+                api_key,
+                model: openai.model.clone(),
+                action: openai.action,
+                retry: llm.retry.clone(),
+            }));
+        }
+        if let Some(judge) = &self.judge {
+            checks.push(Box::new(LlmJudge {
+                model: judge.model.clone(),
+                action: judge.action,
+            }));
+        }
+        Ok(Moderator {
+            checks,
+            max_regenerations: self.max_regenerations,
+            secrets,
+        })
+    }
+}
+
+/// `word` as a whole word. `\b` only holds next to a word character, so
+/// ends such as the `*` of `*bleep*` aren't bounded.
+fn word_pattern(word: &str) -> String {
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    let bound = |c| if is_word(c) { r"\b" } else { "" };
+    format!(
+        "{}{}{}",
+        bound(word.chars().next()),
+        regex::escape(word),
+        bound(word.chars().last())
+    )
+}
+
+impl PolicyConfig {
+    fn regexes(&self) -> Result<Vec<Regex>, String> {
+        let mut sources = self.patterns.clone();
+        if !self.words.is_empty() {
+            let words: Vec<String> = self.words.iter().map(|w| word_pattern(w)).collect();
+            sources.push(format!("(?:{})", words.join("|")));
+        }
+        sources
+            .iter()
+            .map(|source| {
+                RegexBuilder::new(source)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| format!("moderation.policy: invalid pattern {:?}: {}", source, e))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum ModerationError {
+    /// A check objected to the text, or kept objecting to its regenerations.
+    Rejected(String),
+    /// A check could not be performed, so nothing is published.
+    CheckFailed(&'static str, LlmError),
+}
+
+impl StdError for ModerationError {}
+
+impl Display for ModerationError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ModerationError::Rejected(reasons) => write!(f, "Rejected by moderation: {}", reasons),
+            ModerationError::CheckFailed(check, error) => {
+                write!(f, "Moderation check {} failed: {}", check, error)
+            }
+        }
+    }
+}
+
+/// One way of telling whether a text may be published.
+#[async_trait]
+pub trait ContentCheck: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// What to do with a text this check objects to.
+    fn action(&self) -> ModerationAction;
+
+    /// Why the text may not be published as is, or `None` if it may.
+    async fn check(
+        &self,
+        deployment: &Deployment,
+        character: &Character,
+        action: &mut ActionRecord,
+        content: &str,
+    ) -> Result<Option<String>, LlmError>;
+}
+
+/// Local word list and regular expressions.
+pub struct PolicyCheck {
+    regexes: Vec<Regex>,
+    action: ModerationAction,
+}
+
+impl PolicyCheck {
+    /// What `content` contains of the word list and patterns, if anything.
+    fn find(&self, content: &str) -> Option<String> {
+        let matches: Vec<&str> = self
+            .regexes
+            .iter()
+            .filter_map(|regex| regex.find(content))
+            .map(|m| m.as_str())
+            .collect();
+        if matches.is_empty() {
+            return None;
+        }
+        Some(format!("contains {:?}", matches))
+    }
+}
+
+#[async_trait]
+impl ContentCheck for PolicyCheck {
+    fn name(&self) -> &'static str {
+        "policy"
+    }
+
+    fn action(&self) -> ModerationAction {
+        self.action
+    }
+
+    async fn check(
+        &self,
+        _deployment: &Deployment,
+        _character: &Character,
+        _action: &mut ActionRecord,
+        content: &str,
+    ) -> Result<Option<String>, LlmError> {
+        Ok(self.find(content))
+    }
+}
+
+pub struct OpenAiModeration {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    action: ModerationAction,
+    /// The forum's LLM retry policy, for rate limits and server errors.
+    retry: RetryPolicy,
+}
+
+impl OpenAiModeration {
+    async fn moderate(&self, content: &str) -> Result<Value, LlmError> {
+        let url = format!("{}/moderations", self.base_url);
+        let mut request = self.client.post(url).json(&json!({
+            "model": self.model,
+            "input": content
+        }));
+        if let Some(api_key) = &self.api_key {
+            let value = HeaderValue::from_str(&format!("Bearer {}", api_key))
+                .map_err(|_| LlmError::MissingApiKey)?;
+            request = request.header(AUTHORIZATION, value);
+        }
+        println!("Request to moderation (OpenAI), model {}", self.model);
+        send_json(request).await
+    }
+}
+
+#[async_trait]
+impl ContentCheck for OpenAiModeration {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn action(&self) -> ModerationAction {
+        self.action
+    }
+
+    async fn check(
+        &self,
+        _deployment: &Deployment,
+        _character: &Character,
+        _action: &mut ActionRecord,
+        content: &str,
+    ) -> Result<Option<String>, LlmError> {
+        let res = self
+            .retry
+            .retry("Moderation", || self.moderate(content))
+            .await?;
+        let result = &res["results"][0];
+        let flagged = result["flagged"]
+            .as_bool()
+            .ok_or(LlmError::MissingContent)?;
+        if !flagged {
+            return Ok(None);
+        }
+        let categories: Vec<&str> = result["categories"]
+            .as_object()
+            .map(|categories| {
+                categories
+                    .iter()
+                    .filter(|(_, value)| value.as_bool() == Some(true))
+                    .map(|(name, _)| name.as_str())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Some(format!("flagged as {}", categories.join(", "))))
+    }
+}
+
+/// Asks the forum's LLM whether the text is acceptable.
+pub struct LlmJudge {
+    model: Option<String>,
+    action: ModerationAction,
+}
+
+#[async_trait]
+impl ContentCheck for LlmJudge {
+    fn name(&self) -> &'static str {
+        "judge"
+    }
+
+    fn action(&self) -> ModerationAction {
+        self.action
+    }
+
+    async fn check(
+        &self,
+        deployment: &Deployment,
+        _character: &Character,
+        action: &mut ActionRecord,
+        content: &str,
+    ) -> Result<Option<String>, LlmError> {
+        let request = ChatRequest::new(
+            &deployment.lang.get_moderation_system_message(),
+            &deployment.lang.get_moderation_prompt(content),
+        )
+        .with_model(self.model.as_deref());
+        let started = Instant::now();
+        let completion = deployment.llm.complete(&request).await?;
+        action.add_completion(&request.transcript(), &completion, started.elapsed());
+        Ok(judge_objection(&completion.content))
+    }
+}
+
+/// Why the judge's `answer` rejects the text, or `None` if it's `OK`.
+/// Anything but `OK` on its own, such as `OKAY, but…`, rejects it.
+fn judge_objection(answer: &str) -> Option<String> {
+    let answer = answer.trim();
+    let verdict = answer.trim_start_matches(|c: char| !c.is_alphanumeric());
+    let token_end = verdict
+        .find(|c: char| !c.is_alphanumeric())
+        .unwrap_or(verdict.len());
+    let (token, rest) = verdict.split_at(token_end);
+    if token.eq_ignore_ascii_case("OK") {
+        return None;
+    }
+    let reason = rest.trim_start_matches(|c: char| !c.is_alphanumeric());
+    if token.eq_ignore_ascii_case("REJECT") && !reason.is_empty() {
+        Some(reason.to_string())
+    } else {
+        Some(answer.to_string())
+    }
+}
+
+/// What to do with a text once moderated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Publish,
+    Regenerate,
+}
+
+/// Runs the checks of a forum on generated texts.
+pub struct Moderator {
+    checks: Vec<Box<dyn ContentCheck>>,
+    max_regenerations: u32,
+    /// Kept out of the recorded decisions.
+    secrets: Vec<String>,
+}
+
+impl Moderator {
+    /// What happens to a text the checks objected to with `actions`: the
+    /// strictest of them, with a rejection instead of a regeneration once
+    /// `max_regenerations` were made, which ends the regenerating.
+    fn decide(&self, actions: &[ModerationAction], attempt: u32) -> Option<ModerationAction> {
+        match actions.iter().copied().max() {
+            Some(ModerationAction::Regenerate) if attempt >= self.max_regenerations => {
+                Some(ModerationAction::Reject)
+            }
+            worst => worst,
+        }
+    }
+
+    /// Runs every check on `content`, generated for `action` after `attempt`
+    /// regenerations, and records the decision. The harshest action of the
+    /// checks that object wins.
+    pub async fn review(
+        &self,
+        deployment: &Deployment,
+        character: &Character,
+        action: &mut ActionRecord,
+        content: &str,
+        attempt: u32,
+    ) -> Result<Verdict, ModerationError> {
+        let mut actions = Vec::new();
+        let mut reasons = Vec::new();
+        for check in &self.checks {
+            let objection = check
+                .check(deployment, character, action, content)
+                .await
+                .map_err(|e| ModerationError::CheckFailed(check.name(), e))?;
+            if let Some(reason) = objection {
+                actions.push(check.action());
+                reasons.push(format!(
+                    "{} ({}): {}",
+                    check.name(),
+                    check.action().as_str(),
+                    redact(&reason, &self.secrets)
+                ));
+            }
+            // Later checks may cost a request and can't change the outcome.
+            if actions.contains(&ModerationAction::Reject) {
+                break;
+            }
+        }
+        let worst = self.decide(&actions, attempt);
+        let decision = worst.map_or("publish", |w| w.as_str());
+        let reasons = (!reasons.is_empty()).then(|| reasons.join("\n"));
+        println!(
+            "[{}] Moderation of {}'s {}: {}",
+            deployment.name, action.character, action.kind, decision
+        );
+        if let Some(reasons) = &reasons {
+            println!("{}", reasons);
+        }
+        let record = ModerationRecord {
+            created_at: Utc::now().to_rfc3339(),
+            forum: action.forum.clone(),
+            user_id: action.user_id,
+            character: action.character.clone(),
+            kind: action.kind.clone(),
+            discussion_id: action.discussion_id,
+            attempt,
+            decision: decision.to_string(),
+            reasons: reasons.clone(),
+            // The text may be rejected for giving away a secret.
+            content: redact(content, &self.secrets),
+            ..Default::default()
+        };
+        if let Err(e) = deployment.store.record_moderation(&record) {
+            eprintln!("Could not record moderation decision: {}", e);
+        }
+        match worst {
+            None | Some(ModerationAction::Flag) => Ok(Verdict::Publish),
+            Some(ModerationAction::Regenerate) => Ok(Verdict::Regenerate),
+            Some(ModerationAction::Reject) => Err(ModerationError::Rejected(
+                reasons.unwrap_or_default().replace('\n', "; "),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(words: &[&str], patterns: &[&str]) -> PolicyCheck {
+        let config = PolicyConfig {
+            words: words.iter().map(|w| w.to_string()).collect(),
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            action: ModerationAction::Reject,
+        };
+        PolicyCheck {
+            regexes: config.regexes().unwrap(),
+            action: config.action,
+        }
+    }
+
+    fn moderator(max_regenerations: u32) -> Moderator {
+        Moderator {
+            checks: Vec::new(),
+            max_regenerations,
+            secrets: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn matches_whole_words_ignoring_case() {
+        let policy = policy(&["darn", "heck"], &[]);
+        assert_eq!(
+            policy.find("Well, DARN it.").as_deref(),
+            Some("contains [\"DARN\"]")
+        );
+        assert_eq!(
+            policy.find("What the heck!").as_deref(),
+            Some("contains [\"heck\"]")
+        );
+        assert_eq!(policy.find("A darned heckler"), None);
+    }
+
+    #[test]
+    fn matches_words_with_symbols() {
+        let policy = policy(&["f*ck", "*bleep*", "c++"], &[]);
+        assert!(policy.find("Oh f*ck.").is_some());
+        assert!(policy.find("Say *bleep* again").is_some());
+        assert!(policy.find("I write c++ daily").is_some());
+        assert!(policy.find("*bleep*").is_some());
+        assert_eq!(policy.find("a f*cking mess"), None);
+        assert_eq!(policy.find("fuck"), None);
+    }
+
+    #[test]
+    fn matches_patterns() {
+        let policy = policy(&["darn"], &[r"\d{3}-\d{4}"]);
+        assert_eq!(
+            policy.find("Call 555-1234, darn").as_deref(),
+            Some("contains [\"555-1234\", \"darn\"]")
+        );
+        assert_eq!(policy.find("Call me"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let config = ModerationConfig {
+            policy: Some(PolicyConfig {
+                words: Vec::new(),
+                patterns: vec!["(unclosed".to_string()],
+                action: ModerationAction::Flag,
+            }),
+            ..ModerationConfig::default()
+        };
+        let error = config.validate().unwrap_err();
+        assert!(error.starts_with("moderation.policy: invalid pattern \"(unclosed\""));
+    }
+
+    #[test]
+    fn judge_passes_ok() {
+        for answer in ["OK", "ok.", " **OK**\n", "OK - fine"] {
+            assert_eq!(judge_objection(answer), None, "{}", answer);
+        }
+    }
+
+    #[test]
+    fn judge_rejects_anything_but_ok() {
+        assert_eq!(
+            judge_objection("OKAY, but this should be rejected").as_deref(),
+            Some("OKAY, but this should be rejected")
+        );
+        assert_eq!(
+            judge_objection("Not OK at all").as_deref(),
+            Some("Not OK at all")
+        );
+        assert_eq!(judge_objection("").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn judge_gives_its_reason() {
+        assert_eq!(
+            judge_objection("REJECT: threatens a user").as_deref(),
+            Some("threatens a user")
+        );
+        assert_eq!(
+            judge_objection("**Reject** - personal data").as_deref(),
+            Some("personal data")
+        );
+        assert_eq!(judge_objection("REJECT").as_deref(), Some("REJECT"));
+    }
+
+    #[test]
+    fn publishes_what_no_check_objects_to() {
+        assert_eq!(moderator(2).decide(&[], 0), None);
+        assert_eq!(
+            moderator(2).decide(&[ModerationAction::Flag], 0),
+            Some(ModerationAction::Flag)
+        );
+    }
+
+    #[test]
+    fn takes_the_strictest_action() {
+        use ModerationAction::*;
+        let moderator = moderator(2);
+        assert_eq!(moderator.decide(&[Flag, Regenerate], 0), Some(Regenerate));
+        assert_eq!(moderator.decide(&[Reject, Flag], 0), Some(Reject));
+        assert_eq!(
+            moderator.decide(&[Regenerate, Reject, Flag], 0),
+            Some(Reject)
+        );
+    }
+
+    #[test]
+    fn rejects_after_max_regenerations() {
+        use ModerationAction::*;
+        let twice = moderator(2);
+        assert_eq!(twice.decide(&[Regenerate], 1), Some(Regenerate));
+        assert_eq!(twice.decide(&[Regenerate], 2), Some(Reject));
+        assert_eq!(twice.decide(&[Flag, Regenerate], 3), Some(Reject));
+        // Flagged texts are published however many were regenerated.
+        assert_eq!(twice.decide(&[Flag], 2), Some(Flag));
+        assert_eq!(moderator(0).decide(&[Regenerate], 0), Some(Reject));
+    }
+}
@@ -0,0 +1,629 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error as StdError,
+    fmt::{self, Display, Formatter},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+use crate::{
+    characters::Character,
+    language::Language,
+    relationships::{Relationship, RelationshipKind},
+};
+
+#[derive(Debug)]
+pub enum PersonaError {
+    Io(PathBuf, io::Error),
+    Invalid {
+        path: PathBuf,
+        line: Option<usize>,
+        message: String,
+    },
+    UnknownFormat(PathBuf),
+    DuplicateUserId {
+        path: PathBuf,
+        user_id: i32,
+        first: String,
+        second: String,
+    },
+    NoCharacters(String),
+}
+
+impl StdError for PersonaError {}
+
+impl Display for PersonaError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PersonaError::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            PersonaError::Invalid {
+                path,
+                line: Some(line),
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+            PersonaError::Invalid {
+                path,
+                line: None,
+                message,
+            } => write!(f, "{}: {}", path.display(), message),
+            PersonaError::UnknownFormat(path) => write!(
+                f,
+                "{}: persona files must end in .toml, .yaml, .yml or .json",
+                path.display()
+            ),
+            PersonaError::DuplicateUserId {
+                path,
+                user_id,
+                first,
+                second,
+            } => write!(
+                f,
+                "{}: user id {} is used by both {} and {}",
+                path.display(),
+                user_id,
+                first,
+                second
+            ),
+            PersonaError::NoCharacters(forum) => {
+                write!(f, "No characters are configured for forum {}", forum)
+            }
+        }
+    }
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPersona {
+    name: String,
+    /// Forum user id, keyed by forum name from the config file.
+    user_ids: BTreeMap<String, i32>,
+    /// Full persona text, keyed by language code.
+    #[serde(default)]
+    persona: BTreeMap<String, String>,
+    /// Name of the parodied character, keyed by language code. Used with the
+    /// language's generic parody prompt instead of a full persona.
+    #[serde(default)]
+    parody: BTreeMap<String, String>,
+    model: Option<String>,
+    #[serde(default = "default_weight")]
+    weight: f64,
+    /// Tags the character likes to post in, keyed by forum name.
+    #[serde(default)]
+    tags: BTreeMap<String, Vec<String>>,
+    /// What the character is to other characters, keyed by their name.
+    #[serde(default)]
+    relationships: BTreeMap<String, RelationshipKind>,
+}
+
+/// A persona as written in a persona file. Validation happens while
+/// deserializing so that errors carry the parser's location.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "RawPersona")]
+struct Persona {
+    name: String,
+    user_ids: BTreeMap<String, i32>,
+    persona: BTreeMap<String, String>,
+    parody: BTreeMap<String, String>,
+    model: Option<String>,
+    weight: f64,
+    tags: BTreeMap<String, Vec<String>>,
+    relationships: BTreeMap<String, RelationshipKind>,
+}
+
+impl TryFrom<RawPersona> for Persona {
+    type Error = String;
+
+    fn try_from(raw: RawPersona) -> Result<Self, Self::Error> {
+        if raw.name.trim().is_empty() {
+            return Err("character name must not be empty".to_string());
+        }
+        if raw.user_ids.is_empty() {
+            return Err(format!("{}: user_ids must not be empty", raw.name));
+        }
+        if raw.persona.is_empty() && raw.parody.is_empty() {
+            return Err(format!(
+                "{}: needs a persona or parody for at least one language",
+                raw.name
+            ));
+        }
+        if let Some(lang) = raw.persona.keys().find(|l| raw.parody.contains_key(*l)) {
+            return Err(format!(
+                "{}: language {} has both a persona and a parody",
+                raw.name, lang
+            ));
+        }
+        if let Some((lang, _)) = raw.persona.iter().find(|(_, text)| text.trim().is_empty()) {
+            return Err(format!("{}: persona for {} is empty", raw.name, lang));
+        }
+        if raw.relationships.contains_key(&raw.name) {
+            return Err(format!("{}: has a relationship with itself", raw.name));
+        }
+        if !(raw.weight.is_finite() && raw.weight >= 0.0) {
+            return Err(format!(
+                "{}: weight must be a non-negative number",
+                raw.name
+            ));
+        }
+        Ok(Self {
+            name: raw.name,
+            user_ids: raw.user_ids,
+            persona: raw.persona,
+            parody: raw.parody,
+            model: raw.model,
+            weight: raw.weight,
+            tags: raw.tags,
+            relationships: raw.relationships,
+        })
+    }
+}
+
+impl Persona {
+    /// The character as it appears on the given forum, if it is active there.
+    /// `user_ids` maps the names of the characters of the forum to their ids.
+    fn character(
+        &self,
+        forum: &str,
+        language_code: &str,
+        lang: &dyn Language,
+        user_ids: &HashMap<&str, i32>,
+    ) -> Option<Character> {
+        let user_id = *self.user_ids.get(forum)?;
+        let system_message = match (
+            self.persona.get(language_code),
+            self.parody.get(language_code),
+        ) {
+            (Some(persona), _) => lang.persona_system_message(persona),
+            (None, Some(parody)) => lang.parody_system_message(parody),
+            (None, None) => return None,
+        };
+        Some(Character {
+            user_id,
+            name: self.name.clone(),
+            system_message,
+            model: self.model.clone(),
+            weight: self.weight,
+            tags: self.tags.get(forum).cloned().unwrap_or_default(),
+            relationships: self
+                .relationships
+                .iter()
+                .filter_map(|(name, kind)| {
+                    Some(Relationship {
+                        user_id: *user_ids.get(name.as_str())?,
+                        name: name.clone(),
+                        kind: *kind,
+                    })
+                })
+                .collect(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PersonaFile {
+    characters: Vec<Persona>,
+}
+
+fn line_of_offset(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+/// serde_yaml and serde_json append " at line X column Y" to their messages,
+/// which is redundant once the line is reported separately.
+fn without_location(message: String) -> String {
+    match message.rfind(" at line ") {
+        Some(index) => message[..index].to_string(),
+        None => message,
+    }
+}
+
+fn parse_file(path: &Path) -> Result<Vec<Persona>, PersonaError> {
+    let text = fs::read_to_string(path).map_err(|e| PersonaError::Io(path.into(), e))?;
+    parse_personas(path, &text)
+}
+
+/// Parses the text of a persona file in the format its extension names.
+fn parse_personas(path: &Path, text: &str) -> Result<Vec<Persona>, PersonaError> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let parsed: Result<PersonaFile, (Option<usize>, String)> = match extension {
+        "toml" => toml::from_str(text).map_err(|e| {
+            (
+                e.span().map(|span| line_of_offset(text, span.start)),
+                e.message().to_string(),
+            )
+        }),
+        "yaml" | "yml" => serde_yaml::from_str(text).map_err(|e| {
+            (
+                e.location().map(|l| l.line()),
+                without_location(e.to_string()),
+            )
+        }),
+        "json" => serde_json::from_str(text)
+            .map_err(|e| (Some(e.line()), without_location(e.to_string()))),
+        _ => return Err(PersonaError::UnknownFormat(path.into())),
+    };
+    parsed
+        .map(|file| file.characters)
+        .map_err(|(line, message)| PersonaError::Invalid {
+            path: path.into(),
+            line: match invalid_character(extension, text) {
+                Some((line, error)) if message.contains(&error) => Some(line),
+                _ => line,
+            },
+            message,
+        })
+}
+
+/// The line and validation error of the first character of a file that
+/// can't be loaded, if it is its validation that fails. Parsers report those
+/// errors at the start of the list of characters (TOML, YAML) or after the
+/// character (JSON) instead.
+fn invalid_character(extension: &str, text: &str) -> Option<(usize, String)> {
+    #[derive(Deserialize)]
+    struct Entries<T> {
+        characters: Vec<T>,
+    }
+    let entries: Vec<(usize, Option<RawPersona>)> = match extension {
+        "toml" => toml::from_str::<Entries<toml::Spanned<toml::Value>>>(text)
+            .ok()?
+            .characters
+            .into_iter()
+            .map(|entry| {
+                let line = line_of_offset(text, entry.span().start);
+                (line, entry.into_inner().try_into().ok())
+            })
+            .collect(),
+        "yaml" | "yml" => {
+            let entries = serde_yaml::from_str::<Entries<serde_yaml::Value>>(text)
+                .ok()?
+                .characters;
+            let lines = yaml_item_lines(text);
+            if lines.len() != entries.len() {
+                return None;
+            }
+            lines
+                .into_iter()
+                .zip(entries)
+                .map(|(line, value)| (line, serde_yaml::from_value(value).ok()))
+                .collect()
+        }
+        "json" => serde_json::from_str::<Entries<&RawValue>>(text)
+            .ok()?
+            .characters
+            .into_iter()
+            .map(|raw| {
+                let offset = raw.get().as_ptr() as usize - text.as_ptr() as usize;
+                (
+                    line_of_offset(text, offset),
+                    serde_json::from_str(raw.get()).ok(),
+                )
+            })
+            .collect(),
+        _ => return None,
+    };
+    for (line, raw) in entries {
+        if let Err(error) = Persona::try_from(raw?) {
+            return Some((line, error));
+        }
+    }
+    None
+}
+
+/// Lines where the items of the block sequence of characters start in a
+/// YAML persona file.
+fn yaml_item_lines(text: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    let mut in_characters = false;
+    let mut item_indent = None;
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - trimmed.len();
+        let is_item = trimmed == "-" || trimmed.starts_with("- ");
+        if indent == 0 && !is_item {
+            in_characters = trimmed.starts_with("characters:");
+            continue;
+        }
+        if in_characters && is_item && *item_indent.get_or_insert(indent) == indent {
+            lines.push(i + 1);
+        }
+    }
+    lines
+}
+
+fn is_persona_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("toml" | "yaml" | "yml" | "json")
+    )
+}
+
+/// Persona files found at the given paths. Directories are scanned (not
+/// recursively) for `.toml`, `.yaml`, `.yml` and `.json` files.
+fn persona_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, PersonaError> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let entries = fs::read_dir(path).map_err(|e| PersonaError::Io(path.clone(), e))?;
+            let mut dir_files = Vec::new();
+            for entry in entries {
+                let entry = entry.map_err(|e| PersonaError::Io(path.clone(), e))?;
+                if is_persona_file(&entry.path()) {
+                    dir_files.push(entry.path());
+                }
+            }
+            dir_files.sort();
+            files.extend(dir_files);
+        } else {
+            files.push(path.clone());
+        }
+    }
+    Ok(files)
+}
+
+/// Loads every persona file and returns the characters active on `forum`.
+pub fn load_characters(
+    paths: &[PathBuf],
+    forum: &str,
+    language_code: &str,
+    lang: &dyn Language,
+) -> Result<Vec<Character>, PersonaError> {
+    let mut personas: Vec<(PathBuf, Persona)> = Vec::new();
+    for path in persona_files(paths)? {
+        for persona in parse_file(&path)? {
+            personas.push((path.clone(), persona));
+        }
+    }
+    for (path, persona) in &personas {
+        if let Some(name) = persona
+            .relationships
+            .keys()
+            .find(|name| personas.iter().all(|(_, p)| &p.name != *name))
+        {
+            return Err(PersonaError::Invalid {
+                path: path.clone(),
+                line: None,
+                message: format!(
+                    "{}: relationship with unknown character {}",
+                    persona.name, name
+                ),
+            });
+        }
+    }
+    let user_ids: HashMap<&str, i32> = personas
+        .iter()
+        .filter_map(|(_, p)| Some((p.name.as_str(), *p.user_ids.get(forum)?)))
+        .collect();
+
+    let mut characters: Vec<Character> = Vec::new();
+    let mut seen: HashMap<i32, String> = HashMap::new();
+    for (path, persona) in &personas {
+        let Some(character) = persona.character(forum, language_code, lang, &user_ids) else {
+            continue;
+        };
+        if let Some(first) = seen.insert(character.user_id, character.name.clone()) {
+            return Err(PersonaError::DuplicateUserId {
+                path: path.clone(),
+                user_id: character.user_id,
+                first,
+                second: character.name,
+            });
+        }
+        characters.push(character);
+    }
+    if characters.is_empty() {
+        return Err(PersonaError::NoCharacters(forum.to_string()));
+    }
+    Ok(characters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::english::EnglishLanguage;
+
+    fn invalid(file: &str, text: &str) -> (Option<usize>, String) {
+        match parse_personas(Path::new(file), text) {
+            Err(PersonaError::Invalid { line, message, .. }) => (line, message),
+            other => panic!(
+                "expected an invalid persona, got {:?}",
+                other.map(|p| p.len())
+            ),
+        }
+    }
+
+    #[test]
+    fn parses_every_format() {
+        let toml =
+            "[[characters]]\nname = \"Luke\"\nuser_ids = { en = 6 }\nparody = { en = \"Luke\" }\n";
+        let yaml =
+            "characters:\n  - name: Luke\n    user_ids: { en: 6 }\n    parody: { en: Luke }\n";
+        let json = r#"{"characters": [{"name": "Luke", "user_ids": {"en": 6}, "parody": {"en": "Luke"}}]}"#;
+        for (file, text) in [("a.toml", toml), ("a.yaml", yaml), ("a.json", json)] {
+            let personas = parse_personas(Path::new(file), text).unwrap();
+            assert_eq!(personas.len(), 1, "{}", file);
+            assert_eq!(personas[0].name, "Luke");
+            assert_eq!(personas[0].weight, 1.0);
+        }
+    }
+
+    #[test]
+    fn reports_the_line_of_toml_errors() {
+        let text = "[[characters]]\nname = \"Luke\"\nuser_ids = { en = 6 }\n\n[[characters]]\nname = \"Leia\"\nuser_ids = {}\nparody = { en = \"Leia\" }\n";
+        let (line, message) = invalid("a.toml", text);
+        assert_eq!(line, Some(1));
+        assert!(
+            message.contains("Luke: needs a persona or parody"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_the_invalid_character() {
+        let text = "[[characters]]\nname = \"Luke\"\nuser_ids = { en = 6 }\nparody = { en = \"Luke\" }\n\n[[characters]]\nname = \"Leia\"\nuser_ids = { en = 7 }\nparody = { en = \"Leia\" }\nweight = -1.0\n";
+        let (line, message) = invalid("a.toml", text);
+        assert_eq!(line, Some(6));
+        assert_eq!(message, "Leia: weight must be a non-negative number");
+    }
+
+    #[test]
+    fn reports_the_line_of_unknown_fields() {
+        let text = "characters:\n  - name: Luke\n    user_ids: { en: 6 }\n    parody: { en: Luke }\n    wieght: 2\n";
+        let (line, message) = invalid("a.yaml", text);
+        assert_eq!(line, Some(5));
+        assert!(
+            message.starts_with("characters[0]: unknown field `wieght`"),
+            "{}",
+            message
+        );
+        assert!(!message.contains(" at line "), "{}", message);
+    }
+
+    #[test]
+    fn reports_the_line_of_json_errors() {
+        let text = "{\"characters\": [\n  {\"name\": \"\", \"user_ids\": {\"en\": 6}, \"parody\": {\"en\": \"x\"}}\n]}";
+        let (line, message) = invalid("a.json", text);
+        assert_eq!(line, Some(2));
+        assert_eq!(message, "character name must not be empty");
+    }
+
+    #[test]
+    fn reports_the_line_where_a_json_character_starts() {
+        let text = "{\"characters\": [\n  {\"name\": \"Luke\", \"user_ids\": {\"en\": 6}, \"parody\": {\"en\": \"Luke\"}},\n  {\n    \"name\": \"Leia\",\n    \"user_ids\": {}\n  }\n]}";
+        let (line, message) = invalid("a.json", text);
+        assert_eq!(line, Some(3));
+        assert_eq!(message, "Leia: user_ids must not be empty");
+    }
+
+    #[test]
+    fn reports_the_line_of_an_invalid_yaml_character() {
+        let text = "# Characters\ncharacters:\n- name: Luke\n  user_ids: { en: 6 }\n  persona:\n    en: |\n      - not an item\n- name: Leia\n  user_ids: { en: 7 }\n";
+        let (line, message) = invalid("a.yml", text);
+        assert_eq!(line, Some(8));
+        assert_eq!(
+            message,
+            "characters: Leia: needs a persona or parody for at least one language"
+        );
+    }
+
+    #[test]
+    fn keeps_the_line_of_earlier_parse_errors() {
+        let text = "[[characters]]\nname = \"Luke\"\nuser_ids = { en = 6 }\nparody = { en = \"Luke\" }\nweight = \"heavy\"\n\n[[characters]]\nname = \"Leia\"\nuser_ids = {}\n";
+        let (line, message) = invalid("a.toml", text);
+        assert_eq!(line, Some(5));
+        assert!(message.contains("invalid type"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_invalid_personas() {
+        let character = |fields: &str| {
+            format!(
+                "[[characters]]\nname = \"Luke\"\nuser_ids = {{ en = 6 }}\n{}\n",
+                fields
+            )
+        };
+        for (fields, expected) in [
+            (
+                "",
+                "Luke: needs a persona or parody for at least one language",
+            ),
+            (
+                "persona = { en = \"x\" }\nparody = { en = \"Luke\" }",
+                "Luke: language en has both a persona and a parody",
+            ),
+            ("persona = { en = \"  \" }", "Luke: persona for en is empty"),
+            (
+                "parody = { en = \"Luke\" }\nrelationships = { Luke = \"ally\" }",
+                "Luke: has a relationship with itself",
+            ),
+        ] {
+            let (_, message) = invalid("a.toml", &character(fields));
+            assert_eq!(message, expected);
+        }
+        let (_, message) = invalid(
+            "a.toml",
+            "[[characters]]\nname = \"Luke\"\nuser_ids = {}\nparody = { en = \"Luke\" }\n",
+        );
+        assert_eq!(message, "Luke: user_ids must not be empty");
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert!(matches!(
+            parse_personas(Path::new("a.txt"), ""),
+            Err(PersonaError::UnknownFormat(_))
+        ));
+    }
+
+    #[test]
+    fn formats_errors_with_their_line() {
+        let error = PersonaError::Invalid {
+            path: PathBuf::from("personas/a.toml"),
+            line: Some(3),
+            message: "bad".to_string(),
+        };
+        assert_eq!(error.to_string(), "personas/a.toml:3: bad");
+    }
+
+    fn load(name: &str, text: &str) -> Result<Vec<Character>, PersonaError> {
+        let dir = std::env::temp_dir().join(format!(
+            "autoforum-personas-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("characters.toml"), text).unwrap();
+        let result = load_characters(std::slice::from_ref(&dir), "en", "en", &EnglishLanguage);
+        fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn rejects_duplicate_user_ids() {
+        let text = "[[characters]]\nname = \"Luke\"\nuser_ids = { en = 6 }\nparody = { en = \"Luke\" }\n\n[[characters]]\nname = \"Leia\"\nuser_ids = { en = 6 }\nparody = { en = \"Leia\" }\n";
+        let error = load("duplicate", text).unwrap_err();
+        assert!(matches!(
+            &error,
+            PersonaError::DuplicateUserId { user_id: 6, first, second, .. }
+                if first == "Luke" && second == "Leia"
+        ));
+    }
+
+    #[test]
+    fn rejects_relationships_with_unknown_characters() {
+        let text = "[[characters]]\nname = \"Luke\"\nuser_ids = { en = 6 }\nparody = { en = \"Luke\" }\nrelationships = { Vader = \"nemesis\" }\n";
+        let error = load("unknown", text).unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("characters.toml: Luke: relationship with unknown character Vader"));
+    }
+
+    #[test]
+    fn loads_the_characters_of_a_forum() {
+        let text = "[[characters]]\nname = \"Luke\"\nuser_ids = { en = 6 }\nparody = { en = \"Luke\" }\nrelationships = { Leia = \"ally\" }\n\n[[characters]]\nname = \"Leia\"\nuser_ids = { en = 7 }\nparody = { pt = \"Leia\" }\n\n[[characters]]\nname = \"Han\"\nuser_ids = { pt = 8 }\nparody = { en = \"Han\" }\n";
+        let characters = load("forum", text).unwrap();
+        let names: Vec<&str> = characters.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["Luke"]);
+        assert_eq!(characters[0].relationships[0].user_id, 7);
+    }
+
+    #[test]
+    fn needs_characters_on_the_forum() {
+        let text =
+            "[[characters]]\nname = \"Han\"\nuser_ids = { pt = 8 }\nparody = { pt = \"Han\" }\n";
+        assert!(matches!(
+            load("none", text),
+            Err(PersonaError::NoCharacters(forum)) if forum == "en"
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{characters::Character, deployment::Deployment, store::Store};
+
+fn default_evolve() -> bool {
+    true
+}
+
+fn default_step() -> f64 {
+    0.1
+}
+
+/// How relationships between characters evolve, the
+/// `[forums.<name>.relationships]` table.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RelationshipConfig {
+    /// After a character replies to another one, have the LLM judge the tone
+    /// of the reply and move both characters' affinities accordingly.
+    #[serde(default = "default_evolve")]
+    pub evolve: bool,
+    /// How much a single friendly or hostile reply moves an affinity.
+    #[serde(default = "default_step")]
+    pub step: f64,
+}
+
+impl Default for RelationshipConfig {
+    fn default() -> Self {
+        Self {
+            evolve: default_evolve(),
+            step: default_step(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RelationshipKind {
+    Ally,
+    Rival,
+    Crush,
+    Nemesis,
+}
+
+impl RelationshipKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelationshipKind::Ally => "ally",
+            RelationshipKind::Rival => "rival",
+            RelationshipKind::Crush => "crush",
+            RelationshipKind::Nemesis => "nemesis",
+        }
+    }
+
+    /// Affinity before any interaction, from -1 (hatred) to 1 (adoration).
+    pub fn initial_affinity(&self) -> f64 {
+        match self {
+            RelationshipKind::Ally => 0.5,
+            RelationshipKind::Rival => -0.3,
+            RelationshipKind::Crush => 0.7,
+            RelationshipKind::Nemesis => -0.7,
+        }
+    }
+}
+
+/// What a character thinks of another character of the same forum.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Relationship {
+    /// The other character's user id.
+    pub user_id: i32,
+    pub name: String,
+    pub kind: RelationshipKind,
+}
+
+/// Affinity of `character` for `other` before they interacted: the one of
+/// their relationship, else neutral.
+fn initial_affinity(character: &Character, other: &Character) -> f64 {
+    character
+        .relationship(other.user_id)
+        .map_or(0.0, |r| r.kind.initial_affinity())
+}
+
+fn stored_affinity(
+    store: &Store,
+    forum: &str,
+    character: &Character,
+    other: &Character,
+) -> rusqlite::Result<f64> {
+    Ok(store
+        .affinity(forum, character.user_id, other.user_id)?
+        .unwrap_or_else(|| initial_affinity(character, other)))
+}
+
+/// Current affinity of `character` for the character `other`: the stored one
+/// if they interacted, else the one of their relationship, else neutral.
+pub fn affinity(deployment: &Deployment, character: &Character, other: &Character) -> f64 {
+    match stored_affinity(&deployment.store, &deployment.name, character, other) {
+        Ok(affinity) => affinity,
+        Err(e) => {
+            eprintln!("Could not read affinity: {}", e);
+            initial_affinity(character, other)
+        }
+    }
+}
+
+/// The system message of `character` when replying to `other`, with what it
+/// thinks of them.
+pub fn system_message(deployment: &Deployment, character: &Character, other: &Character) -> String {
+    let kind = character.relationship(other.user_id).map(|r| r.kind);
+    let note = deployment.lang.get_relationship_prompt(
+        &other.name,
+        kind,
+        affinity(deployment, character, other),
+    );
+    format!("{}\n\n{}", character.system_message, note)
+}
+
+fn move_affinities(
+    store: &Store,
+    forum: &str,
+    character: &Character,
+    other: &Character,
+    delta: f64,
+) -> rusqlite::Result<()> {
+    for (from, to) in [(character, other), (other, character)] {
+        let initial = initial_affinity(from, to);
+        store.adjust_affinity(forum, from.user_id, to.user_id, initial, delta)?;
+    }
+    Ok(())
+}
+
+/// Moves the affinities of `character` and `other` for each other after
+/// `character` replied to `other` in the given tone, from -1 (hostile) to 1
+/// (friendly). The change is symmetric: the one replied to warms up or cools
+/// down by as much as the one replying, up to `step`.
+pub fn interact(deployment: &Deployment, character: &Character, other: &Character, tone: f64) {
+    let delta = tone.clamp(-1.0, 1.0) * deployment.relationships.step;
+    if let Err(e) = move_affinities(&deployment.store, &deployment.name, character, other, delta) {
+        eprintln!("Could not update affinity: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn character(user_id: i32, name: &str, relationships: &[(i32, RelationshipKind)]) -> Character {
+        Character {
+            user_id,
+            name: name.to_string(),
+            system_message: String::new(),
+            model: None,
+            weight: 1.0,
+            tags: Vec::new(),
+            relationships: relationships
+                .iter()
+                .map(|&(user_id, kind)| Relationship {
+                    user_id,
+                    name: format!("Character {}", user_id),
+                    kind,
+                })
+                .collect(),
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn starts_from_the_relationship_or_neutral() {
+        let luke = character(6, "Luke", &[(7, RelationshipKind::Nemesis)]);
+        let vader = character(7, "Vader", &[]);
+        let leia = character(8, "Leia", &[(6, RelationshipKind::Ally)]);
+        assert_close(initial_affinity(&luke, &vader), -0.7);
+        assert_close(initial_affinity(&vader, &luke), 0.0);
+        assert_close(initial_affinity(&leia, &luke), 0.5);
+        assert_close(initial_affinity(&luke, &leia), 0.0);
+
+        let store = Store::in_memory().unwrap();
+        assert_close(stored_affinity(&store, "en", &luke, &vader).unwrap(), -0.7);
+        assert_close(stored_affinity(&store, "en", &vader, &luke).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn moves_both_affinities_by_the_same_delta() {
+        let store = Store::in_memory().unwrap();
+        let luke = character(6, "Luke", &[(7, RelationshipKind::Nemesis)]);
+        let vader = character(7, "Vader", &[]);
+        move_affinities(&store, "en", &luke, &vader, 0.1).unwrap();
+        assert_close(stored_affinity(&store, "en", &luke, &vader).unwrap(), -0.6);
+        assert_close(stored_affinity(&store, "en", &vader, &luke).unwrap(), 0.1);
+
+        move_affinities(&store, "en", &vader, &luke, -0.1).unwrap();
+        assert_close(stored_affinity(&store, "en", &luke, &vader).unwrap(), -0.7);
+        assert_close(stored_affinity(&store, "en", &vader, &luke).unwrap(), 0.0);
+
+        // Other forums are unaffected.
+        assert_close(stored_affinity(&store, "pt", &vader, &luke).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn keeps_affinities_between_bounds() {
+        let store = Store::in_memory().unwrap();
+        let han = character(6, "Han", &[(8, RelationshipKind::Crush)]);
+        let leia = character(8, "Leia", &[(6, RelationshipKind::Rival)]);
+        for _ in 0..10 {
+            move_affinities(&store, "en", &han, &leia, 0.1).unwrap();
+        }
+        assert_close(stored_affinity(&store, "en", &han, &leia).unwrap(), 1.0);
+        assert_close(stored_affinity(&store, "en", &leia, &han).unwrap(), 0.7);
+        for _ in 0..30 {
+            move_affinities(&store, "en", &leia, &han, -0.1).unwrap();
+        }
+        assert_close(stored_affinity(&store, "en", &han, &leia).unwrap(), -1.0);
+        assert_close(stored_affinity(&store, "en", &leia, &han).unwrap(), -1.0);
+    }
+}
@@ -0,0 +1,327 @@
+use std::{cell::RefCell, collections::VecDeque, time::Duration};
+
+use chrono::{DateTime, Timelike, Utc};
+use chrono_tz::Tz;
+use futures::future::{join, join_all};
+use rand::Rng;
+use serde::Deserialize;
+use tokio::{
+    sync::watch,
+    time::{sleep, Instant},
+};
+
+use crate::{
+    deployment::Deployment,
+    listener::{run_listener, ListenerConfig},
+};
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+fn default_mean_interval() -> f64 {
+    60.0
+}
+
+fn default_jitter() -> f64 {
+    0.5
+}
+
+/// Local hours during which nothing is posted. `start` may be greater than
+/// `end` for a range that crosses midnight, e.g. 23 to 7.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct QuietHours {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl QuietHours {
+    fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= hour && hour < self.end
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+/// Pacing of the daemon for one forum, the `[forums.<name>.schedule]` table.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScheduleConfig {
+    #[serde(default = "default_mean_interval")]
+    pub mean_interval_minutes: f64,
+    /// Fraction of the mean interval by which each wait is randomly
+    /// lengthened or shortened.
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+    #[serde(default)]
+    pub timezone: Tz,
+    pub quiet_hours: Option<QuietHours>,
+    pub max_posts_per_hour: Option<usize>,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            mean_interval_minutes: default_mean_interval(),
+            jitter: default_jitter(),
+            timezone: Tz::default(),
+            quiet_hours: None,
+            max_posts_per_hour: None,
+        }
+    }
+}
+
+impl ScheduleConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.mean_interval_minutes.is_finite() && self.mean_interval_minutes > 0.0) {
+            return Err("mean_interval_minutes must be positive".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err("jitter must be between 0 and 1".to_string());
+        }
+        if let Some(quiet) = self.quiet_hours {
+            if quiet.start > 23 || quiet.end > 23 {
+                return Err("quiet_hours must be between 0 and 23".to_string());
+            }
+        }
+        if self.max_posts_per_hour == Some(0) {
+            return Err("max_posts_per_hour must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// Time until the quiet hours end, if `now` falls in them.
+    fn quiet_hours_left(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let quiet = self.quiet_hours?;
+        let local = now.with_timezone(&self.timezone);
+        if !quiet.contains(local.hour()) {
+            return None;
+        }
+        let minute_of_day = local.hour() * 60 + local.minute();
+        let minutes_left = (quiet.end * 60 + 24 * 60 - minute_of_day) % (24 * 60);
+        Some(Duration::from_secs(u64::from(minutes_left.max(1)) * 60))
+    }
+}
+
+/// Keeps the posts of a forum to its schedule. Shared by the forum's
+/// scheduled actions and its listener.
+pub struct Pacer<'a> {
+    schedule: &'a ScheduleConfig,
+    recent_posts: VecDeque<Instant>,
+}
+
+impl<'a> Pacer<'a> {
+    pub fn new(schedule: &'a ScheduleConfig) -> Self {
+        Self {
+            schedule,
+            recent_posts: VecDeque::new(),
+        }
+    }
+
+    fn next_interval(&self) -> Duration {
+        let jitter = self.schedule.jitter;
+        let factor = if jitter > 0.0 {
+            rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)
+        } else {
+            1.0
+        };
+        Duration::from_secs_f64(self.schedule.mean_interval_minutes * 60.0 * factor)
+    }
+
+    /// How long to wait before posting is allowed again, if it isn't now.
+    pub fn hold_off(&mut self, now: DateTime<Utc>) -> Option<Duration> {
+        if let Some(wait) = self.schedule.quiet_hours_left(now) {
+            return Some(wait);
+        }
+        if let Some(max_posts) = self.schedule.max_posts_per_hour {
+            let hour_ago = Instant::now().checked_sub(HOUR);
+            while let (Some(oldest), Some(hour_ago)) = (self.recent_posts.front(), hour_ago) {
+                if *oldest > hour_ago {
+                    break;
+                }
+                self.recent_posts.pop_front();
+            }
+            if self.recent_posts.len() >= max_posts {
+                let oldest = self.recent_posts[0];
+                return Some((oldest + HOUR).saturating_duration_since(Instant::now()));
+            }
+        }
+        None
+    }
+
+    pub fn record_post(&mut self) {
+        self.recent_posts.push_back(Instant::now());
+    }
+}
+
+/// Sleeps for `duration`, returning false if shutdown was requested first.
+pub async fn sleep_or_shutdown(duration: Duration, shutdown: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = sleep(duration) => true,
+        _ = shutdown.changed() => false,
+    }
+}
+
+async fn run_forum(
+    deployment: &Deployment,
+    pacer: &RefCell<Pacer<'_>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut delay = pacer.borrow().next_interval();
+    loop {
+        println!(
+            "[{}] Next action in {} minutes",
+            deployment.name,
+            delay.as_secs() / 60
+        );
+        if !sleep_or_shutdown(delay, &mut shutdown).await {
+            break;
+        }
+        let hold_off = pacer.borrow_mut().hold_off(Utc::now());
+        if let Some(wait) = hold_off {
+            delay = wait;
+            continue;
+        }
+        match deployment.character(None) {
+            Some(character) => {
+                println!("[{}] Acting as {}", deployment.name, character.name);
+                let result = character.interact_with_forum(deployment).await;
+                match result {
+                    Ok(_) => {
+                        println!("[{}] Success!", deployment.name);
+                        pacer.borrow_mut().record_post();
+                    }
+                    Err(e) => eprintln!("[{}] Error: {}", deployment.name, e),
+                }
+            }
+            None => eprintln!("[{}] No character available", deployment.name),
+        }
+        delay = pacer.borrow().next_interval();
+    }
+    println!("[{}] Stopped", deployment.name);
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/// Elsewhere there is no SIGTERM, only Ctrl-C.
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    _ = tokio::signal::ctrl_c().await;
+}
+
+/// Runs every deployment on its own schedule, and its listener when enabled,
+/// until SIGTERM or Ctrl-C. An action already in progress is allowed to
+/// finish before exiting.
+pub async fn run_daemon(deployments: &[(Deployment, ScheduleConfig, ListenerConfig)]) {
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let pacers: Vec<RefCell<Pacer>> = deployments
+        .iter()
+        .map(|(_, schedule, _)| RefCell::new(Pacer::new(schedule)))
+        .collect();
+    let forums = join_all(deployments.iter().zip(&pacers).map(
+        |((deployment, _, listener), pacer)| {
+            join(
+                run_forum(deployment, pacer, shutdown_rx.clone()),
+                run_listener(deployment, listener, pacer, shutdown_rx.clone()),
+            )
+        },
+    ));
+    tokio::pin!(forums);
+    tokio::select! {
+        _ = &mut forums => {}
+        _ = shutdown_signal() => {
+            println!("Shutting down, waiting for running actions to finish");
+            _ = shutdown_tx.send(true);
+            forums.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn schedule(start: u32, end: u32) -> ScheduleConfig {
+        ScheduleConfig {
+            quiet_hours: Some(QuietHours { start, end }),
+            ..ScheduleConfig::default()
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let quiet = QuietHours { start: 9, end: 17 };
+        assert!(!quiet.contains(8));
+        assert!(quiet.contains(9));
+        assert!(quiet.contains(16));
+        assert!(!quiet.contains(17));
+    }
+
+    #[test]
+    fn quiet_hours_across_midnight() {
+        let quiet = QuietHours { start: 23, end: 7 };
+        for hour in [23, 0, 3, 6] {
+            assert!(quiet.contains(hour), "{}", hour);
+        }
+        for hour in [7, 12, 22] {
+            assert!(!quiet.contains(hour), "{}", hour);
+        }
+    }
+
+    #[test]
+    fn empty_quiet_hours() {
+        let quiet = QuietHours { start: 5, end: 5 };
+        assert!((0..24).all(|hour| !quiet.contains(hour)));
+    }
+
+    #[test]
+    fn time_left_before_midnight() {
+        let left = schedule(23, 7).quiet_hours_left(at(23, 30));
+        assert_eq!(left, Some(Duration::from_secs((7 * 60 + 30) * 60)));
+    }
+
+    #[test]
+    fn time_left_after_midnight() {
+        let left = schedule(23, 7).quiet_hours_left(at(6, 15));
+        assert_eq!(left, Some(Duration::from_secs(45 * 60)));
+    }
+
+    #[test]
+    fn no_time_left_outside_quiet_hours() {
+        assert_eq!(schedule(23, 7).quiet_hours_left(at(7, 0)), None);
+        assert_eq!(schedule(23, 7).quiet_hours_left(at(22, 59)), None);
+        assert_eq!(ScheduleConfig::default().quiet_hours_left(at(3, 0)), None);
+    }
+
+    #[test]
+    fn quiet_hours_follow_the_timezone() {
+        let schedule = ScheduleConfig {
+            timezone: chrono_tz::America::Sao_Paulo,
+            ..schedule(23, 7)
+        };
+        // 02:00 UTC is 23:00 in São Paulo.
+        let left = schedule.quiet_hours_left(at(2, 0));
+        assert_eq!(left, Some(Duration::from_secs(8 * 3600)));
+        assert_eq!(schedule.quiet_hours_left(at(12, 0)), None);
+    }
+
+    #[test]
+    fn validates_quiet_hours() {
+        assert!(schedule(23, 7).validate().is_ok());
+        assert!(schedule(24, 7).validate().is_err());
+    }
+}
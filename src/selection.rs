@@ -0,0 +1,407 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Duration, Utc};
+use rand::{distributions::WeightedIndex, prelude::Distribution};
+use serde::Deserialize;
+
+use crate::{deployment::Deployment, forum::Discussion, store::DiscussionActivity};
+
+fn default_human_weight() -> f64 {
+    4.0
+}
+
+fn default_freshness_half_life() -> f64 {
+    24.0
+}
+
+fn default_bot_share_penalty() -> f64 {
+    2.0
+}
+
+fn default_max_replies_per_day() -> u32 {
+    5
+}
+
+/// How characters pick the discussion to reply to, the
+/// `[forums.<name>.selection]` table.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SelectionConfig {
+    /// How much more likely a discussion whose last post is by a human is
+    /// picked.
+    #[serde(default = "default_human_weight")]
+    pub human_weight: f64,
+    /// Hours after which the activity of a discussion counts half as much.
+    /// 0 disables the freshness preference.
+    #[serde(default = "default_freshness_half_life")]
+    pub freshness_half_life_hours: f64,
+    /// Exponent of the penalty for discussions whose replies are mostly by
+    /// characters. 0 disables it.
+    #[serde(default = "default_bot_share_penalty")]
+    pub bot_share_penalty: f64,
+    /// Most character replies to a discussion in 24 hours. 0 for no limit.
+    #[serde(default = "default_max_replies_per_day")]
+    pub max_replies_per_day: u32,
+}
+
+impl Default for SelectionConfig {
+    fn default() -> Self {
+        Self {
+            human_weight: default_human_weight(),
+            freshness_half_life_hours: default_freshness_half_life(),
+            bot_share_penalty: default_bot_share_penalty(),
+            max_replies_per_day: default_max_replies_per_day(),
+        }
+    }
+}
+
+impl SelectionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let valid = |value: f64| value.is_finite() && value >= 0.0;
+        if !valid(self.human_weight) {
+            return Err("selection.human_weight must be a non-negative number".to_string());
+        }
+        if !valid(self.freshness_half_life_hours) {
+            return Err(
+                "selection.freshness_half_life_hours must be a non-negative number".to_string(),
+            );
+        }
+        if !valid(self.bot_share_penalty) {
+            return Err("selection.bot_share_penalty must be a non-negative number".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What strategies know besides the discussion itself.
+pub struct SelectionContext {
+    pub now: DateTime<Utc>,
+    /// User ids of the forum's characters.
+    pub character_ids: HashSet<i32>,
+    /// Character replies recorded per discussion.
+    pub activity: HashMap<i32, DiscussionActivity>,
+}
+
+impl SelectionContext {
+    pub fn new(deployment: &Deployment) -> rusqlite::Result<Self> {
+        let now = Utc::now();
+        Ok(Self {
+            now,
+            character_ids: deployment.characters.iter().map(|c| c.user_id).collect(),
+            activity: deployment
+                .store
+                .discussion_activity(&deployment.name, now - Duration::days(1))?,
+        })
+    }
+}
+
+/// One consideration in picking a discussion.
+pub trait SelectionStrategy: Send + Sync {
+    /// Factor applied to the weight of a discussion. 0 rules it out.
+    fn weight(&self, discussion: &Discussion, context: &SelectionContext) -> f64;
+}
+
+/// Favors discussions where a human has the last word.
+pub struct HumanFirst {
+    pub human_weight: f64,
+}
+
+impl SelectionStrategy for HumanFirst {
+    fn weight(&self, discussion: &Discussion, context: &SelectionContext) -> f64 {
+        match &discussion.last_poster {
+            Some(user) if !context.character_ids.contains(&user.id) => self.human_weight,
+            _ => 1.0,
+        }
+    }
+}
+
+/// Favors recent activity, halving the weight every `half_life_hours`.
+pub struct Freshness {
+    pub half_life_hours: f64,
+}
+
+impl SelectionStrategy for Freshness {
+    fn weight(&self, discussion: &Discussion, context: &SelectionContext) -> f64 {
+        let last_activity = discussion.last_posted_at.unwrap_or(discussion.created_at);
+        let age_hours = (context.now - last_activity).num_minutes().max(0) as f64 / 60.0;
+        // Old discussions stay possible, just unlikely.
+        0.5f64.powf(age_hours / self.half_life_hours).max(0.01)
+    }
+}
+
+/// Penalizes discussions whose replies are mostly by characters.
+pub struct BotShare {
+    pub penalty: f64,
+}
+
+impl SelectionStrategy for BotShare {
+    fn weight(&self, discussion: &Discussion, context: &SelectionContext) -> f64 {
+        let Some(activity) = context.activity.get(&discussion.id) else {
+            return 1.0;
+        };
+        let replies = f64::from(discussion.reply_count).max(f64::from(activity.total));
+        let share = f64::from(activity.total) / replies.max(1.0);
+        (1.0 - share).max(0.05).powf(self.penalty)
+    }
+}
+
+/// Rules out discussions characters already replied to enough today.
+#[derive(Debug, Clone, Copy)]
+pub struct DailyCap {
+    pub max_replies: u32,
+}
+
+impl DailyCap {
+    fn reached(&self, discussion_id: i32, context: &SelectionContext) -> bool {
+        let last_day = context
+            .activity
+            .get(&discussion_id)
+            .map_or(0, |a| a.last_day);
+        last_day >= self.max_replies
+    }
+}
+
+impl SelectionStrategy for DailyCap {
+    fn weight(&self, discussion: &Discussion, context: &SelectionContext) -> f64 {
+        if self.reached(discussion.id, context) {
+            0.0
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Picks discussions at random, weighted by the product of its strategies'
+/// weights.
+pub struct DiscussionSelector {
+    strategies: Vec<Box<dyn SelectionStrategy>>,
+    daily_cap: Option<DailyCap>,
+}
+
+impl DiscussionSelector {
+    pub fn new(config: &SelectionConfig) -> Self {
+        let mut strategies: Vec<Box<dyn SelectionStrategy>> = vec![Box::new(HumanFirst {
+            human_weight: config.human_weight,
+        })];
+        if config.freshness_half_life_hours > 0.0 {
+            strategies.push(Box::new(Freshness {
+                half_life_hours: config.freshness_half_life_hours,
+            }));
+        }
+        if config.bot_share_penalty > 0.0 {
+            strategies.push(Box::new(BotShare {
+                penalty: config.bot_share_penalty,
+            }));
+        }
+        let daily_cap = (config.max_replies_per_day > 0).then_some(DailyCap {
+            max_replies: config.max_replies_per_day,
+        });
+        if let Some(daily_cap) = daily_cap {
+            strategies.push(Box::new(daily_cap));
+        }
+        Self {
+            strategies,
+            daily_cap,
+        }
+    }
+
+    /// Whether characters already replied to a discussion as often as they
+    /// may today, for replies that don't go through selection.
+    pub fn capped(&self, discussion_id: i32, context: &SelectionContext) -> bool {
+        self.daily_cap
+            .is_some_and(|cap| cap.reached(discussion_id, context))
+    }
+
+    pub fn weight(&self, discussion: &Discussion, context: &SelectionContext) -> f64 {
+        self.strategies
+            .iter()
+            .map(|s| s.weight(discussion, context))
+            .product()
+    }
+
+    pub fn select<'a>(
+        &self,
+        discussions: &'a [Discussion],
+        context: &SelectionContext,
+    ) -> Option<&'a Discussion> {
+        let weights: Vec<f64> = discussions
+            .iter()
+            .map(|d| self.weight(d, context))
+            .collect();
+        // Fails when every weight is 0.
+        let index = WeightedIndex::new(&weights).ok()?;
+        Some(&discussions[index.sample(&mut rand::thread_rng())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forum::ForumUser;
+
+    const CHARACTER: i32 = 2;
+    const HUMAN: i32 = 3;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn user(id: i32) -> ForumUser {
+        ForumUser {
+            id,
+            username: format!("user{}", id),
+            display_name: format!("User {}", id),
+        }
+    }
+
+    fn discussion(id: i32, last_poster: i32, hours_ago: i64, reply_count: i32) -> Discussion {
+        Discussion {
+            id,
+            title: format!("Discussion {}", id),
+            author: Some(user(HUMAN)),
+            created_at: now() - Duration::days(30),
+            last_posted_at: Some(now() - Duration::hours(hours_ago)),
+            last_poster: Some(user(last_poster)),
+            reply_count,
+            tags: Vec::new(),
+        }
+    }
+
+    fn context(activity: &[(i32, u32, u32)]) -> SelectionContext {
+        SelectionContext {
+            now: now(),
+            character_ids: HashSet::from([CHARACTER]),
+            activity: activity
+                .iter()
+                .map(|&(id, total, last_day)| (id, DiscussionActivity { total, last_day }))
+                .collect(),
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn favors_discussions_a_human_posted_last() {
+        let strategy = HumanFirst { human_weight: 4.0 };
+        let context = context(&[]);
+        assert_close(strategy.weight(&discussion(1, HUMAN, 0, 3), &context), 4.0);
+        assert_close(
+            strategy.weight(&discussion(1, CHARACTER, 0, 3), &context),
+            1.0,
+        );
+        let deleted = Discussion {
+            last_poster: None,
+            ..discussion(1, HUMAN, 0, 3)
+        };
+        assert_close(strategy.weight(&deleted, &context), 1.0);
+    }
+
+    #[test]
+    fn halves_the_weight_every_half_life() {
+        let strategy = Freshness {
+            half_life_hours: 24.0,
+        };
+        let context = context(&[]);
+        assert_close(strategy.weight(&discussion(1, HUMAN, 0, 3), &context), 1.0);
+        assert_close(strategy.weight(&discussion(1, HUMAN, 24, 3), &context), 0.5);
+        assert_close(
+            strategy.weight(&discussion(1, HUMAN, 48, 3), &context),
+            0.25,
+        );
+        assert_close(strategy.weight(&discussion(1, HUMAN, -5, 3), &context), 1.0);
+    }
+
+    #[test]
+    fn keeps_old_discussions_possible() {
+        let strategy = Freshness {
+            half_life_hours: 24.0,
+        };
+        let old = discussion(1, HUMAN, 24 * 30, 3);
+        assert_close(strategy.weight(&old, &context(&[])), 0.01);
+    }
+
+    #[test]
+    fn penalizes_discussions_characters_took_over() {
+        let strategy = BotShare { penalty: 2.0 };
+        let context = context(&[(1, 2, 0), (2, 10, 0)]);
+        // Half of 4 replies: (1 - 0.5)².
+        assert_close(strategy.weight(&discussion(1, HUMAN, 0, 4), &context), 0.25);
+        // More recorded replies than the forum counts: all by characters.
+        assert_close(
+            strategy.weight(&discussion(2, HUMAN, 0, 4), &context),
+            0.0025,
+        );
+        assert_close(strategy.weight(&discussion(3, HUMAN, 0, 4), &context), 1.0);
+    }
+
+    #[test]
+    fn rules_out_discussions_at_the_daily_cap() {
+        let cap = DailyCap { max_replies: 2 };
+        let context = context(&[(1, 5, 1), (2, 5, 2)]);
+        assert_close(cap.weight(&discussion(1, HUMAN, 0, 9), &context), 1.0);
+        assert_close(cap.weight(&discussion(2, HUMAN, 0, 9), &context), 0.0);
+        assert_close(cap.weight(&discussion(3, HUMAN, 0, 9), &context), 1.0);
+    }
+
+    #[test]
+    fn caps_replies_outside_selection() {
+        let context = context(&[(1, 9, 5)]);
+        let selector = DiscussionSelector::new(&SelectionConfig::default());
+        assert!(selector.capped(1, &context));
+        assert!(!selector.capped(2, &context));
+        let unlimited = DiscussionSelector::new(&SelectionConfig {
+            max_replies_per_day: 0,
+            ..SelectionConfig::default()
+        });
+        assert!(!unlimited.capped(1, &context));
+    }
+
+    #[test]
+    fn multiplies_strategy_weights() {
+        let selector = DiscussionSelector::new(&SelectionConfig::default());
+        let context = context(&[(1, 2, 1)]);
+        assert_close(
+            selector.weight(&discussion(1, HUMAN, 24, 4), &context),
+            4.0 * 0.5 * 0.25,
+        );
+    }
+
+    #[test]
+    fn selects_only_discussions_with_weight() {
+        let selector = DiscussionSelector::new(&SelectionConfig::default());
+        let context = context(&[(1, 5, 5), (3, 5, 5)]);
+        let discussions = [
+            discussion(1, HUMAN, 0, 9),
+            discussion(2, CHARACTER, 200, 9),
+            discussion(3, HUMAN, 0, 9),
+        ];
+        for _ in 0..20 {
+            assert_eq!(selector.select(&discussions, &context).unwrap().id, 2);
+        }
+        assert!(selector.select(&discussions[..1], &context).is_none());
+        assert!(selector.select(&[], &context).is_none());
+    }
+
+    #[test]
+    fn rejects_negative_weights() {
+        let config = SelectionConfig {
+            human_weight: -1.0,
+            ..SelectionConfig::default()
+        };
+        assert!(config.validate().is_err());
+        let config = SelectionConfig {
+            freshness_half_life_hours: f64::NAN,
+            ..SelectionConfig::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(SelectionConfig::default().validate().is_ok());
+    }
+}
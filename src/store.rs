@@ -0,0 +1,899 @@
+use std::{collections::HashMap, path::Path, sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::llm::Completion;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS actions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TEXT NOT NULL,
+    forum TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    character TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    discussion_id INTEGER,
+    post_id INTEGER,
+    prompt TEXT NOT NULL,
+    content TEXT,
+    model TEXT,
+    prompt_tokens INTEGER,
+    completion_tokens INTEGER,
+    latency_ms INTEGER NOT NULL,
+    outcome TEXT NOT NULL,
+    error TEXT
+);
+CREATE INDEX IF NOT EXISTS actions_forum_created ON actions (forum, created_at);
+CREATE TABLE IF NOT EXISTS memories (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TEXT NOT NULL,
+    forum TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    discussion_id INTEGER,
+    title TEXT NOT NULL,
+    person_id INTEGER,
+    person TEXT,
+    summary TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS memories_character ON memories (forum, user_id);
+CREATE TABLE IF NOT EXISTS affinities (
+    forum TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    other_id INTEGER NOT NULL,
+    affinity REAL NOT NULL,
+    interactions INTEGER NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (forum, user_id, other_id)
+);
+CREATE TABLE IF NOT EXISTS embeddings (
+    forum TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    ref_id INTEGER NOT NULL,
+    user_id INTEGER,
+    model TEXT NOT NULL,
+    title TEXT NOT NULL,
+    text_hash TEXT,
+    vector BLOB NOT NULL,
+    PRIMARY KEY (forum, kind, ref_id)
+);
+CREATE TABLE IF NOT EXISTS moderation (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TEXT NOT NULL,
+    forum TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    character TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    discussion_id INTEGER,
+    attempt INTEGER NOT NULL,
+    decision TEXT NOT NULL,
+    reasons TEXT,
+    content TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS moderation_forum_created ON moderation (forum, created_at);
+";
+
+/// Columns added to tables of `SCHEMA` after they were first created, as
+/// (table, column, definition), so that older databases get them too.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[("embeddings", "text_hash", "TEXT")];
+
+/// Separates the prompts of an action that made several LLM calls.
+const PROMPT_SEPARATOR: &str = "\n\n-----\n\n";
+
+/// One thing a character did (or tried to do) on a forum.
+#[derive(Debug, Clone, Default)]
+pub struct ActionRecord {
+    pub id: i64,
+    pub created_at: String,
+    pub forum: String,
+    pub user_id: i32,
+    pub character: String,
+    /// `new_topic` or `reply`.
+    pub kind: String,
+    pub discussion_id: Option<i32>,
+    pub post_id: Option<i32>,
+    pub prompt: String,
+    pub content: Option<String>,
+    pub model: Option<String>,
+    pub prompt_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+    /// Time spent waiting for the LLM.
+    pub latency_ms: i64,
+    /// `success`, `dry_run` or `error`.
+    pub outcome: String,
+    pub error: Option<String>,
+}
+
+impl ActionRecord {
+    pub fn new(forum: &str, user_id: i32, character: &str, kind: &str) -> Self {
+        Self {
+            created_at: Utc::now().to_rfc3339(),
+            forum: forum.to_string(),
+            user_id,
+            character: character.to_string(),
+            kind: kind.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds an LLM call made while performing the action.
+    pub fn add_completion(&mut self, prompt: &str, completion: &Completion, latency: Duration) {
+        if !self.prompt.is_empty() {
+            self.prompt.push_str(PROMPT_SEPARATOR);
+        }
+        self.prompt.push_str(prompt);
+        self.model = Some(completion.model.clone());
+        let add = |total: Option<u32>, tokens: Option<u32>| match (total, tokens) {
+            (Some(total), Some(tokens)) => Some(total + tokens),
+            (total, tokens) => total.or(tokens),
+        };
+        self.prompt_tokens = add(self.prompt_tokens, completion.prompt_tokens);
+        self.completion_tokens = add(self.completion_tokens, completion.completion_tokens);
+        self.latency_ms += latency.as_millis() as i64;
+    }
+
+    pub fn finish<T, E: ToString>(&mut self, result: &Result<T, E>) {
+        match result {
+            Ok(_) => self.outcome = "success".to_string(),
+            Err(e) => {
+                self.outcome = "error".to_string();
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            created_at: row.get("created_at")?,
+            forum: row.get("forum")?,
+            user_id: row.get("user_id")?,
+            character: row.get("character")?,
+            kind: row.get("kind")?,
+            discussion_id: row.get("discussion_id")?,
+            post_id: row.get("post_id")?,
+            prompt: row.get("prompt")?,
+            content: row.get("content")?,
+            model: row.get("model")?,
+            prompt_tokens: row.get("prompt_tokens")?,
+            completion_tokens: row.get("completion_tokens")?,
+            latency_ms: row.get("latency_ms")?,
+            outcome: row.get("outcome")?,
+            error: row.get("error")?,
+        })
+    }
+}
+
+/// Something a character remembers about one of its posts.
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    pub id: i64,
+    pub created_at: String,
+    pub forum: String,
+    pub user_id: i32,
+    pub discussion_id: Option<i32>,
+    /// Title of the discussion.
+    pub title: String,
+    /// User whose post the character answered.
+    pub person_id: Option<i32>,
+    pub person: Option<String>,
+    pub summary: String,
+}
+
+impl Memory {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            created_at: row.get("created_at")?,
+            forum: row.get("forum")?,
+            user_id: row.get("user_id")?,
+            discussion_id: row.get("discussion_id")?,
+            title: row.get("title")?,
+            person_id: row.get("person_id")?,
+            person: row.get("person")?,
+            summary: row.get("summary")?,
+        })
+    }
+}
+
+/// Embedding of a discussion or a memory.
+#[derive(Debug, Clone)]
+pub struct Embedding {
+    pub forum: String,
+    /// `discussion` or `memory`.
+    pub kind: String,
+    /// Discussion or memory id.
+    pub ref_id: i64,
+    /// Character a memory belongs to.
+    pub user_id: Option<i32>,
+    pub model: String,
+    pub title: String,
+    /// Hash of the embedded text, to tell when it changed. `None` in
+    /// databases from before it was stored.
+    pub text_hash: Option<String>,
+    pub vector: Vec<f32>,
+}
+
+impl Embedding {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let bytes: Vec<u8> = row.get("vector")?;
+        Ok(Self {
+            forum: row.get("forum")?,
+            kind: row.get("kind")?,
+            ref_id: row.get("ref_id")?,
+            user_id: row.get("user_id")?,
+            model: row.get("model")?,
+            title: row.get("title")?,
+            text_hash: row.get("text_hash")?,
+            vector: bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        })
+    }
+}
+
+/// How a character feels about another one after their interactions.
+#[derive(Debug, Clone)]
+pub struct Affinity {
+    pub user_id: i32,
+    pub other_id: i32,
+    /// From -1 (hatred) to 1 (adoration).
+    pub affinity: f64,
+    pub interactions: u32,
+    pub updated_at: String,
+}
+
+impl Affinity {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            user_id: row.get("user_id")?,
+            other_id: row.get("other_id")?,
+            affinity: row.get("affinity")?,
+            interactions: row.get("interactions")?,
+            updated_at: row.get("updated_at")?,
+        })
+    }
+}
+
+/// What moderation decided about a generated text.
+#[derive(Debug, Clone, Default)]
+pub struct ModerationRecord {
+    pub id: i64,
+    pub created_at: String,
+    pub forum: String,
+    pub user_id: i32,
+    pub character: String,
+    /// Kind of the action the text was generated for.
+    pub kind: String,
+    pub discussion_id: Option<i32>,
+    /// 0 for the first version of the text, then one more per regeneration.
+    pub attempt: u32,
+    /// `publish`, `flag`, `regenerate` or `reject`.
+    pub decision: String,
+    /// What the checks objected to, one check per line.
+    pub reasons: Option<String>,
+    pub content: String,
+}
+
+impl ModerationRecord {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            created_at: row.get("created_at")?,
+            forum: row.get("forum")?,
+            user_id: row.get("user_id")?,
+            character: row.get("character")?,
+            kind: row.get("kind")?,
+            discussion_id: row.get("discussion_id")?,
+            attempt: row.get("attempt")?,
+            decision: row.get("decision")?,
+            reasons: row.get("reasons")?,
+            content: row.get("content")?,
+        })
+    }
+}
+
+/// Successful character replies to a discussion.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiscussionActivity {
+    pub total: u32,
+    /// Replies since the `since` given to `Store::discussion_activity`.
+    pub last_day: u32,
+}
+
+/// Which actions `Store::history` returns, newest first.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    pub forum: Option<String>,
+    pub user_id: Option<i32>,
+    pub discussion_id: Option<i32>,
+    /// Only actions that ended in an error, not dry runs, or moderation
+    /// decisions other than publishing.
+    pub failed_only: bool,
+    pub limit: u32,
+}
+
+/// Local SQLite database recording everything the bots do.
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+impl Store {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// A store in memory, gone when dropped.
+    #[cfg(test)]
+    pub fn in_memory() -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> rusqlite::Result<Self> {
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+        for (table, column, definition) in ADDED_COLUMNS {
+            let exists: bool = conn.query_row(
+                &format!(
+                    "SELECT COUNT(*) > 0 FROM pragma_table_info('{}') WHERE name = ?1",
+                    table
+                ),
+                [column],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                conn.execute_batch(&format!(
+                    "ALTER TABLE {} ADD COLUMN {} {}",
+                    table, column, definition
+                ))?;
+            }
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn record(&self, action: &ActionRecord) -> rusqlite::Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO actions (created_at, forum, user_id, character, kind, discussion_id,
+                post_id, prompt, content, model, prompt_tokens, completion_tokens, latency_ms,
+                outcome, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+            params![
+                action.created_at,
+                action.forum,
+                action.user_id,
+                action.character,
+                action.kind,
+                action.discussion_id,
+                action.post_id,
+                action.prompt,
+                action.content,
+                action.model,
+                action.prompt_tokens,
+                action.completion_tokens,
+                action.latency_ms,
+                action.outcome,
+                action.error,
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn history(&self, filter: &HistoryFilter) -> rusqlite::Result<Vec<ActionRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM actions
+             WHERE (?1 IS NULL OR forum = ?1)
+               AND (?2 IS NULL OR user_id = ?2)
+               AND (?3 IS NULL OR discussion_id = ?3)
+               AND (?4 = 0 OR outcome = 'error')
+             ORDER BY id DESC
+             LIMIT ?5",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                filter.forum,
+                filter.user_id,
+                filter.discussion_id,
+                filter.failed_only,
+                filter.limit,
+            ],
+            ActionRecord::from_row,
+        )?;
+        rows.collect()
+    }
+
+    pub fn record_moderation(&self, record: &ModerationRecord) -> rusqlite::Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO moderation (created_at, forum, user_id, character, kind, discussion_id,
+                attempt, decision, reasons, content)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                record.created_at,
+                record.forum,
+                record.user_id,
+                record.character,
+                record.kind,
+                record.discussion_id,
+                record.attempt,
+                record.decision,
+                record.reasons,
+                record.content,
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Moderation decisions, newest first. `failed_only` keeps those that
+    /// didn't let the text through untouched.
+    pub fn moderation_log(
+        &self,
+        filter: &HistoryFilter,
+    ) -> rusqlite::Result<Vec<ModerationRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM moderation
+             WHERE (?1 IS NULL OR forum = ?1)
+               AND (?2 IS NULL OR user_id = ?2)
+               AND (?3 IS NULL OR discussion_id = ?3)
+               AND (?4 = 0 OR decision != 'publish')
+             ORDER BY id DESC
+             LIMIT ?5",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                filter.forum,
+                filter.user_id,
+                filter.discussion_id,
+                filter.failed_only,
+                filter.limit,
+            ],
+            ModerationRecord::from_row,
+        )?;
+        rows.collect()
+    }
+
+    pub fn add_memory(&self, memory: &Memory) -> rusqlite::Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO memories (created_at, forum, user_id, discussion_id, title, person_id,
+                person, summary)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                memory.created_at,
+                memory.forum,
+                memory.user_id,
+                memory.discussion_id,
+                memory.title,
+                memory.person_id,
+                memory.person,
+                memory.summary,
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Memories of a character, those about `discussion_id` or any of
+    /// `person_ids` first, then the most recent ones.
+    pub fn recall(
+        &self,
+        forum: &str,
+        user_id: i32,
+        discussion_id: Option<i32>,
+        person_ids: &[i32],
+        limit: u32,
+    ) -> rusqlite::Result<Vec<Memory>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM memories
+             WHERE forum = ?1 AND user_id = ?2
+             ORDER BY (discussion_id = ?3
+                       OR person_id IN (SELECT value FROM json_each(?4))) DESC,
+                      id DESC
+             LIMIT ?5",
+        )?;
+        let person_ids = serde_json::to_string(person_ids).unwrap_or_default();
+        let rows = stmt.query_map(
+            params![forum, user_id, discussion_id, person_ids, limit],
+            Memory::from_row,
+        )?;
+        rows.collect()
+    }
+
+    pub fn memories(&self, ids: &[i64]) -> rusqlite::Result<Vec<Memory>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM memories WHERE id IN (SELECT value FROM json_each(?1)) ORDER BY id",
+        )?;
+        let ids = serde_json::to_string(ids).unwrap_or_default();
+        let rows = stmt.query_map(params![ids], Memory::from_row)?;
+        rows.collect()
+    }
+
+    /// Adds an embedding, replacing any previous one of the same thing.
+    pub fn put_embedding(&self, embedding: &Embedding) -> rusqlite::Result<()> {
+        let vector: Vec<u8> = embedding
+            .vector
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO embeddings
+             (forum, kind, ref_id, user_id, model, title, text_hash, vector)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                embedding.forum,
+                embedding.kind,
+                embedding.ref_id,
+                embedding.user_id,
+                embedding.model,
+                embedding.title,
+                embedding.text_hash,
+                vector,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn has_embedding(
+        &self,
+        forum: &str,
+        kind: &str,
+        ref_id: i64,
+        model: &str,
+    ) -> rusqlite::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM embeddings
+                WHERE forum = ?1 AND kind = ?2 AND ref_id = ?3 AND model = ?4)",
+            params![forum, kind, ref_id, model],
+            |row| row.get(0),
+        )
+    }
+
+    /// The embedding of one thing, if it was made with `model`.
+    pub fn embedding(
+        &self,
+        forum: &str,
+        kind: &str,
+        ref_id: i64,
+        model: &str,
+    ) -> rusqlite::Result<Option<Embedding>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT * FROM embeddings
+             WHERE forum = ?1 AND kind = ?2 AND ref_id = ?3 AND model = ?4",
+            params![forum, kind, ref_id, model],
+            Embedding::from_row,
+        )
+        .optional()
+    }
+
+    /// Embeddings of one kind made with `model`, only those of `user_id`
+    /// when given.
+    pub fn embeddings(
+        &self,
+        forum: &str,
+        kind: &str,
+        model: &str,
+        user_id: Option<i32>,
+    ) -> rusqlite::Result<Vec<Embedding>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM embeddings
+             WHERE forum = ?1 AND kind = ?2 AND model = ?3 AND (?4 IS NULL OR user_id = ?4)",
+        )?;
+        let rows = stmt.query_map(params![forum, kind, model, user_id], Embedding::from_row)?;
+        rows.collect()
+    }
+
+    pub fn affinity(
+        &self,
+        forum: &str,
+        user_id: i32,
+        other_id: i32,
+    ) -> rusqlite::Result<Option<f64>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT affinity FROM affinities WHERE forum = ?1 AND user_id = ?2 AND other_id = ?3",
+            params![forum, user_id, other_id],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    /// Adds `delta` to an affinity, starting from `initial` for characters
+    /// that never interacted, and keeping it between -1 and 1.
+    pub fn adjust_affinity(
+        &self,
+        forum: &str,
+        user_id: i32,
+        other_id: i32,
+        initial: f64,
+        delta: f64,
+    ) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO affinities (forum, user_id, other_id, affinity, interactions, updated_at)
+             VALUES (?1, ?2, ?3, max(-1.0, min(1.0, ?4 + ?5)), 1, ?6)
+             ON CONFLICT (forum, user_id, other_id) DO UPDATE SET
+                affinity = max(-1.0, min(1.0, affinity + ?5)),
+                interactions = interactions + 1,
+                updated_at = ?6",
+            params![
+                forum,
+                user_id,
+                other_id,
+                initial,
+                delta,
+                Utc::now().to_rfc3339()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Affinities on a forum, only those of `user_id` when given.
+    pub fn affinities(&self, forum: &str, user_id: Option<i32>) -> rusqlite::Result<Vec<Affinity>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM affinities
+             WHERE forum = ?1 AND (?2 IS NULL OR user_id = ?2)
+             ORDER BY user_id, other_id",
+        )?;
+        let rows = stmt.query_map(params![forum, user_id], Affinity::from_row)?;
+        rows.collect()
+    }
+
+    /// Successful replies per discussion, in total and since `since`.
+    pub fn discussion_activity(
+        &self,
+        forum: &str,
+        since: DateTime<Utc>,
+    ) -> rusqlite::Result<HashMap<i32, DiscussionActivity>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT discussion_id, COUNT(*), SUM(created_at >= ?2) FROM actions
+             WHERE forum = ?1 AND kind = 'reply' AND outcome = 'success'
+               AND discussion_id IS NOT NULL
+             GROUP BY discussion_id",
+        )?;
+        let rows = stmt.query_map(params![forum, since.to_rfc3339()], |row| {
+            Ok((
+                row.get(0)?,
+                DiscussionActivity {
+                    total: row.get(1)?,
+                    last_day: row.get(2)?,
+                },
+            ))
+        })?;
+        rows.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(forum: &str, user_id: i32, discussion_id: i32, outcome: &str) -> ActionRecord {
+        ActionRecord {
+            discussion_id: Some(discussion_id),
+            outcome: outcome.to_string(),
+            ..ActionRecord::new(forum, user_id, "Luke", "reply")
+        }
+    }
+
+    fn filter() -> HistoryFilter {
+        HistoryFilter {
+            limit: 100,
+            ..HistoryFilter::default()
+        }
+    }
+
+    fn ids(records: &[ActionRecord]) -> Vec<i64> {
+        records.iter().map(|r| r.id).collect()
+    }
+
+    fn store_with_history() -> Store {
+        let store = Store::in_memory().unwrap();
+        for action in [
+            action("en", 6, 1, "success"),
+            action("en", 7, 1, "error"),
+            action("pt", 6, 2, "success"),
+            action("en", 6, 2, "dry_run"),
+            action("en", 6, 1, "error"),
+        ] {
+            store.record(&action).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn records_actions() {
+        let store = Store::in_memory().unwrap();
+        let mut recorded = action("en", 6, 1, "error");
+        recorded.error = Some("Forum is down".to_string());
+        recorded.prompt_tokens = Some(12);
+        let id = store.record(&recorded).unwrap();
+        let history = store.history(&filter()).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].id, id);
+        assert_eq!(history[0].character, "Luke");
+        assert_eq!(history[0].error.as_deref(), Some("Forum is down"));
+        assert_eq!(history[0].prompt_tokens, Some(12));
+        assert_eq!(history[0].completion_tokens, None);
+    }
+
+    #[test]
+    fn lists_history_newest_first() {
+        let store = store_with_history();
+        assert_eq!(ids(&store.history(&filter()).unwrap()), [5, 4, 3, 2, 1]);
+        let limited = HistoryFilter {
+            limit: 2,
+            ..filter()
+        };
+        assert_eq!(ids(&store.history(&limited).unwrap()), [5, 4]);
+    }
+
+    #[test]
+    fn filters_history() {
+        let store = store_with_history();
+        let by_forum = HistoryFilter {
+            forum: Some("pt".to_string()),
+            ..filter()
+        };
+        assert_eq!(ids(&store.history(&by_forum).unwrap()), [3]);
+        let by_character = HistoryFilter {
+            user_id: Some(7),
+            ..filter()
+        };
+        assert_eq!(ids(&store.history(&by_character).unwrap()), [2]);
+        let by_discussion = HistoryFilter {
+            forum: Some("en".to_string()),
+            discussion_id: Some(2),
+            ..filter()
+        };
+        assert_eq!(ids(&store.history(&by_discussion).unwrap()), [4]);
+    }
+
+    #[test]
+    fn failed_history_leaves_out_dry_runs() {
+        let store = store_with_history();
+        let failed = HistoryFilter {
+            failed_only: true,
+            ..filter()
+        };
+        assert_eq!(ids(&store.history(&failed).unwrap()), [5, 2]);
+    }
+
+    #[test]
+    fn counts_successful_replies() {
+        let store = Store::in_memory().unwrap();
+        let now = Utc::now();
+        let at = |hours_ago: i64, action: ActionRecord| ActionRecord {
+            created_at: (now - chrono::Duration::hours(hours_ago)).to_rfc3339(),
+            ..action
+        };
+        for action in [
+            at(30, action("en", 6, 1, "success")),
+            at(2, action("en", 7, 1, "success")),
+            at(1, action("en", 6, 1, "error")),
+            at(1, action("en", 6, 1, "dry_run")),
+            at(1, action("pt", 6, 1, "success")),
+            at(1, action("en", 6, 2, "success")),
+            at(
+                1,
+                ActionRecord {
+                    outcome: "success".to_string(),
+                    discussion_id: Some(1),
+                    ..ActionRecord::new("en", 6, "Luke", "new_topic")
+                },
+            ),
+        ] {
+            store.record(&action).unwrap();
+        }
+        let activity = store
+            .discussion_activity("en", now - chrono::Duration::days(1))
+            .unwrap();
+        assert_eq!(activity.len(), 2);
+        assert_eq!((activity[&1].total, activity[&1].last_day), (2, 1));
+        assert_eq!((activity[&2].total, activity[&2].last_day), (1, 1));
+    }
+
+    #[test]
+    fn keeps_affinities_between_bounds() {
+        let store = Store::in_memory().unwrap();
+        assert_eq!(store.affinity("en", 6, 7).unwrap(), None);
+        store.adjust_affinity("en", 6, 7, 0.5, 0.25).unwrap();
+        assert_eq!(store.affinity("en", 6, 7).unwrap(), Some(0.75));
+        store.adjust_affinity("en", 6, 7, 0.5, 0.5).unwrap();
+        assert_eq!(store.affinity("en", 6, 7).unwrap(), Some(1.0));
+        store.adjust_affinity("en", 6, 7, 0.5, -3.0).unwrap();
+        assert_eq!(store.affinity("en", 6, 7).unwrap(), Some(-1.0));
+        // The initial affinity is clamped too.
+        store.adjust_affinity("en", 7, 6, -0.9, -0.5).unwrap();
+        assert_eq!(store.affinity("en", 7, 6).unwrap(), Some(-1.0));
+        let affinities = store.affinities("en", Some(6)).unwrap();
+        assert_eq!(affinities.len(), 1);
+        assert_eq!(affinities[0].interactions, 3);
+    }
+
+    fn memory(user_id: i32, discussion_id: i32, person_id: Option<i32>) -> Memory {
+        Memory {
+            created_at: Utc::now().to_rfc3339(),
+            forum: "en".to_string(),
+            user_id,
+            discussion_id: Some(discussion_id),
+            title: format!("Discussion {}", discussion_id),
+            person_id,
+            summary: "Talked".to_string(),
+            ..Memory::default()
+        }
+    }
+
+    #[test]
+    fn recalls_relevant_memories_first() {
+        let store = Store::in_memory().unwrap();
+        for memory in [
+            memory(6, 1, Some(3)),
+            memory(6, 2, Some(4)),
+            memory(6, 5, None),
+            memory(6, 3, None),
+            memory(6, 4, Some(8)),
+            memory(7, 5, Some(3)),
+        ] {
+            store.add_memory(&memory).unwrap();
+        }
+        let recalled: Vec<i64> = store
+            .recall("en", 6, Some(5), &[3, 4], 10)
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        // About discussion 5 or persons 3 and 4, newest first, then others.
+        assert_eq!(recalled, [3, 2, 1, 5, 4]);
+        let recalled = store.recall("en", 6, None, &[], 2).unwrap();
+        assert_eq!(recalled.iter().map(|m| m.id).collect::<Vec<_>>(), [5, 4]);
+    }
+
+    #[test]
+    fn adds_new_columns_to_old_databases() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE embeddings (
+                forum TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                ref_id INTEGER NOT NULL,
+                user_id INTEGER,
+                model TEXT NOT NULL,
+                title TEXT NOT NULL,
+                vector BLOB NOT NULL,
+                PRIMARY KEY (forum, kind, ref_id)
+            );
+            INSERT INTO embeddings VALUES ('en', 'discussion', 4, NULL, 'm', 'Droids', x'0000803f');",
+        )
+        .unwrap();
+        let store = Store::with_connection(conn).unwrap();
+        let old = store
+            .embedding("en", "discussion", 4, "m")
+            .unwrap()
+            .unwrap();
+        assert_eq!(old.text_hash, None);
+        assert_eq!(old.vector, [1.0]);
+        store
+            .put_embedding(&Embedding {
+                text_hash: Some("abc".to_string()),
+                ..old
+            })
+            .unwrap();
+        let new = store
+            .embedding("en", "discussion", 4, "m")
+            .unwrap()
+            .unwrap();
+        assert_eq!(new.text_hash.as_deref(), Some("abc"));
+    }
+}
@@ -0,0 +1,278 @@
+use tiktoken_rs::{
+    cl100k_base_singleton, o200k_base_singleton,
+    tokenizer::{get_tokenizer, Tokenizer},
+    CoreBPE,
+};
+
+const SEPARATOR: &str = "\n\n";
+/// Tokens taken by `SEPARATOR` between two posts.
+const SEPARATOR_TOKENS: usize = 1;
+/// Stands for the posts left out of a truncated history.
+pub const OMITTED: &str = "[…]";
+/// Quotes longer than this are cut down to their first line when a history
+/// doesn't fit.
+const LONG_QUOTE_TOKENS: usize = 60;
+
+/// Counts tokens with the tokenizer of a model. Models without a known
+/// OpenAI tokenizer (Llama, Claude, ...) are approximated with cl100k_base.
+pub struct TokenCounter {
+    bpe: &'static CoreBPE,
+}
+
+impl TokenCounter {
+    pub fn for_model(model: &str) -> Self {
+        let bpe = match get_tokenizer(model) {
+            Some(Tokenizer::O200kBase) => o200k_base_singleton(),
+            _ => cl100k_base_singleton(),
+        };
+        Self { bpe }
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        self.bpe.encode_with_special_tokens(text).len()
+    }
+
+    /// The beginning of `text`, at most `max_tokens` long.
+    pub fn truncate(&self, text: &str, max_tokens: usize) -> String {
+        let tokens = self.bpe.encode_with_special_tokens(text);
+        if tokens.len() <= max_tokens {
+            return text.to_string();
+        }
+        // A cut in the middle of a multi-byte character doesn't decode.
+        (0..=max_tokens)
+            .rev()
+            .find_map(|n| self.bpe.decode(tokens[..n].to_vec()).ok())
+            .map(|prefix| format!("{}…", prefix.trim_end()))
+            .unwrap_or_default()
+    }
+}
+
+/// Cuts quotes longer than `LONG_QUOTE_TOKENS` down to their first line.
+fn trim_quotes(counter: &TokenCounter, post: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut quote: Vec<&str> = Vec::new();
+    let flush = |quote: &mut Vec<&str>, lines: &mut Vec<String>| {
+        if quote.is_empty() {
+            return;
+        }
+        if counter.count(&quote.join("\n")) > LONG_QUOTE_TOKENS {
+            lines.push(quote[0].to_string());
+            lines.push(format!("> {}", OMITTED));
+        } else {
+            lines.extend(quote.iter().map(|l| l.to_string()));
+        }
+        quote.clear();
+    };
+    for line in post.lines() {
+        if line.trim_start().starts_with('>') {
+            quote.push(line);
+        } else {
+            flush(&mut quote, &mut lines);
+            lines.push(line.to_string());
+        }
+    }
+    flush(&mut quote, &mut lines);
+    lines.join("\n")
+}
+
+/// Joins posts into a history of at most about `budget` tokens, with a
+/// marker where posts were left out. Kept posts go through `wrap` along with
+/// their index, and `budget` must leave room for what it adds. See
+/// `fit_posts`.
+pub fn fit_history(
+    counter: &TokenCounter,
+    posts: &[String],
+    budget: usize,
+    wrap: impl Fn(usize, &str) -> String,
+) -> String {
+    let mut history = Vec::new();
+    for (i, post) in fit_posts(counter, posts, budget).into_iter().enumerate() {
+        match post {
+            Some(post) => history.push(wrap(i, &post)),
+            None if i == 1 => history.push(OMITTED.to_string()),
+            None => {}
+        }
+    }
+    history.join(SEPARATOR)
+}
+
+/// Fits posts into about `budget` tokens. When they don't fit, long quotes
+/// are cut down first, then posts are left out from the middle, keeping the
+/// opening post and as many of the most recent posts as possible. Posts too
+/// long on their own are truncated. Left out posts are `None`.
+pub fn fit_posts(counter: &TokenCounter, posts: &[String], budget: usize) -> Vec<Option<String>> {
+    let total = |posts: &[String]| -> usize {
+        posts
+            .iter()
+            .map(|p| counter.count(p) + SEPARATOR_TOKENS)
+            .sum()
+    };
+    if total(posts) <= budget {
+        return posts.iter().cloned().map(Some).collect();
+    }
+    let posts: Vec<String> = posts.iter().map(|p| trim_quotes(counter, p)).collect();
+    if total(&posts) <= budget {
+        return posts.into_iter().map(Some).collect();
+    }
+    let Some((first, rest)) = posts.split_first() else {
+        return Vec::new();
+    };
+
+    // The opening post gets at most a third of the budget, the rest goes to
+    // the most recent posts.
+    let first = counter.truncate(first, budget / 3);
+    let mut remaining = budget
+        .saturating_sub(counter.count(&first) + counter.count(OMITTED) + 2 * SEPARATOR_TOKENS);
+    let mut recent = Vec::new();
+    for post in rest.iter().rev() {
+        let tokens = counter.count(post) + SEPARATOR_TOKENS;
+        if tokens > remaining {
+            if recent.is_empty() {
+                recent.push(counter.truncate(post, remaining.saturating_sub(SEPARATOR_TOKENS)));
+            }
+            break;
+        }
+        remaining -= tokens;
+        recent.push(post.clone());
+    }
+
+    let mut fitted = vec![Some(first)];
+    fitted.extend((recent.len()..rest.len()).map(|_| None));
+    fitted.extend(recent.into_iter().rev().map(Some));
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter() -> TokenCounter {
+        TokenCounter::for_model("gpt-4o")
+    }
+
+    fn words(word: &str, n: usize) -> String {
+        vec![word; n].join(" ")
+    }
+
+    #[test]
+    fn truncates_to_the_token_limit() {
+        let counter = counter();
+        let text = words("hello", 100);
+        assert_eq!(counter.truncate(&text, 200), text);
+        let truncated = counter.truncate(&text, 10);
+        assert!(truncated.ends_with('…'));
+        assert!(text.starts_with(truncated.trim_end_matches('…')));
+        assert!(counter.count(truncated.trim_end_matches('…')) <= 10);
+    }
+
+    #[test]
+    fn truncates_multi_byte_text() {
+        let counter = counter();
+        let truncated = counter.truncate(&"🦀".repeat(50), 5);
+        assert!(truncated.trim_end_matches('…').chars().all(|c| c == '🦀'));
+    }
+
+    #[test]
+    fn collapses_long_quotes_to_their_first_line() {
+        let counter = counter();
+        let quote: Vec<String> = (0..30).map(|i| format!("> quoted line {}", i)).collect();
+        let post = format!("Intro\n{}\nMy answer", quote.join("\n"));
+        assert_eq!(
+            trim_quotes(&counter, &post),
+            "Intro\n> quoted line 0\n> […]\nMy answer"
+        );
+    }
+
+    #[test]
+    fn keeps_short_quotes() {
+        let post = "> short quote\n> on two lines\nMy answer";
+        assert_eq!(trim_quotes(&counter(), post), post);
+    }
+
+    #[test]
+    fn keeps_posts_that_fit() {
+        let posts = vec!["one".to_string(), "two".to_string()];
+        let fitted = fit_posts(&counter(), &posts, 100);
+        assert_eq!(
+            fitted,
+            vec![Some("one".to_string()), Some("two".to_string())]
+        );
+    }
+
+    #[test]
+    fn trims_quotes_before_leaving_posts_out() {
+        let counter = counter();
+        let quote: Vec<String> = (0..30).map(|i| format!("> quoted line {}", i)).collect();
+        let posts = vec![
+            "Opening".to_string(),
+            format!("{}\nReply", quote.join("\n")),
+        ];
+        let fitted = fit_posts(&counter, &posts, 40);
+        assert_eq!(
+            fitted,
+            vec![
+                Some("Opening".to_string()),
+                Some("> quoted line 0\n> […]\nReply".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn caps_the_opening_post_to_a_third_of_the_budget() {
+        let counter = counter();
+        let posts = vec![words("opening", 1000), "recent".to_string()];
+        let fitted = fit_posts(&counter, &posts, 300);
+        let first = fitted[0].as_deref().unwrap();
+        assert!(counter.count(first.trim_end_matches('…')) <= 100);
+        assert_eq!(fitted[1].as_deref(), Some("recent"));
+    }
+
+    #[test]
+    fn leaves_out_posts_from_the_middle() {
+        let counter = counter();
+        let mut posts = vec!["Opening".to_string()];
+        posts.extend((1..=50).map(|i| format!("{} {}", words("post", 20), i)));
+        let fitted = fit_posts(&counter, &posts, 200);
+        assert_eq!(fitted.len(), posts.len());
+        assert_eq!(fitted[0].as_deref(), Some("Opening"));
+        assert_eq!(fitted[1], None);
+        assert_eq!(fitted.last().unwrap().as_ref(), posts.last());
+        // Kept posts are the most recent ones, with nothing left out after.
+        let kept = fitted.iter().skip(1).skip_while(|p| p.is_none());
+        assert!(kept.clone().all(Option::is_some));
+        assert!(kept.count() > 1);
+    }
+
+    #[test]
+    fn marks_left_out_posts_once_after_the_opening_post() {
+        let counter = counter();
+        let mut posts = vec!["Opening".to_string()];
+        posts.extend((1..=50).map(|i| format!("{} {}", words("post", 20), i)));
+        let history = fit_history(&counter, &posts, 200, |_, post| post.to_string());
+        let parts: Vec<&str> = history.split(SEPARATOR).collect();
+        assert_eq!(parts[0], "Opening");
+        assert_eq!(parts[1], OMITTED);
+        assert_eq!(parts.iter().filter(|p| **p == OMITTED).count(), 1);
+        assert_eq!(parts.last(), posts.last().map(String::as_str).as_ref());
+        assert!(counter.count(&history) <= 200);
+    }
+
+    #[test]
+    fn wraps_kept_posts_with_their_index() {
+        let posts = vec!["a".to_string(), "b".to_string()];
+        let history = fit_history(&counter(), &posts, 100, |i, post| format!("{}:{}", i, post));
+        assert_eq!(history, "0:a\n\n1:b");
+    }
+
+    #[test]
+    fn truncates_a_single_oversize_recent_post() {
+        let counter = counter();
+        let posts = vec!["Opening".to_string(), words("recent", 1000)];
+        let fitted = fit_posts(&counter, &posts, 200);
+        assert_eq!(fitted[0].as_deref(), Some("Opening"));
+        let recent = fitted[1].as_deref().unwrap();
+        assert!(recent.ends_with('…'));
+        assert!(recent.starts_with("recent recent"));
+        assert!(counter.count(recent) <= 200);
+    }
+}